@@ -7,6 +7,7 @@ use mbooks::{
         Summary,
     },
 };
+use rust_decimal::Decimal;
 use slog::o;
 use tokio::sync::mpsc;
 
@@ -18,18 +19,21 @@ fn merger_benchmark(c: &mut Criterion) {
         for i in 0..size {
             bids.push(Level {
                 exchange: binance.clone(),
-                price: 1.0 + (size - i) as f64,
-                quantity: 10.0,
+                price: Decimal::from(1 + size - i),
+                quantity: Decimal::from(10),
+                ..Default::default()
             });
             asks.push(Level {
                 exchange: binance.clone(),
-                price: (2 * size + i) as f64,
-                quantity: 10.0,
+                price: Decimal::from(2 * size + i),
+                quantity: Decimal::from(10),
+                ..Default::default()
             });
         }
         let summary_binance = Summary {
             asks,
             bids,
+            ..Default::default()
         };
 
         let bitstamp = "bitstamp".to_string();
@@ -38,18 +42,21 @@ fn merger_benchmark(c: &mut Criterion) {
         for i in 0..size {
             bids.push(Level {
                 exchange: bitstamp.clone(),
-                price: 2.0 + (size - i) as f64,
-                quantity: 10.0,
+                price: Decimal::from(2 + size - i),
+                quantity: Decimal::from(10),
+                ..Default::default()
             });
             asks.push(Level {
                 exchange: bitstamp.clone(),
-                price: (3 * size + i) as f64,
-                quantity: 10.0,
+                price: Decimal::from(3 * size + i),
+                quantity: Decimal::from(10),
+                ..Default::default()
             });
         }
         let summary_bitstamp = Summary {
             asks,
             bids,
+            ..Default::default()
         };
 
         c.bench_function(format!("merger merging {} objects", size).as_str(), move |b| {
@@ -63,13 +70,14 @@ fn merger_benchmark(c: &mut Criterion) {
                     logger.clone(), summary_receiver, summary_sender, 2,
                 );
 
-                test_sender.send(summary_binance.clone()).unwrap();
-                test_sender.send(summary_bitstamp.clone()).unwrap();
+                test_sender.send(summary_binance.clone().into()).unwrap();
+                test_sender.send(summary_bitstamp.clone().into()).unwrap();
 
-                test_sender.send(summary_binance.clone()).unwrap();
-                test_sender.send(summary_bitstamp.clone()).unwrap();
+                test_sender.send(summary_binance.clone().into()).unwrap();
+                test_sender.send(summary_bitstamp.clone().into()).unwrap();
                 drop(test_sender);
-                merger.start().await.unwrap();
+                let (shutdown_sender, _shutdown_receiver) = tokio::sync::broadcast::channel(1);
+                merger.start(shutdown_sender).await.unwrap();
             })
         });
     }
@@ -0,0 +1,215 @@
+use crate::{
+    orderbook,
+    types::{
+        timestamp_us,
+        Level,
+        Summary,
+    },
+};
+use rust_decimal::Decimal;
+use std::{
+    cmp::Reverse,
+    time::SystemTime,
+};
+
+/// Opportunity is buying the asks of `buy_exchange` below the bids of `sell_exchange`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Opportunity {
+    pub buy_exchange: String,
+    pub sell_exchange: String,
+    /// Best ask of `buy_exchange`.
+    pub buy_price: Decimal,
+    /// Best bid of `sell_exchange`.
+    pub sell_price: Decimal,
+    /// Quantity that can be bought and sold while the levels are crossed.
+    pub quantity: Decimal,
+    /// Profit of crossing the quantity, in the quote asset.
+    pub profit: Decimal,
+}
+
+/// Arbitrage has the opportunities found in a merged summary.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Arbitrage {
+    /// Sorted by profit, the most profitable first.
+    pub opportunities: Vec<Opportunity>,
+    pub exchange_timestamp: Option<SystemTime>,
+    pub received_timestamp: Option<SystemTime>,
+}
+
+impl Arbitrage {
+    /// Finds the opportunities of every pair of exchanges in the summary of an `OrderbookMerger`.
+    /// The prices are compared adjusted by the fees of the levels, so the profit is after the taker
+    /// fees when the merger has them.
+    /// Each pair walks all the levels of its exchanges, so the quantity of different pairs may overlap.
+    pub fn find(summary: &Summary) -> Self {
+        let mut exchanges: Vec<&str> = Vec::new();
+        for level in summary.bids.iter().chain(&summary.asks) {
+            if !exchanges.contains(&level.exchange.as_str()) {
+                exchanges.push(&level.exchange);
+            }
+        }
+
+        let of_exchange = |levels: &[Level], exchange: &str| -> Vec<Level> {
+            levels.iter().filter(|level| level.exchange == exchange).cloned().collect()
+        };
+        let mut opportunities = Vec::new();
+        for buy_exchange in &exchanges {
+            let asks = of_exchange(&summary.asks, buy_exchange);
+            for sell_exchange in exchanges.iter().filter(|exchange| *exchange != buy_exchange) {
+                let bids = of_exchange(&summary.bids, sell_exchange);
+                if let Some(opportunity) = Self::cross(&bids, &asks) {
+                    opportunities.push(opportunity);
+                }
+            }
+        }
+        opportunities.sort_by_key(|opportunity| Reverse(opportunity.profit));
+
+        Arbitrage {
+            opportunities,
+            exchange_timestamp: summary.exchange_timestamp,
+            received_timestamp: summary.received_timestamp,
+        }
+    }
+
+    /// Takes the `asks` of an exchange while they are below the `bids` of another one, both sorted
+    /// from the best level, `None` when they are not crossed.
+    fn cross(bids: &[Level], asks: &[Level]) -> Option<Opportunity> {
+        let (best_bid, best_ask) = (bids.first()?, asks.first()?);
+        let (mut bids, mut asks) = (bids.iter(), asks.iter());
+        let (mut bid, mut ask) = (bids.next(), asks.next());
+        let (mut bid_left, mut ask_left) = (best_bid.quantity, best_ask.quantity);
+        let mut quantity = Decimal::ZERO;
+        let mut profit = Decimal::ZERO;
+        while let (Some(bid_level), Some(ask_level)) = (bid, ask) {
+            let margin = bid_level.adjusted_price(Decimal::NEGATIVE_ONE) - ask_level.adjusted_price(Decimal::ONE);
+            if margin <= Decimal::ZERO {
+                break;
+            }
+            let crossed = bid_left.min(ask_left);
+            quantity += crossed;
+            profit += crossed * margin;
+            bid_left -= crossed;
+            ask_left -= crossed;
+            if bid_left.is_zero() {
+                bid = bids.next();
+                bid_left = bid.map(|level| level.quantity).unwrap_or_default();
+            }
+            if ask_left.is_zero() {
+                ask = asks.next();
+                ask_left = ask.map(|level| level.quantity).unwrap_or_default();
+            }
+        }
+
+        if quantity.is_zero() {
+            return None;
+        }
+        Some(Opportunity {
+            buy_exchange: best_ask.exchange.clone(),
+            sell_exchange: best_bid.exchange.clone(),
+            buy_price: best_ask.price,
+            sell_price: best_bid.price,
+            quantity,
+            profit,
+        })
+    }
+}
+
+impl From<&Opportunity> for orderbook::ArbitrageOpportunity {
+    fn from(opportunity: &Opportunity) -> Self {
+        orderbook::ArbitrageOpportunity {
+            buy_exchange: opportunity.buy_exchange.clone(),
+            sell_exchange: opportunity.sell_exchange.clone(),
+            exact_buy_price: opportunity.buy_price.to_string(),
+            exact_sell_price: opportunity.sell_price.to_string(),
+            exact_quantity: opportunity.quantity.to_string(),
+            exact_profit: opportunity.profit.to_string(),
+        }
+    }
+}
+
+impl From<Arbitrage> for orderbook::ArbitrageUpdate {
+    fn from(arbitrage: Arbitrage) -> Self {
+        orderbook::ArbitrageUpdate {
+            opportunities: arbitrage.opportunities.iter().map(|x| x.into()).collect(),
+            exchange_timestamp_us: timestamp_us(arbitrage.exchange_timestamp),
+            received_timestamp_us: timestamp_us(arbitrage.received_timestamp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        arbitrage::Arbitrage,
+        types::{
+            Level,
+            Summary,
+        },
+    };
+    use rust_decimal_macros::dec;
+
+    fn levels(levels: &[(&str, &str, &str)]) -> Vec<Level> {
+        levels.iter()
+            .map(|(exchange, price, quantity)| Level::parse(exchange, &[price.to_string(), quantity.to_string()]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn should_not_find_opportunities_when_not_crossed() {
+        // Given
+        let summary = Summary::from_levels(
+            levels(&[("binance", "0.0675", "1"), ("bitstamp", "0.0674", "2")]),
+            levels(&[("bitstamp", "0.0676", "1"), ("binance", "0.0677", "1")]),
+        );
+
+        // When
+        let arbitrage = Arbitrage::find(&summary);
+
+        // Then
+        assert!(arbitrage.opportunities.is_empty());
+    }
+
+    #[test]
+    fn should_find_the_crossable_quantity_and_profit() {
+        // Given
+        let summary = Summary::from_levels(
+            levels(&[("binance", "0.0680", "1"), ("binance", "0.0678", "2"), ("bitstamp", "0.0674", "2")]),
+            levels(&[("bitstamp", "0.0676", "2"), ("bitstamp", "0.0679", "5"), ("binance", "0.0681", "1")]),
+        );
+
+        // When
+        let arbitrage = Arbitrage::find(&summary);
+
+        // Then
+        assert_eq!(1, arbitrage.opportunities.len());
+        let opportunity = &arbitrage.opportunities[0];
+        assert_eq!("bitstamp", opportunity.buy_exchange);
+        assert_eq!("binance", opportunity.sell_exchange);
+        assert_eq!(dec!(0.0676), opportunity.buy_price);
+        assert_eq!(dec!(0.0680), opportunity.sell_price);
+        // 1 at 0.0680 - 0.0676 and 1 at 0.0678 - 0.0676, the next ask is above the bids
+        assert_eq!(dec!(2), opportunity.quantity);
+        assert_eq!(dec!(0.0006), opportunity.profit);
+    }
+
+    #[test]
+    fn should_find_opportunities_after_fees() {
+        // Given
+        let mut bids = levels(&[("binance", "102", "1")]);
+        let mut asks = levels(&[("bitstamp", "100", "1"), ("kraken", "101", "1")]);
+        for level in bids.iter_mut().chain(asks.iter_mut()) {
+            level.fee = dec!(0.005);
+        }
+        let summary = Summary::from_levels(bids, asks);
+
+        // When
+        let arbitrage = Arbitrage::find(&summary);
+
+        // Then
+        // 102 * 0.995 - 100 * 1.005 and 102 * 0.995 - 101 * 1.005 is no longer positive
+        assert_eq!(1, arbitrage.opportunities.len());
+        assert_eq!("bitstamp", arbitrage.opportunities[0].buy_exchange);
+        assert_eq!(dec!(1), arbitrage.opportunities[0].quantity);
+        assert_eq!(dec!(0.99), arbitrage.opportunities[0].profit);
+    }
+}
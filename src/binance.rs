@@ -1,8 +1,19 @@
-use crate::types::{
-    Level,
-    Symbol,
-    Summary,
-    MBooksError,
+use crate::{
+    book::LocalBook,
+    source::{
+        BookMode,
+        ConnectionEnd,
+        Endpoint,
+        Source,
+        SourceConfig,
+        SummarySenders,
+    },
+    types::{
+        Level,
+        Symbol,
+        Summary,
+        MBooksError,
+    },
 };
 use futures_util::StreamExt;
 use opentelemetry::{
@@ -22,41 +33,133 @@ use slog::{
     info,
     o,
     error,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
 };
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::broadcast;
 use tokio_tungstenite::connect_async;
 
+const WEBSOCKET_URL: &str = "wss://stream.binance.com:9443";
+const REST_URL: &str = "https://api.binance.com";
+/// Minimum number of levels requested in the REST snapshot used by the diff depth stream.
+const SNAPSHOT_LIMIT: usize = 1000;
+/// Maximum number of levels Binance returns in the REST snapshot.
+const MAX_SNAPSHOT_LIMIT: usize = 5000;
+
+/// Message from the combined stream, wrapping the event with the name of the stream it came from.
+#[derive(Debug, Deserialize)]
+struct StreamMessage<T> {
+    stream: String,
+    data: T,
+}
+
 #[derive(Debug, Deserialize)]
 struct DepthSnapshot {
+    #[serde(rename(deserialize = "lastUpdateId"))]
+    last_update_id: u64,
     bids: Vec<Vec<String>>,
     asks: Vec<Vec<String>>,
 }
 
+/// Event from the diff depth stream, the quantities are absolute and zero removes the level.
+#[derive(Debug, Deserialize)]
+struct DepthUpdate {
+    /// Event time in milliseconds.
+    #[serde(rename(deserialize = "E"))]
+    event_time: u64,
+    #[serde(rename(deserialize = "U"))]
+    first_update_id: u64,
+    #[serde(rename(deserialize = "u"))]
+    final_update_id: u64,
+    #[serde(rename(deserialize = "b"))]
+    bids: Vec<Vec<String>>,
+    #[serde(rename(deserialize = "a"))]
+    asks: Vec<Vec<String>>,
+}
+
+/// Result of applying a `DepthUpdate` to the `DiffBook`.
+#[derive(Debug, PartialEq)]
+enum Sequence {
+    /// The update was already contained in the snapshot.
+    Outdated,
+    Applied,
+    /// Some update was lost, the book needs a new snapshot.
+    Gap,
+}
+
+/// DiffBook keeps the local book from the diff depth stream.
+/// It starts from a REST snapshot and only applies the updates that continue the sequence of
+/// update IDs, so the book is consistent with the one in the exchange.
+struct DiffBook {
+    book: LocalBook,
+    last_update_id: u64,
+    /// Whether an update was applied after the snapshot.
+    synced: bool,
+}
+
+impl DiffBook {
+    fn new(snapshot: DepthSnapshot) -> Result<Self, MBooksError> {
+        let mut book = LocalBook::new("binance");
+        book.update_bids(&snapshot.bids)?;
+        book.update_asks(&snapshot.asks)?;
+
+        Ok(Self {
+            book,
+            last_update_id: snapshot.last_update_id,
+            synced: false,
+        })
+    }
+
+    fn apply(&mut self, update: &DepthUpdate) -> Result<Sequence, MBooksError> {
+        if update.final_update_id <= self.last_update_id {
+            return Ok(Sequence::Outdated);
+        }
+
+        // The first update must contain the snapshot one, after that they must be contiguous
+        let expected = self.last_update_id + 1;
+        let in_sequence = if self.synced {
+            update.first_update_id == expected
+        } else {
+            update.first_update_id <= expected
+        };
+        if !in_sequence {
+            return Ok(Sequence::Gap);
+        }
+
+        self.book.update_bids(&update.bids)?;
+        self.book.update_asks(&update.asks)?;
+        self.last_update_id = update.final_update_id;
+        self.synced = true;
+
+        Ok(Sequence::Applied)
+    }
+}
+
 impl TryInto<Summary> for DepthSnapshot {
     type Error = MBooksError;
 
     fn try_into(self) -> Result<Summary, Self::Error> {
         let mut bids = Vec::with_capacity(self.bids.len());
         for bid in &self.bids {
-            bids.push(Level {
-                exchange: "binance".to_string(),
-                price: bid[0].parse::<f64>().map_err(MBooksError::ParseError)?,
-                quantity: bid[1].parse::<f64>().map_err(MBooksError::ParseError)?,
-            });
+            bids.push(Level::parse("binance", bid)?);
         }
 
         let mut asks = Vec::with_capacity(self.asks.len());
         for ask in &self.asks {
-            asks.push(Level {
-                exchange: "binance".to_string(),
-                price: ask[0].parse::<f64>().map_err(MBooksError::ParseError)?,
-                quantity: ask[1].parse::<f64>().map_err(MBooksError::ParseError)?,
-            });
+            asks.push(Level::parse("binance", ask)?);
         }
 
         Ok(Summary {
             bids,
             asks,
+            ..Default::default()
         })
     }
 }
@@ -65,20 +168,91 @@ fn symbol_to_string(symbol: &Symbol) -> String {
     format!("{}{}", symbol.base.to_string(), symbol.quote.to_string()).to_lowercase()
 }
 
-pub async fn run_binance(
-    log: Logger,
-    shutdown_receiver: tokio::sync::broadcast::Receiver<String>,
-    summary_tx: UnboundedSender<Summary>,
-    symbol: &Symbol, depth: usize,
-) -> Result<(), Box<dyn std::error::Error>> {
+/// Fetches the REST snapshot used to start the `DiffBook`.
+async fn fetch_diff_book(
+    log: &Logger, rest_url: &str, symbol: &Symbol, depth: usize,
+) -> Result<DiffBook, Box<dyn std::error::Error>> {
+    let url = format!(
+        "{}/api/v3/depth?symbol={}&limit={}",
+        rest_url, symbol_to_string(symbol).to_uppercase(), depth.clamp(SNAPSHOT_LIMIT, MAX_SNAPSHOT_LIMIT),
+    );
+    info!(log, "fetching binance snapshot"; "url" => &url);
+
+    let snapshot: DepthSnapshot = reqwest::get(url).await?
+        .error_for_status()?
+        .json().await?;
+    info!(log, "got binance snapshot"; "last_update_id" => snapshot.last_update_id);
+
+    Ok(DiffBook::new(snapshot)?)
+}
+
+/// Name of the stream with the book of the symbol.
+fn stream_name(symbol: &Symbol, depth: usize, book_mode: BookMode) -> String {
+    match book_mode {
+        BookMode::Snapshot => format!("{}@depth{}@100ms", symbol_to_string(symbol), depth),
+        BookMode::Diff => format!("{}@depth@100ms", symbol_to_string(symbol)),
+    }
+}
+
+/// Binance `Source` implementation, all the symbols share the same combined stream.
+pub struct Binance {
+    symbols: Vec<Symbol>,
+    depth: usize,
+    book_mode: BookMode,
+    endpoint: Endpoint,
+}
+
+impl Binance {
+    pub fn new(config: SourceConfig) -> Self {
+        Self {
+            endpoint: config.endpoints.endpoint("binance", WEBSOCKET_URL, REST_URL),
+            symbols: config.symbols,
+            depth: config.depth,
+            book_mode: config.book_mode,
+        }
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for Binance {
+    fn name(&self) -> &'static str {
+        "binance"
+    }
+
+    fn symbol_to_string(&self, symbol: &Symbol) -> String {
+        symbol_to_string(symbol)
+    }
+
+    async fn connect(
+        &self,
+        log: &Logger,
+        shutdown_receiver: &mut broadcast::Receiver<String>,
+        summary_txs: &SummarySenders,
+    ) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+        run_binance(
+            log, shutdown_receiver, summary_txs, &self.symbols, self.depth, self.book_mode, &self.endpoint,
+        ).await
+    }
+}
+
+/// Connects to Binance and streams the books until the connection ends.
+async fn run_binance(
+    log: &Logger,
+    shutdown_receiver: &mut broadcast::Receiver<String>,
+    summary_txs: &SummarySenders,
+    symbols: &[Symbol], depth: usize, book_mode: BookMode, endpoint: &Endpoint,
+) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
     let tracer = global::tracer("run_binance");
     let span = tracer.start("running binance");
     let cx = Context::current_with_span(span);
-    let log = log.new(o!("exchange" => "binance", "symbol" => format!("{:?}", symbol)));
-    info!(log, "running binance");
+    info!(log, "running binance"; "symbols" => format!("{:?}", symbols));
 
+    let streams: HashMap<String, Symbol> = symbols.iter()
+        .map(|symbol| (stream_name(symbol, depth, book_mode), symbol.clone()))
+        .collect();
     let connect_addr = format!(
-        "wss://stream.binance.com:9443/ws/{}@depth{}@100ms", symbol_to_string(symbol), depth,
+        "{}/stream?streams={}",
+        endpoint.websocket, streams.keys().cloned().collect::<Vec<_>>().join("/"),
     );
 
     let url = url::Url::parse(&connect_addr)?;
@@ -87,12 +261,22 @@ pub async fn run_binance(
 
     let (ws_stream, _) = connect_async(url)
         .with_context(cx.clone())
-        .await.expect("Failed to connect");
+        .await?;
     info!(log, "WebSocket handshake has been successfully completed");
 
     let (_, mut read) = ws_stream.split();
 
-    let mut shutdown_receiver= shutdown_receiver;
+    // The snapshots are fetched after subscribing, so the updates after them are buffered in the stream
+    let mut diff_books = HashMap::new();
+    if book_mode == BookMode::Diff {
+        for symbol in symbols {
+            let log = log.new(o!("symbol" => symbol.to_string()));
+            diff_books.insert(
+                symbol.clone(),
+                fetch_diff_book(&log, &endpoint.rest, symbol, depth).with_context(cx.clone()).await?,
+            );
+        }
+    }
 
     loop {
         tokio::select! {
@@ -101,18 +285,117 @@ pub async fn run_binance(
                     debug!(log, "websocket got message");
                     match message {
                         Ok(message_data) => {
+                            let received_timestamp = SystemTime::now();
                             let message_data = message_data.into_data();
-                            let binance_parse: serde_json::Result<DepthSnapshot> = serde_json::from_slice(
+                            summary_txs.record("binance", received_timestamp, &message_data);
+                            if book_mode == BookMode::Diff {
+                                let binance_parse: serde_json::Result<StreamMessage<DepthUpdate>> = serde_json::from_slice(
+                                    &message_data,
+                                );
+
+                                match binance_parse {
+                                    Ok(StreamMessage { stream, data: depth_update }) => {
+                                        let (symbol, diff_book) = match streams.get(&stream)
+                                            .and_then(|symbol| diff_books.get_mut(symbol).map(|book| (symbol, book))) {
+                                            Some(entry) => entry,
+                                            None => {
+                                                warn!(log, "message from an unknown stream"; "stream" => stream);
+                                                continue;
+                                            }
+                                        };
+                                        let log = log.new(o!("symbol" => symbol.to_string()));
+
+                                        match diff_book.apply(&depth_update) {
+                                            Ok(Sequence::Applied) => {
+                                                let summary = diff_book.book.summary(depth).with_timestamps(
+                                                    Some(UNIX_EPOCH + Duration::from_millis(depth_update.event_time)),
+                                                    received_timestamp,
+                                                );
+                                                if let Err(err) = summary_txs.send(symbol, summary) {
+                                                    error!(
+                                                        log, "error sending information to the channel";
+                                                        "error" => format!("{}", err)
+                                                    );
+                                                    cx.span().add_event(
+                                                        "error sending information to the channel",
+                                                        vec![
+                                                            Key::new("error").string(format!("{}", err)),
+                                                        ],
+                                                    );
+                                                }
+                                            }
+                                            Ok(Sequence::Outdated) => {
+                                                debug!(
+                                                    log, "ignoring outdated update";
+                                                    "final_update_id" => depth_update.final_update_id
+                                                );
+                                            }
+                                            Ok(Sequence::Gap) => {
+                                                warn!(
+                                                    log, "gap in the diff depth stream, fetching a new snapshot";
+                                                    "last_update_id" => diff_book.last_update_id,
+                                                    "first_update_id" => depth_update.first_update_id
+                                                );
+                                                cx.span().add_event(
+                                                    "gap in the diff depth stream",
+                                                    vec![
+                                                        Key::new("symbol").string(symbol.to_string()),
+                                                        Key::new("last_update_id").i64(diff_book.last_update_id as i64),
+                                                    ],
+                                                );
+                                                *diff_book = fetch_diff_book(&log, &endpoint.rest, symbol, depth)
+                                                    .with_context(cx.clone()).await?;
+                                            }
+                                            Err(err) => {
+                                                error!(
+                                                    log, "error converting WebSocket data to domain type";
+                                                    "error" => format!("{}", err)
+                                                );
+                                                cx.span().add_event(
+                                                    "error converting WebSocket data to domain type",
+                                                    vec![
+                                                        Key::new("error").string(format!("{:?}", err)),
+                                                    ],
+                                                );
+                                            }
+                                        }
+                                    }
+                                    Err(err) => {
+                                        error!(log, "error parsing WebSocket data"; "error" => format!("{}", err));
+                                        cx.span().add_event(
+                                            "error parsing WebSocket data",
+                                            vec![
+                                                Key::new("message").string(format!("{:?}", message_data)),
+                                                Key::new("error").string(format!("{}", err)),
+                                            ],
+                                        );
+                                    }
+                                }
+                                continue;
+                            }
+
+                            let binance_parse: serde_json::Result<StreamMessage<DepthSnapshot>> = serde_json::from_slice(
                                 &message_data,
                             );
 
                             match binance_parse {
-                                Ok(depth_update) => {
-                                    match depth_update.try_into() {
+                                Ok(StreamMessage { stream, data: depth_update }) => {
+                                    let symbol = match streams.get(&stream) {
+                                        Some(symbol) => symbol,
+                                        None => {
+                                            warn!(log, "message from an unknown stream"; "stream" => stream);
+                                            continue;
+                                        }
+                                    };
+
+                                    match TryInto::<Summary>::try_into(depth_update) {
                                         Ok(summary) => {
-                                            if let Err(err) = summary_tx.send(summary) {
+                                            // The partial book stream has no event time
+                                            let summary = summary.with_timestamps(None, received_timestamp);
+                                            if let Err(err) = summary_txs.send(symbol, summary) {
                                                 error!(
                                                     log, "error sending information to the channel";
+                                                    "symbol" => symbol.to_string(),
                                                     "error" => format!("{}", err)
                                                 );
                                                 cx.span().add_event(
@@ -151,16 +434,17 @@ pub async fn run_binance(
                         }
                         Err(err) => {
                             error!(log, "problem fetching message"; "error" => format!("{}", err));
+                            return Err(Box::new(err));
                         }
                     }
                 } else {
                     info!(log, "no more messages run_binance");
-                    return Ok(());
+                    return Ok(ConnectionEnd::Closed);
                 }
             }
             _ = shutdown_receiver.recv() => {
                 info!(log, "application is shutting down, closing run_binance");
-                return Ok(());
+                return Ok(ConnectionEnd::Shutdown);
             }
         }
     }
@@ -170,14 +454,21 @@ pub async fn run_binance(
 mod test {
     use crate::{
         binance::{
+            stream_name,
             symbol_to_string,
             DepthSnapshot,
+            DepthUpdate,
+            DiffBook,
+            Sequence,
+            StreamMessage,
         },
+        source::BookMode,
         types::{
             Asset,
             Symbol,
         },
     };
+    use rust_decimal_macros::dec;
 
     #[test]
     fn should_parse_data() {
@@ -188,10 +479,103 @@ mod test {
         let resp: DepthSnapshot = serde_json::from_str(msg).unwrap();
 
         // Then
+        assert_eq!(6062044077, resp.last_update_id);
         assert_eq!(2, resp.bids.len());
         assert_eq!(3, resp.asks.len());
     }
 
+    #[test]
+    fn should_parse_combined_stream_data() {
+        // Given
+        let msg = r#"{"stream":"ethbtc@depth10@100ms","data":{"lastUpdateId":6062044077,"bids":[["0.06754400","31.99050000"]],"asks":[["0.06754500","27.06160000"]]}}"#;
+
+        // When
+        let resp: StreamMessage<DepthSnapshot> = serde_json::from_str(msg).unwrap();
+
+        // Then
+        let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+        assert_eq!(stream_name(&symbol, 10, BookMode::Snapshot), resp.stream);
+        assert_eq!(6062044077, resp.data.last_update_id);
+    }
+
+    #[test]
+    fn should_parse_diff_data() {
+        // Given
+        let msg = r#"{"e":"depthUpdate","E":1666200249249,"s":"ETHBTC","U":6062044078,"u":6062044080,"b":[["0.06754400","0.00000000"]],"a":[["0.06754500","20.00000000"],["0.06754800","1.00000000"]]}"#;
+
+        // When
+        let resp: DepthUpdate = serde_json::from_str(msg).unwrap();
+
+        // Then
+        assert_eq!(1666200249249, resp.event_time);
+        assert_eq!(6062044078, resp.first_update_id);
+        assert_eq!(6062044080, resp.final_update_id);
+        assert_eq!(1, resp.bids.len());
+        assert_eq!(2, resp.asks.len());
+    }
+
+    fn diff_book() -> DiffBook {
+        let msg = r#"{"lastUpdateId":100,"bids":[["0.06754400","31.99050000"],["0.06754300","4.60890000"]],"asks":[["0.06754500","27.06160000"]]}"#;
+        DiffBook::new(serde_json::from_str(msg).unwrap()).unwrap()
+    }
+
+    fn depth_update(first_update_id: u64, final_update_id: u64) -> DepthUpdate {
+        DepthUpdate {
+            event_time: 1666200249249,
+            first_update_id,
+            final_update_id,
+            bids: vec![vec!["0.06754400".to_string(), "0.00000000".to_string()]],
+            asks: vec![vec!["0.06754600".to_string(), "1.00000000".to_string()]],
+        }
+    }
+
+    #[test]
+    fn should_apply_diff_updates_in_sequence() {
+        // Given
+        let mut book = diff_book();
+
+        // When
+        let outdated = book.apply(&depth_update(95, 100)).unwrap();
+        let first = book.apply(&depth_update(98, 102)).unwrap();
+        let second = book.apply(&depth_update(103, 105)).unwrap();
+
+        // Then
+        assert_eq!(Sequence::Outdated, outdated);
+        assert_eq!(Sequence::Applied, first);
+        assert_eq!(Sequence::Applied, second);
+        assert_eq!(105, book.last_update_id);
+        let summary = book.book.summary(10);
+        assert_eq!(1, summary.bids.len());
+        assert_eq!(dec!(0.067543), summary.bids[0].price);
+        assert_eq!(2, summary.asks.len());
+    }
+
+    #[test]
+    fn should_detect_gap_in_diff_updates() {
+        // Given
+        let mut book = diff_book();
+        book.apply(&depth_update(101, 102)).unwrap();
+
+        // When
+        let resp = book.apply(&depth_update(104, 106)).unwrap();
+
+        // Then
+        assert_eq!(Sequence::Gap, resp);
+        assert_eq!(102, book.last_update_id);
+    }
+
+    #[test]
+    fn should_detect_gap_after_snapshot() {
+        // Given
+        let mut book = diff_book();
+
+        // When
+        let resp = book.apply(&depth_update(102, 103)).unwrap();
+
+        // Then
+        assert_eq!(Sequence::Gap, resp);
+    }
+
     #[test]
     fn should_convert_symbol() {
         // Given
@@ -1,8 +1,19 @@
-use crate::types::{
-    Level,
-    Symbol,
-    Summary,
-    MBooksError,
+use crate::{
+    book::LocalBook,
+    source::{
+        BookMode,
+        ConnectionEnd,
+        Endpoint,
+        Source,
+        SourceConfig,
+        SummarySenders,
+    },
+    types::{
+        Level,
+        Symbol,
+        Summary,
+        MBooksError,
+    },
 };
 use futures_util::{
     SinkExt,
@@ -25,44 +36,64 @@ use slog::{
     Logger,
     info,
     o,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
 };
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::broadcast;
 use tokio_tungstenite::{
     connect_async,
     tungstenite::protocol::Message,
 };
 
+const WEBSOCKET_URL: &str = "wss://ws.bitstamp.net";
+const REST_URL: &str = "https://www.bitstamp.net";
+
+/// Book data, used by the `order_book` and `diff_order_book` channels and by the REST order book.
 #[derive(Debug, Deserialize)]
 struct Data {
+    microtimestamp: String,
     bids: Vec<Vec<String>>,
     asks: Vec<Vec<String>>,
 }
 
+impl Data {
+    fn microtimestamp(&self) -> Result<u64, MBooksError> {
+        self.microtimestamp.parse::<u64>()
+            .map_err(|_| MBooksError::InvalidTimestamp(self.microtimestamp.clone()))
+    }
+
+    /// When the exchange generated the data, if the `microtimestamp` is valid.
+    fn exchange_timestamp(&self) -> Option<SystemTime> {
+        self.microtimestamp().ok()
+            .map(|microtimestamp| UNIX_EPOCH + Duration::from_micros(microtimestamp))
+    }
+}
+
 impl TryInto<Summary> for Data {
     type Error = MBooksError;
 
     fn try_into(self) -> Result<Summary, Self::Error> {
         let mut bids = Vec::with_capacity(self.bids.len());
         for bid in &self.bids {
-            bids.push(Level {
-                exchange: "bitstamp".to_string(),
-                price: bid[0].parse::<f64>().map_err(MBooksError::ParseError)?,
-                quantity: bid[1].parse::<f64>().map_err(MBooksError::ParseError)?,
-            });
+            bids.push(Level::parse("bitstamp", bid)?);
         }
 
         let mut asks = Vec::with_capacity(self.asks.len());
         for ask in &self.asks {
-            asks.push(Level {
-                exchange: "bitstamp".to_string(),
-                price: ask[0].parse::<f64>().map_err(MBooksError::ParseError)?,
-                quantity: ask[1].parse::<f64>().map_err(MBooksError::ParseError)?,
-            });
+            asks.push(Level::parse("bitstamp", ask)?);
         }
 
         Ok(Summary {
             bids,
             asks,
+            ..Default::default()
         })
     }
 }
@@ -72,45 +103,170 @@ impl TryInto<Summary> for Data {
 enum WebSocketEvent {
     #[serde(rename(deserialize = "bts:subscription_succeeded"))]
     Succeeded,
+    #[serde(rename(deserialize = "bts:request_reconnect"))]
+    RequestReconnect,
     #[serde(rename(deserialize = "data"))]
-    Data { data: Data },
+    Data { channel: String, data: Data },
+}
+
+/// DiffBook keeps the local book from the `diff_order_book` channel.
+/// It starts from the REST order book and applies only the updates newer than the last one applied,
+/// using the `microtimestamp` to sequence them.
+struct DiffBook {
+    book: LocalBook,
+    microtimestamp: u64,
+}
+
+impl DiffBook {
+    fn new(snapshot: Data) -> Result<Self, MBooksError> {
+        let mut book = LocalBook::new("bitstamp");
+        book.update_bids(&snapshot.bids)?;
+        book.update_asks(&snapshot.asks)?;
+
+        Ok(Self {
+            book,
+            microtimestamp: snapshot.microtimestamp()?,
+        })
+    }
+
+    /// Applies the update returning if it was newer than the book.
+    /// Both sides are parsed first, so the book is left as it was when a level is invalid.
+    fn apply(&mut self, data: &Data) -> Result<bool, MBooksError> {
+        let microtimestamp = data.microtimestamp()?;
+        if microtimestamp <= self.microtimestamp {
+            return Ok(false);
+        }
+
+        let parse = |levels: &[Vec<String>]| levels.iter()
+            .map(|level| Level::parse("bitstamp", level))
+            .collect::<Result<Vec<_>, _>>();
+        let (bids, asks) = (parse(&data.bids)?, parse(&data.asks)?);
+        for bid in bids {
+            self.book.update_bid(bid.price, bid.quantity);
+        }
+        for ask in asks {
+            self.book.update_ask(ask.price, ask.quantity);
+        }
+        self.microtimestamp = microtimestamp;
+
+        Ok(true)
+    }
 }
 
 fn symbol_to_string(symbol: &Symbol) -> String {
     format!("{}{}", symbol.base.to_string(), symbol.quote.to_string()).to_lowercase()
 }
 
-pub async fn run_bitstamp(
-    log: Logger,
-    shutdown_receiver: tokio::sync::broadcast::Receiver<String>,
-    summary_tx: UnboundedSender<Summary>,
-    symbol: &Symbol, depth: usize,
-) -> Result<(), Box<dyn std::error::Error>> {
+/// Fetches the REST order book used to start the `DiffBook`.
+async fn fetch_diff_book(
+    log: &Logger, rest_url: &str, symbol: &Symbol,
+) -> Result<DiffBook, Box<dyn std::error::Error>> {
+    let url = format!(
+        "{}/api/v2/order_book/{}/", rest_url, symbol_to_string(symbol),
+    );
+    info!(log, "fetching bitstamp order book"; "url" => &url);
+
+    let snapshot: Data = reqwest::get(url).await?
+        .error_for_status()?
+        .json().await?;
+    info!(log, "got bitstamp order book"; "microtimestamp" => &snapshot.microtimestamp);
+
+    Ok(DiffBook::new(snapshot)?)
+}
+
+/// Name of the channel with the book of the symbol.
+fn channel_name(symbol: &Symbol, book_mode: BookMode) -> String {
+    let channel = match book_mode {
+        BookMode::Snapshot => "order_book",
+        BookMode::Diff => "diff_order_book",
+    };
+    format!("{}_{}", channel, symbol_to_string(symbol))
+}
+
+/// Bitstamp `Source` implementation, all the symbols are subscribed in the same connection.
+pub struct Bitstamp {
+    symbols: Vec<Symbol>,
+    depth: usize,
+    book_mode: BookMode,
+    endpoint: Endpoint,
+}
+
+impl Bitstamp {
+    pub fn new(config: SourceConfig) -> Self {
+        Self {
+            endpoint: config.endpoints.endpoint("bitstamp", WEBSOCKET_URL, REST_URL),
+            symbols: config.symbols,
+            depth: config.depth,
+            book_mode: config.book_mode,
+        }
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for Bitstamp {
+    fn name(&self) -> &'static str {
+        "bitstamp"
+    }
+
+    fn symbol_to_string(&self, symbol: &Symbol) -> String {
+        symbol_to_string(symbol)
+    }
+
+    async fn connect(
+        &self,
+        log: &Logger,
+        shutdown_receiver: &mut broadcast::Receiver<String>,
+        summary_txs: &SummarySenders,
+    ) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+        run_bitstamp(
+            log, shutdown_receiver, summary_txs, &self.symbols, self.depth, self.book_mode, &self.endpoint,
+        ).await
+    }
+}
+
+/// Connects to Bitstamp and streams the books until the connection ends.
+async fn run_bitstamp(
+    log: &Logger,
+    shutdown_receiver: &mut broadcast::Receiver<String>,
+    summary_txs: &SummarySenders,
+    symbols: &[Symbol], depth: usize, book_mode: BookMode, endpoint: &Endpoint,
+) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
     let tracer = global::tracer("run_bitstamp");
     let span = tracer.start("running bitstamp");
     let cx = Context::current_with_span(span);
-    let log = log.new(o!("exchange" => "bitstamp", "symbol" => format!("{:?}", symbol)));
-    info!(log, "running bitstamp");
+    info!(log, "running bitstamp"; "symbols" => format!("{:?}", symbols));
 
-    let connect_addr = "wss://ws.bitstamp.net";
-
-    let url = url::Url::parse(connect_addr)?;
+    let url = url::Url::parse(&endpoint.websocket)?;
     info!(log, "bitstamp url"; "url" => format!("{:?}", url));
 
     let (ws_stream, _) = connect_async(url)
         .with_context(cx.clone())
-        .await.expect("Failed to connect");
+        .await?;
     info!(log, "WebSocket handshake has been successfully completed");
 
     let (mut write, mut read) = ws_stream.split();
-    write.send(Message::Text(
-        format!(
-            "{{\"event\":\"bts:subscribe\",\"data\":{{\"channel\": \"order_book_{}\"}}}}",
-            symbol_to_string(symbol),
-        ))
-    ).with_context(cx.clone()).await?;
+    let mut channels = HashMap::with_capacity(symbols.len());
+    for symbol in symbols {
+        let channel = channel_name(symbol, book_mode);
+        write.send(Message::Text(
+            format!(
+                "{{\"event\":\"bts:subscribe\",\"data\":{{\"channel\": \"{}\"}}}}",
+                channel,
+            ))
+        ).with_context(cx.clone()).await?;
+        channels.insert(channel, symbol.clone());
+    }
 
-    let mut shutdown_receiver = shutdown_receiver;
+    // The order books are fetched after subscribing, so the updates after them are buffered in the stream
+    let mut diff_books = HashMap::new();
+    if book_mode == BookMode::Diff {
+        for symbol in symbols {
+            let log = log.new(o!("symbol" => symbol.to_string()));
+            diff_books.insert(
+                symbol.clone(), fetch_diff_book(&log, &endpoint.rest, symbol).with_context(cx.clone()).await?,
+            );
+        }
+    }
 
     loop {
         tokio::select! {
@@ -119,7 +275,9 @@ pub async fn run_bitstamp(
                     debug!(log, "websocket got message");
                     match message {
                         Ok(message_data) => {
+                            let received_timestamp = SystemTime::now();
                             let message_data = message_data.into_data();
+                            summary_txs.record("bitstamp", received_timestamp, &message_data);
                             let bitstamp_parse: serde_json::Result<WebSocketEvent> = serde_json::from_slice(
                                 &message_data,
                             );
@@ -128,7 +286,65 @@ pub async fn run_bitstamp(
                                 Ok(event) => {
                                     match event {
                                         WebSocketEvent::Succeeded => {}
-                                        WebSocketEvent::Data { mut data } => {
+                                        WebSocketEvent::RequestReconnect => {
+                                            info!(log, "bitstamp requested a reconnection");
+                                            return Ok(ConnectionEnd::Closed);
+                                        }
+                                        WebSocketEvent::Data { channel, mut data } => {
+                                            let symbol = match channels.get(&channel) {
+                                                Some(symbol) => symbol,
+                                                None => {
+                                                    warn!(log, "data from an unknown channel"; "channel" => channel);
+                                                    continue;
+                                                }
+                                            };
+
+                                            if let Some(diff_book) = diff_books.get_mut(symbol) {
+                                                match diff_book.apply(&data) {
+                                                    Ok(true) => {
+                                                        let summary = diff_book.book.summary(depth).with_timestamps(
+                                                            data.exchange_timestamp(), received_timestamp,
+                                                        );
+                                                        if let Err(err) = summary_txs.send(symbol, summary) {
+                                                            error!(
+                                                                log, "error information to the channel";
+                                                                "symbol" => symbol.to_string(),
+                                                                "error" => format!("{}", err)
+                                                            );
+                                                            cx.span().add_event(
+                                                                "error information to the channel",
+                                                                vec![
+                                                                    Key::new("error").string(format!("{}", err)),
+                                                                ],
+                                                            );
+                                                        }
+                                                    }
+                                                    Ok(false) => {
+                                                        debug!(
+                                                            log, "ignoring outdated update";
+                                                            "microtimestamp" => &data.microtimestamp
+                                                        );
+                                                    }
+                                                    Err(err) => {
+                                                        error!(
+                                                            log, "invalid diff update, fetching a new order book";
+                                                            "symbol" => symbol.to_string(),
+                                                            "error" => format!("{}", err)
+                                                        );
+                                                        cx.span().add_event(
+                                                            "invalid diff update",
+                                                            vec![
+                                                                Key::new("error").string(format!("{:?}", err)),
+                                                            ],
+                                                        );
+                                                        let log = log.new(o!("symbol" => symbol.to_string()));
+                                                        *diff_book = fetch_diff_book(&log, &endpoint.rest, symbol)
+                                                            .with_context(cx.clone()).await?;
+                                                    }
+                                                }
+                                                continue;
+                                            }
+
                                             // Keeping only the updates within the depth
                                             if data.bids.len() > depth as usize {
                                                 data.bids.truncate(depth);
@@ -137,11 +353,14 @@ pub async fn run_bitstamp(
                                                 data.asks.truncate(depth);
                                             }
 
+                                            let exchange_timestamp = data.exchange_timestamp();
                                             match TryInto::<Summary>::try_into(data) {
                                                 Ok(summary) => {
-                                                    if let Err(err) = summary_tx.send(summary) {
+                                                    let summary = summary.with_timestamps(exchange_timestamp, received_timestamp);
+                                                    if let Err(err) = summary_txs.send(symbol, summary) {
                                                         error!(
                                                             log, "error information to the channel";
+                                                            "symbol" => symbol.to_string(),
                                                             "error" => format!("{}", err)
                                                         );
                                                         cx.span().add_event(
@@ -182,13 +401,17 @@ pub async fn run_bitstamp(
                         }
                         Err(err) => {
                             error!(log, "problem fetching message"; "error" => format!("{}", err));
+                            return Err(Box::new(err));
                         }
                     }
+                } else {
+                    info!(log, "no more messages run_bitstamp");
+                    return Ok(ConnectionEnd::Closed);
                 }
             }
             _ = shutdown_receiver.recv() => {
                 info!(log, "application is shutting down, closing run_bitstamp");
-                return Ok(());
+                return Ok(ConnectionEnd::Shutdown);
             }
         }
     }
@@ -198,14 +421,19 @@ pub async fn run_bitstamp(
 mod test {
     use crate::{
         bitstamp::{
+            channel_name,
             symbol_to_string,
+            Data,
+            DiffBook,
             WebSocketEvent,
         },
+        source::BookMode,
         types::{
             Asset,
             Symbol,
         },
     };
+    use rust_decimal_macros::dec;
 
     #[test]
     fn should_parse_a_subscribe() {
@@ -223,6 +451,18 @@ mod test {
         }
     }
 
+    #[test]
+    fn should_parse_a_request_reconnect() {
+        // Given
+        let msg = r#"{"event":"bts:request_reconnect","channel":"","data":""}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        assert!(matches!(resp, WebSocketEvent::RequestReconnect));
+    }
+
     #[test]
     fn should_parse_data() {
         // Given
@@ -232,7 +472,9 @@ mod test {
         let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
 
         // Then
-        if let WebSocketEvent::Data { data } = resp {
+        if let WebSocketEvent::Data { channel, data } = resp {
+            let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+            assert_eq!(channel_name(&symbol, BookMode::Snapshot), channel);
             assert_eq!(2, data.bids.len());
             assert_eq!(3, data.asks.len());
         } else {
@@ -240,6 +482,78 @@ mod test {
         }
     }
 
+    #[test]
+    fn should_parse_diff_data() {
+        // Given
+        let msg = r#"{"data":{"timestamp":"1666200250","microtimestamp":"1666200250012345","bids":[["0.06760079","0.00000000"]],"asks":[["0.06764067","1.25000000"]]},"channel":"diff_order_book_ethbtc","event":"data"}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        if let WebSocketEvent::Data { channel, data } = resp {
+            let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+            assert_eq!(channel_name(&symbol, BookMode::Diff), channel);
+            assert_eq!(1666200250012345, data.microtimestamp().unwrap());
+            assert_eq!(1, data.bids.len());
+            assert_eq!(1, data.asks.len());
+        } else {
+            panic!("not a data");
+        }
+    }
+
+    fn data(microtimestamp: &str, bid: (&str, &str)) -> Data {
+        Data {
+            microtimestamp: microtimestamp.to_string(),
+            bids: vec![vec![bid.0.to_string(), bid.1.to_string()]],
+            asks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn should_apply_only_newer_diff_updates() {
+        // Given
+        let snapshot: Data = serde_json::from_str(
+            r#"{"timestamp":"1666200249","microtimestamp":"1666200249249913","bids":[["0.06760079","0.55000000"],["0.06759456","5.79242377"]],"asks":[["0.06764067","0.55000000"]]}"#,
+        ).unwrap();
+        let mut book = DiffBook::new(snapshot).unwrap();
+
+        // When
+        let outdated = book.apply(&data("1666200249249000", ("0.06759456", "0"))).unwrap();
+        let removed = book.apply(&data("1666200249250000", ("0.06760079", "0.00000000"))).unwrap();
+        let added = book.apply(&data("1666200249260000", ("0.06761000", "1.00000000"))).unwrap();
+
+        // Then
+        assert!(!outdated);
+        assert!(removed);
+        assert!(added);
+        assert_eq!(1666200249260000, book.microtimestamp);
+        let summary = book.book.summary(10);
+        assert_eq!(2, summary.bids.len());
+        assert_eq!(dec!(0.06761), summary.bids[0].price);
+        assert_eq!(dec!(0.06759456), summary.bids[1].price);
+        assert_eq!(1, summary.asks.len());
+    }
+
+    #[test]
+    fn should_not_apply_part_of_an_invalid_diff_update() {
+        // Given
+        let snapshot: Data = serde_json::from_str(
+            r#"{"timestamp":"1666200249","microtimestamp":"1666200249249913","bids":[["0.06760079","0.55000000"]],"asks":[["0.06764067","0.55000000"]]}"#,
+        ).unwrap();
+        let mut book = DiffBook::new(snapshot).unwrap();
+        let mut update = data("1666200249250000", ("0.06760079", "0"));
+        update.asks = vec![vec!["invalid".to_string(), "1.0".to_string()]];
+
+        // When
+        let resp = book.apply(&update);
+
+        // Then
+        assert!(resp.is_err());
+        assert_eq!(1666200249249913, book.microtimestamp);
+        assert_eq!(1, book.book.summary(10).bids.len());
+    }
+
     #[test]
     fn should_convert_symbol() {
         // Given
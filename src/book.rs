@@ -0,0 +1,204 @@
+use crate::types::{
+    Level,
+    MBooksError,
+    Summary,
+};
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+
+/// LocalBook keeps the whole book of an exchange for the sources that receive incremental updates,
+/// so it is possible to produce the `Summary` with the best levels after every update.
+pub struct LocalBook {
+    exchange: &'static str,
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+}
+
+impl LocalBook {
+    pub fn new(exchange: &'static str) -> Self {
+        Self {
+            exchange,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+        }
+    }
+
+    /// Sets the quantity for a bid price, a zero quantity removes the level.
+    pub fn update_bid(&mut self, price: Decimal, quantity: Decimal) {
+        Self::update(&mut self.bids, price, quantity);
+    }
+
+    /// Sets the quantity for an ask price, a zero quantity removes the level.
+    pub fn update_ask(&mut self, price: Decimal, quantity: Decimal) {
+        Self::update(&mut self.asks, price, quantity);
+    }
+
+    /// Applies the bids in the exchange format `[price, quantity, ...]`.
+    pub fn update_bids(&mut self, bids: &[Vec<String>]) -> Result<(), MBooksError> {
+        for bid in bids {
+            let level = Level::parse(self.exchange, bid)?;
+            self.update_bid(level.price, level.quantity);
+        }
+        Ok(())
+    }
+
+    /// Applies the asks in the exchange format `[price, quantity, ...]`.
+    pub fn update_asks(&mut self, asks: &[Vec<String>]) -> Result<(), MBooksError> {
+        for ask in asks {
+            let level = Level::parse(self.exchange, ask)?;
+            self.update_ask(level.price, level.quantity);
+        }
+        Ok(())
+    }
+
+    fn update(side: &mut BTreeMap<Decimal, Decimal>, price: Decimal, quantity: Decimal) {
+        if quantity.is_zero() {
+            side.remove(&price);
+        } else {
+            side.insert(price, quantity);
+        }
+    }
+
+    fn level(&self, price: &Decimal, quantity: &Decimal) -> Level {
+        Level {
+            exchange: self.exchange.to_string(),
+            price: *price,
+            quantity: *quantity,
+            ..Default::default()
+        }
+    }
+
+    /// Removes the levels after the best `depth` ones of each side, for the exchanges that only
+    /// keep the book up to the subscribed depth.
+    pub fn truncate(&mut self, depth: usize) {
+        while self.bids.len() > depth {
+            let worst = *self.bids.keys().next().unwrap();
+            self.bids.remove(&worst);
+        }
+        while self.asks.len() > depth {
+            let worst = *self.asks.keys().next_back().unwrap();
+            self.asks.remove(&worst);
+        }
+    }
+
+    /// Generates a `Summary` with the best `depth` levels of each side.
+    pub fn summary(&self, depth: usize) -> Summary {
+        Summary {
+            bids: self.bids.iter().rev().take(depth)
+                .map(|(price, quantity)| self.level(price, quantity)).collect(),
+            asks: self.asks.iter().take(depth)
+                .map(|(price, quantity)| self.level(price, quantity)).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        book::LocalBook,
+        types::Level,
+    };
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn should_keep_levels_sorted() {
+        // Given
+        let mut book = LocalBook::new("binance");
+
+        // When
+        book.update_bid(dec!(1.0), dec!(10.0));
+        book.update_bid(dec!(1.1), dec!(5.0));
+        book.update_bid(dec!(0.9), dec!(1.0));
+        book.update_ask(dec!(2.1), dec!(10.0));
+        book.update_ask(dec!(2.0), dec!(5.0));
+        let summary = book.summary(2);
+
+        // Then
+        assert_eq!(
+            summary.bids,
+            vec![
+                Level {
+                    exchange: "binance".to_string(),
+                    price: dec!(1.1),
+                    quantity: dec!(5.0),
+                    ..Default::default()
+                },
+                Level {
+                    exchange: "binance".to_string(),
+                    price: dec!(1.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
+                },
+            ],
+        );
+        assert_eq!(
+            summary.asks,
+            vec![
+                Level {
+                    exchange: "binance".to_string(),
+                    price: dec!(2.0),
+                    quantity: dec!(5.0),
+                    ..Default::default()
+                },
+                Level {
+                    exchange: "binance".to_string(),
+                    price: dec!(2.1),
+                    quantity: dec!(10.0),
+                    ..Default::default()
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn should_remove_level_with_zero_quantity() {
+        // Given
+        let mut book = LocalBook::new("bitstamp");
+        book.update_bids(&[
+            vec!["1.0".to_string(), "10.0".to_string()],
+            vec!["0.9".to_string(), "1.0".to_string()],
+        ]).unwrap();
+
+        // When
+        book.update_bids(&[vec!["1.0".to_string(), "0.00000000".to_string()]]).unwrap();
+
+        // Then
+        let summary = book.summary(10);
+        assert_eq!(1, summary.bids.len());
+        assert_eq!(dec!(0.9), summary.bids[0].price);
+        assert!(summary.asks.is_empty());
+    }
+
+    #[test]
+    fn should_fail_parsing_incomplete_level() {
+        // Given
+        let mut book = LocalBook::new("bitstamp");
+
+        // When
+        let resp = book.update_asks(&[vec!["1.0".to_string()]]);
+
+        // Then
+        assert!(resp.is_err());
+    }
+
+    #[test]
+    fn should_truncate_worst_levels() {
+        // Given
+        let mut book = LocalBook::new("kraken");
+        book.update_bid(dec!(1.0), dec!(10.0));
+        book.update_bid(dec!(1.1), dec!(5.0));
+        book.update_ask(dec!(2.1), dec!(10.0));
+        book.update_ask(dec!(2.0), dec!(5.0));
+
+        // When
+        book.truncate(1);
+
+        // Then
+        let summary = book.summary(10);
+        assert_eq!(1, summary.bids.len());
+        assert_eq!(dec!(1.1), summary.bids[0].price);
+        assert_eq!(1, summary.asks.len());
+        assert_eq!(dec!(2.0), summary.asks[0].price);
+    }
+}
@@ -0,0 +1,396 @@
+use crate::{
+    book::LocalBook,
+    source::{
+        ConnectionEnd,
+        Source,
+        SourceConfig,
+        SummarySenders,
+    },
+    types::{
+        MBooksError,
+        Symbol,
+    },
+};
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+use opentelemetry::{
+    Context,
+    global,
+    Key,
+    trace::{
+        FutureExt,
+        TraceContextExt,
+        Tracer,
+    },
+};
+use serde_derive::Deserialize;
+use serde_json::json;
+use slog::{
+    debug,
+    error,
+    Logger,
+    info,
+    o,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use tokio::sync::broadcast;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::Message,
+};
+
+const WEBSOCKET_URL: &str = "wss://stream.bybit.com/v5/public/spot";
+/// Depths of the spot `orderbook` topic.
+const ORDERBOOK_DEPTHS: [usize; 4] = [1, 50, 200, 1000];
+/// Bybit closes the connection without a ping in the last 20 seconds.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Levels in the format `[price, size]`, the size is absolute and zero removes the level.
+#[derive(Debug, Deserialize)]
+struct OrderbookData {
+    #[serde(rename(deserialize = "s"))]
+    symbol: String,
+    #[serde(rename(deserialize = "b"))]
+    bids: Vec<Vec<String>>,
+    #[serde(rename(deserialize = "a"))]
+    asks: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum WebSocketEvent {
+    /// Data pushed for a subscribed topic.
+    Topic {
+        topic: String,
+        #[serde(rename(deserialize = "type"))]
+        kind: String,
+        /// Time in milliseconds.
+        ts: u64,
+        data: OrderbookData,
+    },
+    /// Response to the `subscribe` and `ping` requests.
+    Response {
+        op: String,
+        success: bool,
+        ret_msg: String,
+    },
+}
+
+fn symbol_to_string(symbol: &Symbol) -> String {
+    format!("{}{}", symbol.base.to_string(), symbol.quote.to_string()).to_uppercase()
+}
+
+/// Smallest depth of the `orderbook` topic with at least `depth` levels.
+fn orderbook_depth(depth: usize) -> usize {
+    ORDERBOOK_DEPTHS.iter().copied()
+        .find(|orderbook_depth| *orderbook_depth >= depth)
+        .unwrap_or(ORDERBOOK_DEPTHS[ORDERBOOK_DEPTHS.len() - 1])
+}
+
+/// Applies a `snapshot` or `delta` message to the book, a snapshot always replaces it.
+fn apply_orderbook<'a>(
+    books: &'a mut HashMap<String, LocalBook>, kind: &str, data: &OrderbookData,
+) -> Result<Option<&'a LocalBook>, MBooksError> {
+    if kind == "snapshot" {
+        books.insert(data.symbol.clone(), LocalBook::new("bybit"));
+    }
+    let book = match books.get_mut(&data.symbol) {
+        Some(book) => book,
+        None => return Ok(None),
+    };
+    book.update_bids(&data.bids)?;
+    book.update_asks(&data.asks)?;
+    Ok(Some(book))
+}
+
+/// Bybit `Source` implementation, it only supports the spot `orderbook` topic which is kept in a
+/// `LocalBook` for each symbol.
+pub struct Bybit {
+    symbols: Vec<Symbol>,
+    depth: usize,
+    websocket_url: String,
+}
+
+impl Bybit {
+    pub fn new(config: SourceConfig) -> Self {
+        Self {
+            websocket_url: config.endpoints.websocket("bybit").unwrap_or(WEBSOCKET_URL).to_string(),
+            symbols: config.symbols,
+            depth: config.depth,
+        }
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for Bybit {
+    fn name(&self) -> &'static str {
+        "bybit"
+    }
+
+    fn symbol_to_string(&self, symbol: &Symbol) -> String {
+        symbol_to_string(symbol)
+    }
+
+    async fn connect(
+        &self,
+        log: &Logger,
+        shutdown_receiver: &mut broadcast::Receiver<String>,
+        summary_txs: &SummarySenders,
+    ) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+        run_bybit(log, shutdown_receiver, summary_txs, &self.symbols, self.depth, &self.websocket_url).await
+    }
+}
+
+/// Connects to Bybit and streams the books until the connection ends.
+async fn run_bybit(
+    log: &Logger,
+    shutdown_receiver: &mut broadcast::Receiver<String>,
+    summary_txs: &SummarySenders,
+    symbols: &[Symbol], depth: usize, websocket_url: &str,
+) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+    let tracer = global::tracer("run_bybit");
+    let span = tracer.start("running bybit");
+    let cx = Context::current_with_span(span);
+    info!(log, "running bybit"; "symbols" => format!("{:?}", symbols));
+
+    let url = url::Url::parse(websocket_url)?;
+    info!(log, "bybit url"; "url" => format!("{:?}", url));
+
+    let (ws_stream, _) = connect_async(url)
+        .with_context(cx.clone())
+        .await?;
+    info!(log, "WebSocket handshake has been successfully completed");
+
+    let markets: HashMap<String, Symbol> = symbols.iter()
+        .map(|symbol| (symbol_to_string(symbol), symbol.clone()))
+        .collect();
+    let topics: Vec<String> = markets.keys()
+        .map(|market| format!("orderbook.{}.{}", orderbook_depth(depth), market))
+        .collect();
+    let (mut write, mut read) = ws_stream.split();
+    write.send(Message::Text(
+        json!({"op": "subscribe", "args": topics}).to_string()
+    )).with_context(cx.clone()).await?;
+
+    // The books are only available after the snapshot of each symbol
+    let mut books: HashMap<String, LocalBook> = HashMap::new();
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+
+    loop {
+        tokio::select! {
+            message = read.next() => {
+                if let Some(message) = message {
+                    debug!(log, "websocket got message");
+                    match message {
+                        Ok(message_data) => {
+                            let received_timestamp = SystemTime::now();
+                            let message_data = message_data.into_data();
+                            summary_txs.record("bybit", received_timestamp, &message_data);
+                            let bybit_parse: serde_json::Result<WebSocketEvent> = serde_json::from_slice(
+                                &message_data,
+                            );
+
+                            let (kind, ts, data) = match bybit_parse {
+                                Ok(WebSocketEvent::Topic { topic, kind, ts, data }) => {
+                                    debug!(log, "bybit topic"; "topic" => topic);
+                                    (kind, ts, data)
+                                }
+                                Ok(WebSocketEvent::Response { op, success, ret_msg }) => {
+                                    if !success {
+                                        error!(log, "bybit request failed"; "op" => &op, "message" => &ret_msg);
+                                        return Err(Box::new(MBooksError::ExchangeError(ret_msg)));
+                                    }
+                                    continue;
+                                }
+                                Err(err) => {
+                                    error!(log, "error parsing WebSocket data"; "error" => format!("{}", err));
+                                    cx.span().add_event(
+                                        "error parsing WebSocket data",
+                                        vec![
+                                            Key::new("message").string(format!("{:?}", message_data)),
+                                            Key::new("error").string(format!("{}", err)),
+                                        ],
+                                    );
+                                    continue;
+                                }
+                            };
+
+                            let symbol = match markets.get(&data.symbol) {
+                                Some(symbol) => symbol,
+                                None => {
+                                    warn!(log, "book from an unknown symbol"; "symbol" => &data.symbol);
+                                    continue;
+                                }
+                            };
+                            let log = log.new(o!("symbol" => data.symbol.clone()));
+
+                            match apply_orderbook(&mut books, &kind, &data) {
+                                Ok(Some(book)) => {
+                                    let summary = book.summary(depth).with_timestamps(
+                                        Some(UNIX_EPOCH + Duration::from_millis(ts)), received_timestamp,
+                                    );
+                                    if let Err(err) = summary_txs.send(symbol, summary) {
+                                        error!(
+                                            log, "error sending information to the channel";
+                                            "error" => format!("{}", err)
+                                        );
+                                        cx.span().add_event(
+                                            "error sending information to the channel",
+                                            vec![
+                                                Key::new("error").string(format!("{}", err)),
+                                            ],
+                                        );
+                                    }
+                                }
+                                Ok(None) => {
+                                    debug!(log, "ignoring delta before the snapshot");
+                                }
+                                Err(err) => {
+                                    error!(
+                                        log, "error converting WebSocket data to domain type";
+                                        "error" => format!("{}", err)
+                                    );
+                                    cx.span().add_event(
+                                        "error converting WebSocket data to domain type",
+                                        vec![
+                                            Key::new("error").string(format!("{:?}", err)),
+                                        ],
+                                    );
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            error!(log, "problem fetching message"; "error" => format!("{}", err));
+                            return Err(Box::new(err));
+                        }
+                    }
+                } else {
+                    info!(log, "no more messages run_bybit");
+                    return Ok(ConnectionEnd::Closed);
+                }
+            }
+            _ = ping.tick() => {
+                debug!(log, "sending ping");
+                write.send(Message::Text("{\"op\":\"ping\"}".to_string()))
+                    .with_context(cx.clone()).await?;
+            }
+            _ = shutdown_receiver.recv() => {
+                info!(log, "application is shutting down, closing run_bybit");
+                return Ok(ConnectionEnd::Shutdown);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        book::LocalBook,
+        bybit::{
+            apply_orderbook,
+            orderbook_depth,
+            symbol_to_string,
+            OrderbookData,
+            WebSocketEvent,
+        },
+        types::{
+            Asset,
+            Symbol,
+        },
+    };
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+
+    fn topic(msg: &str) -> (String, OrderbookData) {
+        match serde_json::from_str(msg).unwrap() {
+            WebSocketEvent::Topic { kind, data, .. } => (kind, data),
+            _ => panic!("not a topic"),
+        }
+    }
+
+    #[test]
+    fn should_parse_response() {
+        // Given
+        let msg = r#"{"success":true,"ret_msg":"pong","conn_id":"0970e817-426e-429a-a679-ff7f55e0b16a","op":"ping"}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        if let WebSocketEvent::Response { op, success, .. } = resp {
+            assert_eq!("ping", op);
+            assert!(success);
+        } else {
+            panic!("not a response");
+        }
+    }
+
+    #[test]
+    fn should_apply_snapshot_and_delta() {
+        // Given
+        let snapshot = r#"{"topic":"orderbook.50.ETHBTC","type":"snapshot","ts":1672304484978,"data":{"s":"ETHBTC","b":[["0.06754","12.5"],["0.06753","1.2"]],"a":[["0.06756","3.0"]],"u":177400507,"seq":66544703342},"cts":1672304484976}"#;
+        let delta = r#"{"topic":"orderbook.50.ETHBTC","type":"delta","ts":1672304485078,"data":{"s":"ETHBTC","b":[["0.06754","0"]],"a":[["0.06755","2.0"]],"u":177400508,"seq":66544703343},"cts":1672304485076}"#;
+        let mut books = HashMap::new();
+
+        // When
+        let (kind, data) = topic(snapshot);
+        apply_orderbook(&mut books, &kind, &data).unwrap();
+        let (kind, data) = topic(delta);
+        let book = apply_orderbook(&mut books, &kind, &data).unwrap().unwrap();
+
+        // Then
+        let summary = book.summary(10);
+        assert_eq!(1, summary.bids.len());
+        assert_eq!(dec!(0.06753), summary.bids[0].price);
+        assert_eq!(2, summary.asks.len());
+        assert_eq!(dec!(0.06755), summary.asks[0].price);
+    }
+
+    #[test]
+    fn should_ignore_delta_before_snapshot() {
+        // Given
+        let delta = r#"{"topic":"orderbook.50.ETHBTC","type":"delta","ts":1672304485078,"data":{"s":"ETHBTC","b":[],"a":[["0.06755","2.0"]],"u":177400508,"seq":66544703343},"cts":1672304485076}"#;
+        let mut books: HashMap<String, LocalBook> = HashMap::new();
+
+        // When
+        let (kind, data) = topic(delta);
+        let resp = apply_orderbook(&mut books, &kind, &data).unwrap();
+
+        // Then
+        assert!(resp.is_none());
+    }
+
+    #[test]
+    fn should_choose_orderbook_depth() {
+        // Given
+        let depths = vec![1, 10, 50, 5000];
+
+        // When
+        let resp: Vec<usize> = depths.into_iter().map(orderbook_depth).collect();
+
+        // Then
+        assert_eq!(vec![1, 50, 50, 1000], resp);
+    }
+
+    #[test]
+    fn should_convert_symbol() {
+        // Given
+        let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+
+        // When
+        let resp = symbol_to_string(&symbol);
+
+        // Then
+        assert_eq!("ETHBTC", resp)
+    }
+}
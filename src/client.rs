@@ -1,6 +1,13 @@
-use crate::orderbook::{
-    Empty,
-    orderbook_aggregator_client::OrderbookAggregatorClient,
+use crate::{
+    delta::DeltaBook,
+    orderbook::{
+        ArbitrageRequest,
+        ArbitrageUpdate,
+        BookSummaryRequest,
+        Empty,
+        orderbook_aggregator_client::OrderbookAggregatorClient,
+        Summary,
+    },
 };
 use opentelemetry::{
     Key,
@@ -19,11 +26,60 @@ use slog::{
 use tonic::Request;
 use tokio::sync::broadcast::Receiver;
 
+/// SummaryRequest is the view of the book requested by the client.
+#[derive(Clone, Debug, Default)]
+pub struct SummaryRequest {
+    /// The symbol, the first one served when not set.
+    pub symbol: Option<String>,
+    /// Levels on each side, 0 for the server depth.
+    pub depth: u32,
+    /// Exchanges to keep, all of them when empty.
+    pub exchanges: Vec<String>,
+    /// A single level per price with the amount of all the exchanges.
+    pub aggregated: bool,
+    /// Price step to group the levels in, the prices of the exchanges when not set.
+    pub bucket_size: Option<String>,
+}
+
+impl SummaryRequest {
+    /// Whether it only has the server defaults.
+    fn is_default(&self) -> bool {
+        self.symbol.is_none() && self.depth == 0 && self.exchanges.is_empty() && !self.aggregated
+            && self.bucket_size.is_none()
+    }
+}
+
+impl From<SummaryRequest> for BookSummaryRequest {
+    fn from(request: SummaryRequest) -> Self {
+        BookSummaryRequest {
+            symbol: request.symbol.unwrap_or_default(),
+            depth: request.depth,
+            exchanges: request.exchanges,
+            aggregated: request.aggregated,
+            bucket_size: request.bucket_size.unwrap_or_default(),
+        }
+    }
+}
+
 /// Connects to the server and listen to all received updates printing in the log.
+/// The `request` is only sent to the server when it is not the default one, otherwise it uses the
+/// server defaults.
 pub async fn run_client(
     log: Logger,
     shutdown_receiver: &mut Receiver<String>,
     address: String,
+    request: SummaryRequest,
+) -> Result<(), Box<dyn std::error::Error>> {
+    run_client_with(log, shutdown_receiver, address, request, |_| {}).await
+}
+
+/// Same as `run_client`, also handing every received summary to `on_summary`.
+pub(crate) async fn run_client_with<F: FnMut(&Summary)>(
+    log: Logger,
+    shutdown_receiver: &mut Receiver<String>,
+    address: String,
+    request: SummaryRequest,
+    mut on_summary: F,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let tracer = global::tracer("run_client");
     let span = tracer.start(format!("running client at: {}", address));
@@ -34,16 +90,23 @@ pub async fn run_client(
         address,
     ).with_context(cx.clone()).await?;
 
-    info!(log, "requesting book_summary");
-    let response = client.book_summary(Request::new(Empty {})).with_context(cx.clone()).await?;
+    let response = if request.is_default() {
+        info!(log, "requesting book_summary");
+        client.book_summary(Request::new(Empty {})).with_context(cx.clone()).await?
+    } else {
+        let request = BookSummaryRequest::from(request);
+        info!(log, "requesting filtered_book_summary"; "request" => format!("{:?}", request));
+        client.filtered_book_summary(Request::new(request)).with_context(cx.clone()).await?
+    };
     let mut inbound = response.into_inner();
 
     loop {
         tokio::select! {
             message = inbound.message().with_context(cx.clone()) => {
                 if let Some(summary) = message? {
-                    cx.span().add_event("got summary", vec![Key::new("spread").f64(summary.spread)]);
+                    cx.span().add_event("got summary", vec![Key::new("spread").string(summary.exact_spread.clone())]);
                     info!(log, "got a summary"; "summary" => format!("{:?}", summary));
+                    on_summary(&summary);
                 } else {
                     info!(log, "no more messages");
                     return Ok(());
@@ -55,4 +118,133 @@ pub async fn run_client(
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Same as `run_client` with the `BookDeltas` stream, so the server only sends the changes and the
+/// book is reassembled by a `DeltaBook`.
+/// It fails when a delta is missing, since the book is no longer valid.
+pub async fn run_delta_client(
+    log: Logger,
+    shutdown_receiver: &mut Receiver<String>,
+    address: String,
+    request: SummaryRequest,
+) -> Result<(), Box<dyn std::error::Error>> {
+    run_delta_client_with(log, shutdown_receiver, address, request, |_| {}).await
+}
+
+/// Same as `run_delta_client`, also handing every reassembled summary to `on_summary`.
+pub(crate) async fn run_delta_client_with<F: FnMut(&Summary)>(
+    log: Logger,
+    shutdown_receiver: &mut Receiver<String>,
+    address: String,
+    request: SummaryRequest,
+    mut on_summary: F,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tracer = global::tracer("run_delta_client");
+    let span = tracer.start(format!("running delta client at: {}", address));
+    let cx = Context::current_with_span(span);
+
+    info!(log, "starting client"; "address" => &address);
+    let mut client = OrderbookAggregatorClient::connect(
+        address,
+    ).with_context(cx.clone()).await?;
+
+    let request = BookSummaryRequest::from(request);
+    info!(log, "requesting book_deltas"; "request" => format!("{:?}", request));
+    let mut inbound = client.book_deltas(Request::new(request)).with_context(cx.clone()).await?.into_inner();
+
+    let mut book = DeltaBook::new();
+    loop {
+        tokio::select! {
+            message = inbound.message().with_context(cx.clone()) => {
+                if let Some(delta) = message? {
+                    cx.span().add_event("got delta", vec![Key::new("sequence").i64(delta.sequence as i64)]);
+                    book.apply(&delta)?;
+                    let summary = book.summary();
+                    info!(log, "got a summary"; "sequence" => delta.sequence, "summary" => format!("{:?}", summary));
+                    on_summary(&summary);
+                } else {
+                    info!(log, "no more messages");
+                    return Ok(());
+                }
+            }
+            _ = shutdown_receiver.recv() => {
+                info!(log, "application is shutting down, closing client");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Connects to the server and listens to the arbitrage opportunities of the `symbol`, the first one
+/// served when not set, printing them in the log.
+pub async fn run_arbitrage_client(
+    log: Logger,
+    shutdown_receiver: &mut Receiver<String>,
+    address: String,
+    symbol: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    run_arbitrage_client_with(log, shutdown_receiver, address, symbol, |_| {}).await
+}
+
+/// Same as `run_arbitrage_client`, also handing every received update to `on_update`.
+pub(crate) async fn run_arbitrage_client_with<F: FnMut(&ArbitrageUpdate)>(
+    log: Logger,
+    shutdown_receiver: &mut Receiver<String>,
+    address: String,
+    symbol: Option<String>,
+    mut on_update: F,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(log, "starting client"; "address" => &address);
+    let mut client = OrderbookAggregatorClient::connect(address).await?;
+
+    let request = ArbitrageRequest { symbol: symbol.unwrap_or_default() };
+    info!(log, "requesting arbitrage"; "request" => format!("{:?}", request));
+    let mut inbound = client.arbitrage(Request::new(request)).await?.into_inner();
+
+    loop {
+        tokio::select! {
+            message = inbound.message() => {
+                if let Some(update) = message? {
+                    if update.opportunities.is_empty() {
+                        info!(log, "no more arbitrage opportunities");
+                    }
+                    for opportunity in &update.opportunities {
+                        info!(log, "got an arbitrage opportunity";
+                            "buy_exchange" => &opportunity.buy_exchange,
+                            "sell_exchange" => &opportunity.sell_exchange,
+                            "buy_price" => &opportunity.exact_buy_price,
+                            "sell_price" => &opportunity.exact_sell_price,
+                            "quantity" => &opportunity.exact_quantity,
+                            "profit" => &opportunity.exact_profit);
+                    }
+                    on_update(&update);
+                } else {
+                    info!(log, "no more messages");
+                    return Ok(());
+                }
+            }
+            _ = shutdown_receiver.recv() => {
+                info!(log, "application is shutting down, closing client");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Connects to the server and requests the current book once, printing it in the log.
+pub async fn get_snapshot(
+    log: Logger,
+    address: String,
+    request: SummaryRequest,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(log, "starting client"; "address" => &address);
+    let mut client = OrderbookAggregatorClient::connect(address).await?;
+
+    let request = BookSummaryRequest::from(request);
+    info!(log, "requesting get_book_snapshot"; "request" => format!("{:?}", request));
+    let summary = client.get_book_snapshot(Request::new(request)).await?.into_inner();
+    info!(log, "got a snapshot"; "summary" => format!("{:?}", summary));
+
+    Ok(())
+}
@@ -0,0 +1,459 @@
+use crate::{
+    book::LocalBook,
+    source::{
+        ConnectionEnd,
+        Source,
+        SourceConfig,
+        SummarySenders,
+    },
+    types::{
+        Level,
+        MBooksError,
+        Symbol,
+    },
+};
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+use opentelemetry::{
+    Context,
+    global,
+    Key,
+    trace::{
+        FutureExt,
+        TraceContextExt,
+        Tracer,
+    },
+};
+use serde_derive::Deserialize;
+use serde_json::json;
+use slog::{
+    debug,
+    error,
+    Logger,
+    info,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    time::SystemTime,
+};
+use tokio::sync::broadcast;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::Message,
+};
+
+const WEBSOCKET_URL: &str = "wss://ws-feed.exchange.coinbase.com";
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum WebSocketEvent {
+    #[serde(rename(deserialize = "subscriptions"))]
+    Subscriptions,
+    /// Whole book of the product, sent once after subscribing.
+    #[serde(rename(deserialize = "snapshot"))]
+    Snapshot {
+        product_id: String,
+        bids: Vec<Vec<String>>,
+        asks: Vec<Vec<String>>,
+    },
+    /// Changes in the format `[side, price, size]`, the size is absolute and zero removes the level.
+    #[serde(rename(deserialize = "l2update"))]
+    L2Update {
+        product_id: String,
+        changes: Vec<Vec<String>>,
+        time: String,
+    },
+    #[serde(rename(deserialize = "error"))]
+    Error {
+        message: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Creates the local book from the `snapshot` message.
+fn snapshot_book(bids: &[Vec<String>], asks: &[Vec<String>]) -> Result<LocalBook, MBooksError> {
+    let mut book = LocalBook::new("coinbase");
+    book.update_bids(bids)?;
+    book.update_asks(asks)?;
+    Ok(book)
+}
+
+/// Applies the changes from the `l2update` message to the local book.
+/// Every change is parsed first, so the book is left as it was when one of them is invalid.
+fn apply_changes(book: &mut LocalBook, changes: &[Vec<String>]) -> Result<(), MBooksError> {
+    let mut levels = Vec::with_capacity(changes.len());
+    for change in changes {
+        let bid = match change.first().map(String::as_str) {
+            Some("buy") => true,
+            Some("sell") => false,
+            _ => return Err(MBooksError::InvalidLevel(change.clone())),
+        };
+        levels.push((bid, Level::parse("coinbase", &change[1..])?));
+    }
+    for (bid, level) in levels {
+        if bid {
+            book.update_bid(level.price, level.quantity);
+        } else {
+            book.update_ask(level.price, level.quantity);
+        }
+    }
+    Ok(())
+}
+
+/// Request of the `type`, `subscribe` or `unsubscribe`, for the `level2` channel of the products.
+fn level2_request(kind: &str, product_ids: &[&String]) -> String {
+    json!({
+        "type": kind,
+        "product_ids": product_ids,
+        "channels": ["level2"],
+    }).to_string()
+}
+
+/// Parses the RFC 3339 `time` sent by Coinbase.
+fn parse_time(time: &str) -> Result<SystemTime, MBooksError> {
+    chrono::DateTime::parse_from_rfc3339(time)
+        .map(SystemTime::from)
+        .map_err(|_| MBooksError::InvalidTimestamp(time.to_string()))
+}
+
+fn symbol_to_string(symbol: &Symbol) -> String {
+    format!("{}-{}", symbol.base.to_string(), symbol.quote.to_string()).to_uppercase()
+}
+
+/// Coinbase `Source` implementation, it only supports the `level2` channel which is kept in a
+/// `LocalBook` for each product.
+pub struct Coinbase {
+    symbols: Vec<Symbol>,
+    depth: usize,
+    websocket_url: String,
+}
+
+impl Coinbase {
+    pub fn new(config: SourceConfig) -> Self {
+        Self {
+            websocket_url: config.endpoints.websocket("coinbase").unwrap_or(WEBSOCKET_URL).to_string(),
+            symbols: config.symbols,
+            depth: config.depth,
+        }
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for Coinbase {
+    fn name(&self) -> &'static str {
+        "coinbase"
+    }
+
+    fn symbol_to_string(&self, symbol: &Symbol) -> String {
+        symbol_to_string(symbol)
+    }
+
+    async fn connect(
+        &self,
+        log: &Logger,
+        shutdown_receiver: &mut broadcast::Receiver<String>,
+        summary_txs: &SummarySenders,
+    ) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+        run_coinbase(log, shutdown_receiver, summary_txs, &self.symbols, self.depth, &self.websocket_url).await
+    }
+}
+
+/// Connects to Coinbase and streams the books until the connection ends.
+async fn run_coinbase(
+    log: &Logger,
+    shutdown_receiver: &mut broadcast::Receiver<String>,
+    summary_txs: &SummarySenders,
+    symbols: &[Symbol], depth: usize, websocket_url: &str,
+) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+    let tracer = global::tracer("run_coinbase");
+    let span = tracer.start("running coinbase");
+    let cx = Context::current_with_span(span);
+    info!(log, "running coinbase"; "symbols" => format!("{:?}", symbols));
+
+    let url = url::Url::parse(websocket_url)?;
+    info!(log, "coinbase url"; "url" => format!("{:?}", url));
+
+    let (ws_stream, _) = connect_async(url)
+        .with_context(cx.clone())
+        .await?;
+    info!(log, "WebSocket handshake has been successfully completed");
+
+    let products: HashMap<String, Symbol> = symbols.iter()
+        .map(|symbol| (symbol_to_string(symbol), symbol.clone()))
+        .collect();
+    let (mut write, mut read) = ws_stream.split();
+    write.send(Message::Text(
+        level2_request("subscribe", &products.keys().collect::<Vec<_>>())
+    )).with_context(cx.clone()).await?;
+
+    // The books are only available after the snapshot of each product
+    let mut books: HashMap<Symbol, LocalBook> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            message = read.next() => {
+                if let Some(message) = message {
+                    debug!(log, "websocket got message");
+                    match message {
+                        Ok(message_data) => {
+                            let received_timestamp = SystemTime::now();
+                            let message_data = message_data.into_data();
+                            summary_txs.record("coinbase", received_timestamp, &message_data);
+                            let coinbase_parse: serde_json::Result<WebSocketEvent> = serde_json::from_slice(
+                                &message_data,
+                            );
+
+                            let (product_id, exchange_timestamp, book) = match coinbase_parse {
+                                Ok(WebSocketEvent::Subscriptions) | Ok(WebSocketEvent::Other) => continue,
+                                Ok(WebSocketEvent::Error { message }) => {
+                                    error!(log, "coinbase sent an error"; "message" => &message);
+                                    return Err(Box::new(MBooksError::ExchangeError(message)));
+                                }
+                                Ok(WebSocketEvent::Snapshot { product_id, bids, asks }) => {
+                                    let symbol = match products.get(&product_id) {
+                                        Some(symbol) => symbol,
+                                        None => {
+                                            warn!(log, "snapshot from an unknown product"; "product_id" => product_id);
+                                            continue;
+                                        }
+                                    };
+                                    // A new snapshot always replaces the book
+                                    let book = match snapshot_book(&bids, &asks) {
+                                        Ok(book) => {
+                                            books.insert(symbol.clone(), book);
+                                            Ok(&books[symbol])
+                                        }
+                                        Err(err) => Err(err),
+                                    };
+                                    (product_id, None, book)
+                                }
+                                Ok(WebSocketEvent::L2Update { product_id, changes, time }) => {
+                                    let symbol = match products.get(&product_id) {
+                                        Some(symbol) if books.contains_key(symbol) => symbol,
+                                        _ => {
+                                            warn!(log, "update before the snapshot"; "product_id" => product_id);
+                                            continue;
+                                        }
+                                    };
+                                    let book = books.get_mut(symbol).unwrap();
+                                    if let Err(err) = apply_changes(book, &changes) {
+                                        warn!(
+                                            log, "invalid l2update, subscribing again";
+                                            "product_id" => &product_id, "error" => format!("{}", err)
+                                        );
+                                        cx.span().add_event(
+                                            "invalid l2update",
+                                            vec![
+                                                Key::new("product_id").string(product_id.clone()),
+                                            ],
+                                        );
+                                        books.remove(symbol);
+                                        for kind in ["unsubscribe", "subscribe"] {
+                                            write.send(Message::Text(
+                                                level2_request(kind, &[&product_id])
+                                            )).with_context(cx.clone()).await?;
+                                        }
+                                        continue;
+                                    }
+                                    (product_id, parse_time(&time).ok(), Ok(&*book))
+                                }
+                                Err(err) => {
+                                    error!(log, "error parsing WebSocket data"; "error" => format!("{}", err));
+                                    cx.span().add_event(
+                                        "error parsing WebSocket data",
+                                        vec![
+                                            Key::new("message").string(format!("{:?}", message_data)),
+                                            Key::new("error").string(format!("{}", err)),
+                                        ],
+                                    );
+                                    continue;
+                                }
+                            };
+
+                            match book {
+                                Ok(book) => {
+                                    let summary = book.summary(depth).with_timestamps(
+                                        exchange_timestamp, received_timestamp,
+                                    );
+                                    if let Err(err) = summary_txs.send(&products[&product_id], summary) {
+                                        error!(
+                                            log, "error sending information to the channel";
+                                            "product_id" => &product_id,
+                                            "error" => format!("{}", err)
+                                        );
+                                        cx.span().add_event(
+                                            "error sending information to the channel",
+                                            vec![
+                                                Key::new("error").string(format!("{}", err)),
+                                            ],
+                                        );
+                                    }
+                                }
+                                Err(err) => {
+                                    error!(
+                                        log, "error converting WebSocket data to domain type";
+                                        "error" => format!("{}", err)
+                                    );
+                                    cx.span().add_event(
+                                        "error converting WebSocket data to domain type",
+                                        vec![
+                                            Key::new("error").string(format!("{:?}", err)),
+                                        ],
+                                    );
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            error!(log, "problem fetching message"; "error" => format!("{}", err));
+                            return Err(Box::new(err));
+                        }
+                    }
+                } else {
+                    info!(log, "no more messages run_coinbase");
+                    return Ok(ConnectionEnd::Closed);
+                }
+            }
+            _ = shutdown_receiver.recv() => {
+                info!(log, "application is shutting down, closing run_coinbase");
+                return Ok(ConnectionEnd::Shutdown);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        coinbase::{
+            apply_changes,
+            level2_request,
+            parse_time,
+            snapshot_book,
+            symbol_to_string,
+            WebSocketEvent,
+        },
+        types::{
+            Asset,
+            Symbol,
+        },
+    };
+    use rust_decimal_macros::dec;
+    use std::time::{
+        Duration,
+        UNIX_EPOCH,
+    };
+
+    #[test]
+    fn should_parse_snapshot() {
+        // Given
+        let msg = r#"{"type":"snapshot","product_id":"ETH-BTC","bids":[["0.06754","12.5"],["0.06753","1.2"]],"asks":[["0.06756","3.0"]]}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        if let WebSocketEvent::Snapshot { product_id, bids, asks } = resp {
+            assert_eq!("ETH-BTC", product_id);
+            assert_eq!(2, bids.len());
+            assert_eq!(1, asks.len());
+        } else {
+            panic!("not a snapshot");
+        }
+    }
+
+    #[test]
+    fn should_ignore_other_events() {
+        // Given
+        let msg = r#"{"type":"heartbeat","sequence":90,"last_trade_id":20,"product_id":"ETH-BTC","time":"2022-10-19T17:24:09.249913Z"}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        assert!(matches!(resp, WebSocketEvent::Other));
+    }
+
+    #[test]
+    fn should_apply_l2update() {
+        // Given
+        let msg = r#"{"type":"l2update","product_id":"ETH-BTC","changes":[["buy","0.06754","0.00000000"],["sell","0.06755","2.0"]],"time":"2022-10-19T17:24:09.249913Z"}"#;
+        let mut book = snapshot_book(
+            &[vec!["0.06754".to_string(), "12.5".to_string()], vec!["0.06753".to_string(), "1.2".to_string()]],
+            &[vec!["0.06756".to_string(), "3.0".to_string()]],
+        ).unwrap();
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+        let time = if let WebSocketEvent::L2Update { changes, time, .. } = resp {
+            apply_changes(&mut book, &changes).unwrap();
+            time
+        } else {
+            panic!("not a l2update");
+        };
+
+        // Then
+        let summary = book.summary(10);
+        assert_eq!(1, summary.bids.len());
+        assert_eq!(dec!(0.06753), summary.bids[0].price);
+        assert_eq!(2, summary.asks.len());
+        assert_eq!(dec!(0.06755), summary.asks[0].price);
+        assert_eq!(UNIX_EPOCH + Duration::from_micros(1666200249249913), parse_time(&time).unwrap());
+    }
+
+    #[test]
+    fn should_fail_applying_invalid_side() {
+        // Given
+        let mut book = snapshot_book(&[], &[]).unwrap();
+
+        // When
+        let resp = apply_changes(&mut book, &[vec!["hold".to_string(), "1.0".to_string(), "1.0".to_string()]]);
+
+        // Then
+        assert!(resp.is_err());
+    }
+
+    #[test]
+    fn should_not_apply_part_of_an_invalid_l2update() {
+        // Given
+        let mut book = snapshot_book(&[vec!["0.06754".to_string(), "12.5".to_string()]], &[]).unwrap();
+        let changes = vec![
+            vec!["buy".to_string(), "0.06754".to_string(), "0".to_string()],
+            vec!["sell".to_string(), "invalid".to_string(), "1.0".to_string()],
+        ];
+
+        // When
+        let resp = apply_changes(&mut book, &changes);
+
+        // Then
+        assert!(resp.is_err());
+        assert_eq!(vec![dec!(0.06754)], book.summary(10).bids.iter().map(|x| x.price).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn should_build_level2_request() {
+        // Given
+        let product_id = "ETH-BTC".to_string();
+
+        // When
+        let resp = level2_request("unsubscribe", &[&product_id]);
+
+        // Then
+        assert_eq!(r#"{"channels":["level2"],"product_ids":["ETH-BTC"],"type":"unsubscribe"}"#, resp);
+    }
+
+    #[test]
+    fn should_convert_symbol() {
+        // Given
+        let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+
+        // When
+        let resp = symbol_to_string(&symbol);
+
+        // Then
+        assert_eq!("ETH-BTC", resp)
+    }
+}
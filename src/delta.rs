@@ -0,0 +1,301 @@
+use crate::{
+    orderbook::{
+        BookDelta,
+        Level,
+        LevelChange,
+        level_change::Kind,
+        Summary,
+    },
+    types::MBooksError,
+};
+use rust_decimal::{
+    prelude::ToPrimitive,
+    Decimal,
+};
+use std::collections::HashMap;
+
+/// Whether the level has a different amount, or a different one from any exchange when aggregated.
+fn amount_changed(previous: &Level, current: &Level) -> bool {
+    previous.exact_amount != current.exact_amount
+        || previous.breakdown.len() != current.breakdown.len()
+        || previous.breakdown.iter().zip(&current.breakdown).any(|(previous, current)| amount_changed(previous, current))
+}
+
+/// Changes to go from the `previous` levels of a side to the `current` ones, comparing the levels by
+/// exchange and price.
+fn side_changes(previous: &[Level], current: &[Level]) -> Vec<LevelChange> {
+    let key = |level: &Level| (level.exchange.clone(), level.exact_price.clone());
+    let mut remaining: HashMap<_, &Level> = previous.iter().map(|level| (key(level), level)).collect();
+
+    let mut changes = Vec::new();
+    for level in current {
+        let kind = match remaining.remove(&key(level)) {
+            None => Kind::Insert,
+            Some(previous) if amount_changed(previous, level) => Kind::Update,
+            Some(_) => continue,
+        };
+        changes.push(LevelChange { kind: kind as i32, level: Some(level.clone()) });
+    }
+    // The remaining levels are gone, keeping the order they had
+    changes.extend(previous.iter()
+        .filter(|level| remaining.contains_key(&key(level)))
+        .map(|level| LevelChange { kind: Kind::Delete as i32, level: Some(level.clone()) }));
+    changes
+}
+
+/// DeltaEncoder turns the summaries sent to a client into `BookDelta`s, keeping the last levels
+/// sent so any summary can be skipped in between.
+#[derive(Default)]
+pub struct DeltaEncoder {
+    sequence: u64,
+    bids: Vec<Level>,
+    asks: Vec<Level>,
+}
+
+impl DeltaEncoder {
+    /// The first delta is a snapshot with every level, `None` when nothing changed since the last one.
+    pub fn encode(&mut self, summary: Summary) -> Option<BookDelta> {
+        let snapshot = self.sequence == 0;
+        let bids = side_changes(&self.bids, &summary.bids);
+        let asks = side_changes(&self.asks, &summary.asks);
+        if !snapshot && bids.is_empty() && asks.is_empty() {
+            return None;
+        }
+
+        self.sequence += 1;
+        self.bids = summary.bids;
+        self.asks = summary.asks;
+        Some(BookDelta {
+            sequence: self.sequence,
+            snapshot,
+            bids,
+            asks,
+            exchange_timestamp_us: summary.exchange_timestamp_us,
+            received_timestamp_us: summary.received_timestamp_us,
+        })
+    }
+}
+
+/// DeltaBook reassembles the book from the `BookDelta`s of a stream, so the client gets the same
+/// view as a `FilteredBookSummary` stream.
+/// The levels with the same price are ordered by when they were inserted.
+#[derive(Debug, Default)]
+pub struct DeltaBook {
+    /// Sequence of the last delta applied, 0 before the first snapshot.
+    sequence: u64,
+    bids: Vec<Level>,
+    asks: Vec<Level>,
+    exchange_timestamp_us: u64,
+    received_timestamp_us: u64,
+}
+
+impl DeltaBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies the changes of the delta.
+    /// It fails when a delta is missing, the book is not valid until the stream is requested again.
+    pub fn apply(&mut self, delta: &BookDelta) -> Result<(), MBooksError> {
+        if delta.snapshot {
+            self.bids.clear();
+            self.asks.clear();
+        } else if self.sequence == 0 || delta.sequence != self.sequence + 1 {
+            return Err(MBooksError::SequenceGap(format!(
+                "expected delta {}, got {}", self.sequence + 1, delta.sequence,
+            )));
+        }
+
+        Self::apply_side(&mut self.bids, &delta.bids, Decimal::NEGATIVE_ONE);
+        Self::apply_side(&mut self.asks, &delta.asks, Decimal::ONE);
+        self.sequence = delta.sequence;
+        self.exchange_timestamp_us = delta.exchange_timestamp_us;
+        self.received_timestamp_us = delta.received_timestamp_us;
+        Ok(())
+    }
+
+    /// Applies the changes to the levels of a side, sorting them by price times the `multiplier`,
+    /// -1 for the bids and 1 for the asks.
+    fn apply_side(levels: &mut Vec<Level>, changes: &[LevelChange], multiplier: Decimal) {
+        for change in changes {
+            let level = match &change.level {
+                Some(level) => level,
+                None => continue,
+            };
+            let position = levels.iter()
+                .position(|x| x.exchange == level.exchange && x.exact_price == level.exact_price);
+            match (change.kind(), position) {
+                (Kind::Delete, Some(position)) => {
+                    levels.remove(position);
+                }
+                (Kind::Delete, None) => {}
+                (_, Some(position)) => levels[position] = level.clone(),
+                (_, None) => levels.push(level.clone()),
+            }
+        }
+        levels.sort_by_cached_key(|level| Self::adjusted_price(level, multiplier) * multiplier);
+    }
+
+    /// Price of the level adjusted by its fee, as the server merges them.
+    fn adjusted_price(level: &Level, multiplier: Decimal) -> Decimal {
+        let price = level.exact_price.parse::<Decimal>().unwrap_or_default();
+        match level.exact_fee.parse::<Decimal>() {
+            Ok(fee) => price * (Decimal::ONE + multiplier * fee),
+            Err(_) => price,
+        }
+    }
+
+    /// The reassembled book as a `Summary`.
+    pub fn summary(&self) -> Summary {
+        let spread = match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) => {
+                Some(Self::adjusted_price(ask, Decimal::ONE) - Self::adjusted_price(bid, Decimal::NEGATIVE_ONE))
+            }
+            _ => None,
+        };
+        Summary {
+            spread: spread.and_then(|spread| spread.to_f64()).unwrap_or(f64::NAN),
+            exact_spread: spread.map(|spread| spread.to_string()).unwrap_or_default(),
+            bids: self.bids.clone(),
+            asks: self.asks.clone(),
+            exchange_timestamp_us: self.exchange_timestamp_us,
+            received_timestamp_us: self.received_timestamp_us,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        delta::{
+            DeltaBook,
+            DeltaEncoder,
+        },
+        orderbook::{
+            self,
+            level_change::Kind,
+        },
+        types::{
+            Level,
+            MBooksError,
+            Summary,
+        },
+    };
+
+    fn summary(bids: &[(&str, &str, &str)], asks: &[(&str, &str, &str)]) -> orderbook::Summary {
+        let levels = |levels: &[(&str, &str, &str)]| levels.iter()
+            .map(|(exchange, price, amount)| Level::parse(exchange, &[price.to_string(), amount.to_string()]).unwrap())
+            .collect();
+        Summary::from_levels(levels(bids), levels(asks)).into()
+    }
+
+    fn kinds(changes: &[orderbook::LevelChange]) -> Vec<(Kind, &str, &str)> {
+        changes.iter()
+            .map(|change| {
+                let level = change.level.as_ref().unwrap();
+                (change.kind(), level.exchange.as_str(), level.exact_price.as_str())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_encode_level_changes() {
+        // Given
+        let mut encoder = DeltaEncoder::default();
+        let first = summary(&[("binance", "0.0675", "1"), ("bitstamp", "0.0674", "2")], &[("binance", "0.0677", "1")]);
+        let second = summary(&[("binance", "0.0675", "3"), ("binance", "0.0673", "1")], &[("binance", "0.0677", "1")]);
+
+        // When
+        let snapshot = encoder.encode(first).unwrap();
+        let delta = encoder.encode(second.clone()).unwrap();
+        let unchanged = encoder.encode(second);
+
+        // Then
+        assert!(snapshot.snapshot);
+        assert_eq!(1, snapshot.sequence);
+        assert_eq!(
+            vec![(Kind::Insert, "binance", "0.0675"), (Kind::Insert, "bitstamp", "0.0674")],
+            kinds(&snapshot.bids),
+        );
+        assert!(!delta.snapshot);
+        assert_eq!(2, delta.sequence);
+        assert_eq!(
+            vec![
+                (Kind::Update, "binance", "0.0675"),
+                (Kind::Insert, "binance", "0.0673"),
+                (Kind::Delete, "bitstamp", "0.0674"),
+            ],
+            kinds(&delta.bids),
+        );
+        assert!(delta.asks.is_empty());
+        assert!(unchanged.is_none());
+    }
+
+    #[test]
+    fn should_reassemble_the_book_from_deltas() {
+        // Given
+        let mut encoder = DeltaEncoder::default();
+        let summaries = vec![
+            summary(&[("binance", "0.0675", "1")], &[("bitstamp", "0.0678", "2")]),
+            summary(&[("bitstamp", "0.0676", "2"), ("binance", "0.0675", "1")], &[("binance", "0.0677", "1"), ("bitstamp", "0.0678", "2")]),
+            summary(&[("bitstamp", "0.0676", "5"), ("binance", "0.0674", "1")], &[("binance", "0.0677", "1")]),
+        ];
+        let mut book = DeltaBook::new();
+
+        // When
+        for summary in summaries.clone() {
+            book.apply(&encoder.encode(summary).unwrap()).unwrap();
+        }
+
+        // Then
+        assert_eq!(3, book.sequence);
+        assert_eq!(summaries[2], book.summary());
+        assert_eq!("0.0001", book.summary().exact_spread);
+    }
+
+    #[test]
+    fn should_detect_missing_deltas() {
+        // Given
+        let mut encoder = DeltaEncoder::default();
+        let snapshot = encoder.encode(summary(&[("binance", "0.0675", "1")], &[])).unwrap();
+        let lost = encoder.encode(summary(&[("binance", "0.0675", "2")], &[])).unwrap();
+        let delta = encoder.encode(summary(&[("binance", "0.0675", "3")], &[])).unwrap();
+        let mut book = DeltaBook::new();
+
+        // When
+        let before_snapshot = book.apply(&delta);
+        book.apply(&snapshot).unwrap();
+        let after_lost = book.apply(&delta);
+        book.apply(&lost).unwrap();
+
+        // Then
+        assert!(matches!(before_snapshot, Err(MBooksError::SequenceGap(_))));
+        assert!(matches!(after_lost, Err(MBooksError::SequenceGap(_))));
+        assert_eq!(2, book.sequence);
+        assert_eq!("2", book.summary().bids[0].exact_amount);
+    }
+
+    #[test]
+    fn should_update_aggregated_level_when_the_breakdown_changes() {
+        // Given
+        let mut encoder = DeltaEncoder::default();
+        let aggregated = |binance: &str, bitstamp: &str| {
+            let mut summary = summary(&[("binance", "0.0675", binance), ("bitstamp", "0.0675", bitstamp)], &[]);
+            let breakdown = std::mem::take(&mut summary.bids);
+            summary.bids = vec![orderbook::Level {
+                exchange: "binance,bitstamp".to_string(),
+                exact_amount: "3".to_string(),
+                breakdown,
+                ..Default::default()
+            }];
+            summary
+        };
+
+        // When
+        encoder.encode(aggregated("1", "2")).unwrap();
+        let resp = encoder.encode(aggregated("2", "1"));
+
+        // Then
+        assert_eq!(vec![Kind::Update], resp.unwrap().bids.iter().map(|x| x.kind()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,602 @@
+use crate::{
+    book::LocalBook,
+    source::{
+        ConnectionEnd,
+        Source,
+        SourceConfig,
+        SummarySenders,
+    },
+    types::{
+        Asset,
+        MBooksError,
+        Symbol,
+    },
+};
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+use opentelemetry::{
+    Context,
+    global,
+    Key,
+    trace::{
+        FutureExt,
+        TraceContextExt,
+        Tracer,
+    },
+};
+use rust_decimal::Decimal;
+use serde_derive::Deserialize;
+use serde_json::json;
+use slog::{
+    debug,
+    error,
+    Logger,
+    info,
+    o,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    time::SystemTime,
+};
+use tokio::sync::broadcast;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::Message,
+};
+
+const WEBSOCKET_URL: &str = "wss://ws.kraken.com/v2";
+/// Depths accepted by the `book` channel.
+const BOOK_DEPTHS: [usize; 5] = [10, 25, 100, 500, 1000];
+/// Levels of each side used to calculate the checksum.
+const CHECKSUM_DEPTH: usize = 10;
+
+#[derive(Debug, Deserialize)]
+struct BookLevel {
+    price: Decimal,
+    qty: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct BookData {
+    symbol: String,
+    bids: Vec<BookLevel>,
+    asks: Vec<BookLevel>,
+    /// CRC32 of the top levels of the book after applying the message.
+    checksum: u32,
+    timestamp: Option<String>,
+}
+
+/// Precision used to format the prices and quantities of a pair in the checksum.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+struct PairPrecision {
+    price_precision: u32,
+    qty_precision: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct PairInfo {
+    symbol: String,
+    #[serde(flatten)]
+    precision: PairPrecision,
+}
+
+#[derive(Debug, Deserialize)]
+struct InstrumentData {
+    pairs: Vec<PairInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "channel")]
+enum ChannelEvent {
+    #[serde(rename(deserialize = "book"))]
+    Book {
+        #[serde(rename(deserialize = "type"))]
+        kind: String,
+        data: Vec<BookData>,
+    },
+    #[serde(rename(deserialize = "instrument"))]
+    Instrument {
+        data: InstrumentData,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Response to the `subscribe` and `unsubscribe` requests.
+#[derive(Debug, Deserialize)]
+struct MethodResponse {
+    method: String,
+    success: bool,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum WebSocketEvent {
+    Channel(ChannelEvent),
+    Method(MethodResponse),
+}
+
+/// Kraken names for the assets.
+/// The WebSocket v2 API uses the ISO names, e.g. `BTC`, instead of `XBT` used by the REST and
+/// WebSocket v1 APIs.
+fn asset_to_string(asset: &Asset) -> &'static str {
+    match asset {
+        Asset::ADA => "ADA",
+        Asset::BTC => "BTC",
+        Asset::DOT => "DOT",
+        Asset::ETH => "ETH",
+        Asset::LINK => "LINK",
+        Asset::LTC => "LTC",
+        Asset::SOL => "SOL",
+        Asset::USD => "USD",
+        Asset::USDC => "USDC",
+        Asset::USDT => "USDT",
+    }
+}
+
+fn symbol_to_string(symbol: &Symbol) -> String {
+    format!("{}/{}", asset_to_string(&symbol.base), asset_to_string(&symbol.quote))
+}
+
+/// Smallest depth accepted by the `book` channel with at least `depth` levels.
+fn book_depth(depth: usize) -> usize {
+    BOOK_DEPTHS.iter().copied()
+        .find(|book_depth| *book_depth >= depth)
+        .unwrap_or(BOOK_DEPTHS[BOOK_DEPTHS.len() - 1])
+}
+
+/// Request for the `book` channel of the pairs.
+fn book_request(method: &str, pairs: &[&String], depth: usize) -> String {
+    json!({
+        "method": method,
+        "params": {
+            "channel": "book",
+            "symbol": pairs,
+            "depth": depth,
+        },
+    }).to_string()
+}
+
+/// Formats the value for the checksum: with the pair precision, without the decimal point and the
+/// leading zeros.
+fn checksum_value(value: Decimal, precision: u32) -> String {
+    let mut value = value;
+    value.rescale(precision);
+    value.to_string().replace('.', "").trim_start_matches('0').to_string()
+}
+
+/// Calculates the CRC32 of the top asks followed by the top bids, each level being the price
+/// followed by the quantity.
+fn checksum(book: &LocalBook, precision: PairPrecision) -> u32 {
+    let summary = book.summary(CHECKSUM_DEPTH);
+    let mut hasher = crc32fast::Hasher::new();
+    for level in summary.asks.iter().chain(summary.bids.iter()) {
+        hasher.update(checksum_value(level.price, precision.price_precision).as_bytes());
+        hasher.update(checksum_value(level.quantity, precision.qty_precision).as_bytes());
+    }
+    hasher.finalize()
+}
+
+/// Applies the levels from a `book` message, keeping only the subscribed depth.
+fn apply_book_data(book: &mut LocalBook, data: &BookData, depth: usize) {
+    for bid in &data.bids {
+        book.update_bid(bid.price, bid.qty);
+    }
+    for ask in &data.asks {
+        book.update_ask(ask.price, ask.qty);
+    }
+    book.truncate(depth);
+}
+
+/// State of the book of a pair after a `book` message.
+#[derive(Debug, PartialEq)]
+enum Checksum {
+    /// The book matches the checksum of the message.
+    Valid,
+    /// An update before the snapshot of the pair, it is ignored.
+    NoSnapshot,
+    /// The book does not match the checksum, it is removed until the pair is subscribed again.
+    Mismatch,
+}
+
+/// Applies a `book` message of the type `kind` to the book of its pair, starting a new one with a
+/// snapshot, and validates it with the checksum.
+fn apply_book_message(
+    books: &mut HashMap<String, LocalBook>, kind: &str, data: &BookData, depth: usize, precision: PairPrecision,
+) -> Checksum {
+    if kind == "snapshot" {
+        books.insert(data.symbol.clone(), LocalBook::new("kraken"));
+    }
+    let book = match books.get_mut(&data.symbol) {
+        Some(book) => book,
+        None => return Checksum::NoSnapshot,
+    };
+    apply_book_data(book, data, depth);
+
+    if checksum(book, precision) != data.checksum {
+        books.remove(&data.symbol);
+        return Checksum::Mismatch;
+    }
+    Checksum::Valid
+}
+
+/// Requests to get a new snapshot of the pair after a checksum mismatch.
+fn resubscribe_requests(pair: &String, depth: usize) -> Vec<String> {
+    ["unsubscribe", "subscribe"].iter()
+        .map(|method| book_request(method, &[pair], depth))
+        .collect()
+}
+
+/// Kraken `Source` implementation, it only supports the `book` channel which is kept in a
+/// `LocalBook` for each pair and validated with the checksum sent on every message.
+pub struct Kraken {
+    symbols: Vec<Symbol>,
+    depth: usize,
+    websocket_url: String,
+}
+
+impl Kraken {
+    pub fn new(config: SourceConfig) -> Self {
+        Self {
+            websocket_url: config.endpoints.websocket("kraken").unwrap_or(WEBSOCKET_URL).to_string(),
+            symbols: config.symbols,
+            depth: config.depth,
+        }
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for Kraken {
+    fn name(&self) -> &'static str {
+        "kraken"
+    }
+
+    fn symbol_to_string(&self, symbol: &Symbol) -> String {
+        symbol_to_string(symbol)
+    }
+
+    async fn connect(
+        &self,
+        log: &Logger,
+        shutdown_receiver: &mut broadcast::Receiver<String>,
+        summary_txs: &SummarySenders,
+    ) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+        run_kraken(log, shutdown_receiver, summary_txs, &self.symbols, self.depth, &self.websocket_url).await
+    }
+}
+
+/// Connects to Kraken and streams the books until the connection ends.
+/// It first subscribes to the `instrument` channel to get the precision of each pair used in the
+/// checksum, and only then to the `book` channel.
+async fn run_kraken(
+    log: &Logger,
+    shutdown_receiver: &mut broadcast::Receiver<String>,
+    summary_txs: &SummarySenders,
+    symbols: &[Symbol], depth: usize, websocket_url: &str,
+) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+    let tracer = global::tracer("run_kraken");
+    let span = tracer.start("running kraken");
+    let cx = Context::current_with_span(span);
+    info!(log, "running kraken"; "symbols" => format!("{:?}", symbols));
+
+    let url = url::Url::parse(websocket_url)?;
+    info!(log, "kraken url"; "url" => format!("{:?}", url));
+
+    let (ws_stream, _) = connect_async(url)
+        .with_context(cx.clone())
+        .await?;
+    info!(log, "WebSocket handshake has been successfully completed");
+
+    let pairs: HashMap<String, Symbol> = symbols.iter()
+        .map(|symbol| (symbol_to_string(symbol), symbol.clone()))
+        .collect();
+    let book_depth = book_depth(depth);
+    let (mut write, mut read) = ws_stream.split();
+    write.send(Message::Text(
+        "{\"method\":\"subscribe\",\"params\":{\"channel\":\"instrument\"}}".to_string()
+    )).with_context(cx.clone()).await?;
+
+    let mut precisions: HashMap<String, PairPrecision> = HashMap::new();
+    let mut books: HashMap<String, LocalBook> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            message = read.next() => {
+                if let Some(message) = message {
+                    debug!(log, "websocket got message");
+                    match message {
+                        Ok(message_data) => {
+                            let received_timestamp = SystemTime::now();
+                            let message_data = message_data.into_data();
+                            summary_txs.record("kraken", received_timestamp, &message_data);
+                            let kraken_parse: serde_json::Result<WebSocketEvent> = serde_json::from_slice(
+                                &message_data,
+                            );
+
+                            match kraken_parse {
+                                Ok(WebSocketEvent::Method(response)) => {
+                                    if !response.success {
+                                        error!(
+                                            log, "kraken request failed";
+                                            "method" => &response.method, "error" => format!("{:?}", response.error)
+                                        );
+                                    }
+                                }
+                                Ok(WebSocketEvent::Channel(ChannelEvent::Instrument { data })) => {
+                                    let subscribed = !precisions.is_empty();
+                                    for pair in data.pairs {
+                                        if pairs.contains_key(&pair.symbol) {
+                                            precisions.insert(pair.symbol, pair.precision);
+                                        }
+                                    }
+                                    if subscribed {
+                                        continue;
+                                    }
+
+                                    let missing: Vec<&String> = pairs.keys()
+                                        .filter(|pair| !precisions.contains_key(*pair))
+                                        .collect();
+                                    if !missing.is_empty() {
+                                        return Err(Box::new(MBooksError::ExchangeError(
+                                            format!("pairs not available: {:?}", missing),
+                                        )));
+                                    }
+                                    info!(log, "subscribing to the books"; "depth" => book_depth);
+                                    write.send(Message::Text(
+                                        book_request("subscribe", &pairs.keys().collect::<Vec<_>>(), book_depth)
+                                    )).with_context(cx.clone()).await?;
+                                }
+                                Ok(WebSocketEvent::Channel(ChannelEvent::Book { kind, data })) => {
+                                    for data in data {
+                                        let (symbol, precision) = match (pairs.get(&data.symbol), precisions.get(&data.symbol)) {
+                                            (Some(symbol), Some(precision)) => (symbol, *precision),
+                                            _ => {
+                                                warn!(log, "book from an unknown pair"; "pair" => &data.symbol);
+                                                continue;
+                                            }
+                                        };
+                                        let log = log.new(o!("pair" => data.symbol.clone()));
+
+                                        match apply_book_message(&mut books, &kind, &data, book_depth, precision) {
+                                            Checksum::Valid => {}
+                                            Checksum::NoSnapshot => {
+                                                debug!(log, "ignoring update before the snapshot");
+                                                continue;
+                                            }
+                                            Checksum::Mismatch => {
+                                                warn!(log, "checksum mismatch, subscribing again"; "checksum" => data.checksum);
+                                                cx.span().add_event(
+                                                    "checksum mismatch",
+                                                    vec![
+                                                        Key::new("pair").string(data.symbol.clone()),
+                                                    ],
+                                                );
+                                                for request in resubscribe_requests(&data.symbol, book_depth) {
+                                                    write.send(Message::Text(request)).with_context(cx.clone()).await?;
+                                                }
+                                                continue;
+                                            }
+                                        }
+                                        let book = &books[&data.symbol];
+
+                                        let exchange_timestamp = data.timestamp.as_deref()
+                                            .and_then(|timestamp| chrono::DateTime::parse_from_rfc3339(timestamp).ok())
+                                            .map(SystemTime::from);
+                                        let summary = book.summary(depth).with_timestamps(
+                                            exchange_timestamp, received_timestamp,
+                                        );
+                                        if let Err(err) = summary_txs.send(symbol, summary) {
+                                            error!(
+                                                log, "error sending information to the channel";
+                                                "error" => format!("{}", err)
+                                            );
+                                            cx.span().add_event(
+                                                "error sending information to the channel",
+                                                vec![
+                                                    Key::new("error").string(format!("{}", err)),
+                                                ],
+                                            );
+                                        }
+                                    }
+                                }
+                                Ok(WebSocketEvent::Channel(ChannelEvent::Other)) => {}
+                                Err(err) => {
+                                    error!(log, "error parsing WebSocket data"; "error" => format!("{}", err));
+                                    cx.span().add_event(
+                                        "error parsing WebSocket data",
+                                        vec![
+                                            Key::new("message").string(format!("{:?}", message_data)),
+                                            Key::new("error").string(format!("{}", err)),
+                                        ],
+                                    );
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            error!(log, "problem fetching message"; "error" => format!("{}", err));
+                            return Err(Box::new(err));
+                        }
+                    }
+                } else {
+                    info!(log, "no more messages run_kraken");
+                    return Ok(ConnectionEnd::Closed);
+                }
+            }
+            _ = shutdown_receiver.recv() => {
+                info!(log, "application is shutting down, closing run_kraken");
+                return Ok(ConnectionEnd::Shutdown);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        kraken::{
+            apply_book_message,
+            book_depth,
+            book_request,
+            resubscribe_requests,
+            symbol_to_string,
+            ChannelEvent,
+            Checksum,
+            PairPrecision,
+            WebSocketEvent,
+        },
+        types::{
+            Asset,
+            Symbol,
+        },
+    };
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+
+    const PRECISION: PairPrecision = PairPrecision {
+        price_precision: 5,
+        qty_precision: 8,
+    };
+
+    fn book_data(msg: &str) -> (String, Vec<super::BookData>) {
+        match serde_json::from_str(msg).unwrap() {
+            WebSocketEvent::Channel(ChannelEvent::Book { kind, data }) => (kind, data),
+            _ => panic!("not a book"),
+        }
+    }
+
+    #[test]
+    fn should_parse_instrument() {
+        // Given
+        let msg = r#"{"channel":"instrument","type":"snapshot","data":{"assets":[],"pairs":[{"symbol":"ETH/BTC","base":"ETH","quote":"BTC","status":"online","qty_precision":8,"qty_increment":0.00000001,"price_precision":5,"cost_precision":10,"marginable":true,"has_index":true,"cost_min":0.00002,"tick_size":0.00001,"price_increment":0.00001,"qty_min":0.002}]}}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        if let WebSocketEvent::Channel(ChannelEvent::Instrument { data }) = resp {
+            assert_eq!("ETH/BTC", data.pairs[0].symbol);
+            assert_eq!(PRECISION, data.pairs[0].precision);
+        } else {
+            panic!("not an instrument");
+        }
+    }
+
+    #[test]
+    fn should_parse_method_response() {
+        // Given
+        let msg = r#"{"method":"subscribe","error":"Already subscribed","success":false,"symbol":"ETH/BTC","time_in":"2023-10-06T17:35:55.440295Z","time_out":"2023-10-06T17:35:55.440342Z"}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        if let WebSocketEvent::Method(response) = resp {
+            assert!(!response.success);
+            assert_eq!(Some("Already subscribed".to_string()), response.error);
+        } else {
+            panic!("not a method response");
+        }
+    }
+
+    /// Book of the example in the Kraken guide to the v2 book checksum.
+    const CHECKSUM_SNAPSHOT: &str = r#"{"channel":"book","type":"snapshot","data":[{"symbol":"BTC/USD","bids":[{"price":45283.5,"qty":0.10000000},{"price":45283.4,"qty":1.54582015},{"price":45282.1,"qty":0.10000000},{"price":45281.0,"qty":0.10000000},{"price":45280.3,"qty":1.54592586},{"price":45279.0,"qty":0.07990000},{"price":45277.6,"qty":0.03310103},{"price":45277.5,"qty":0.30000000},{"price":45277.3,"qty":1.54602737},{"price":45276.6,"qty":0.15445238}],"asks":[{"price":45285.2,"qty":0.00100000},{"price":45286.4,"qty":1.54571953},{"price":45286.6,"qty":1.54571109},{"price":45289.6,"qty":1.54560911},{"price":45290.2,"qty":0.15890660},{"price":45291.8,"qty":1.54553491},{"price":45294.7,"qty":0.04454749},{"price":45296.1,"qty":0.35380000},{"price":45297.5,"qty":0.09945542},{"price":45299.5,"qty":0.18772827}],"checksum":3310070434}]}"#;
+    const CHECKSUM_PRECISION: PairPrecision = PairPrecision {
+        price_precision: 1,
+        qty_precision: 8,
+    };
+
+    fn checksum_update(checksum: u32) -> String {
+        format!(
+            r#"{{"channel":"book","type":"update","data":[{{"symbol":"BTC/USD","bids":[{{"price":45283.5,"qty":0.2}}],"asks":[{{"price":45285.0,"qty":0.5}}],"checksum":{},"timestamp":"2023-10-06T17:35:55.440295Z"}}]}}"#,
+            checksum,
+        )
+    }
+
+    #[test]
+    fn should_validate_checksum() {
+        // Given
+        let mut books = HashMap::new();
+        let (snapshot_kind, snapshot) = book_data(CHECKSUM_SNAPSHOT);
+        // The best ask is inserted and the worst one is left out of the 10 levels
+        let (update_kind, update) = book_data(&checksum_update(2652606665));
+
+        // When
+        let snapshot_resp = apply_book_message(&mut books, &snapshot_kind, &snapshot[0], 10, CHECKSUM_PRECISION);
+        let update_resp = apply_book_message(&mut books, &update_kind, &update[0], 10, CHECKSUM_PRECISION);
+
+        // Then
+        assert_eq!(Checksum::Valid, snapshot_resp);
+        assert_eq!(Checksum::Valid, update_resp);
+        let summary = books["BTC/USD"].summary(10);
+        assert_eq!(dec!(0.2), summary.bids[0].quantity);
+        assert_eq!(dec!(45285.0), summary.asks[0].price);
+        assert_eq!(dec!(45297.5), summary.asks[9].price);
+    }
+
+    #[test]
+    fn should_subscribe_again_on_checksum_mismatch() {
+        // Given
+        let mut books = HashMap::new();
+        let (snapshot_kind, snapshot) = book_data(CHECKSUM_SNAPSHOT);
+        let (update_kind, update) = book_data(&checksum_update(3310070434));
+        apply_book_message(&mut books, &snapshot_kind, &snapshot[0], 10, CHECKSUM_PRECISION);
+
+        // When
+        let mismatch = apply_book_message(&mut books, &update_kind, &update[0], 10, CHECKSUM_PRECISION);
+        let before_snapshot = apply_book_message(&mut books, &update_kind, &update[0], 10, CHECKSUM_PRECISION);
+        let requests = resubscribe_requests(&update[0].symbol, 10);
+
+        // Then
+        assert_eq!(Checksum::Mismatch, mismatch);
+        assert_eq!(Checksum::NoSnapshot, before_snapshot);
+        assert!(books.is_empty());
+        let methods: Vec<serde_json::Value> = requests.iter()
+            .map(|request| serde_json::from_str::<serde_json::Value>(request).unwrap()["method"].clone())
+            .collect();
+        assert_eq!(vec!["unsubscribe", "subscribe"], methods);
+    }
+
+    #[test]
+    fn should_build_book_request() {
+        // Given
+        let pairs = vec!["ETH/BTC".to_string(), "\"ÉTH\"/BTC".to_string()];
+
+        // When
+        let resp = book_request("subscribe", &pairs.iter().collect::<Vec<_>>(), 10);
+
+        // Then
+        let request: serde_json::Value = serde_json::from_str(&resp).unwrap();
+        assert_eq!("subscribe", request["method"]);
+        assert_eq!("book", request["params"]["channel"]);
+        assert_eq!(serde_json::json!(pairs), request["params"]["symbol"]);
+        assert_eq!(10, request["params"]["depth"]);
+    }
+
+    #[test]
+    fn should_choose_book_depth() {
+        // Given
+        let depths = vec![5, 10, 20, 5000];
+
+        // When
+        let resp: Vec<usize> = depths.into_iter().map(book_depth).collect();
+
+        // Then
+        assert_eq!(vec![10, 10, 25, 1000], resp);
+    }
+
+    #[test]
+    fn should_convert_symbol() {
+        // Given
+        let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+
+        // When
+        let resp = symbol_to_string(&symbol);
+
+        // Then
+        assert_eq!("ETH/BTC", resp)
+    }
+}
@@ -0,0 +1,628 @@
+use crate::{
+    book::LocalBook,
+    source::{
+        ConnectionEnd,
+        Source,
+        SourceConfig,
+        SummarySenders,
+    },
+    types::{
+        Level,
+        MBooksError,
+        Symbol,
+    },
+};
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+use opentelemetry::{
+    Context,
+    global,
+    Key,
+    trace::{
+        FutureExt,
+        TraceContextExt,
+        Tracer,
+    },
+};
+use serde_derive::Deserialize;
+use serde_json::json;
+use slog::{
+    debug,
+    error,
+    Logger,
+    info,
+    o,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use tokio::sync::broadcast;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::Message,
+};
+
+const REST_URL: &str = "https://api.kucoin.com";
+/// Levels of the REST snapshot, the book is kept to this depth since the levels after it are unknown.
+const SNAPSHOT_DEPTH: usize = 100;
+
+/// Response from the KuCoin REST API.
+#[derive(Debug, Deserialize)]
+struct RestResponse<T> {
+    code: String,
+    data: Option<T>,
+    msg: Option<String>,
+}
+
+impl<T> RestResponse<T> {
+    fn into_data(self) -> Result<T, MBooksError> {
+        match self.data {
+            Some(data) if self.code == "200000" => Ok(data),
+            _ => Err(MBooksError::ExchangeError(format!(
+                "{}: {}", self.code, self.msg.unwrap_or_default(),
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+struct InstanceServer {
+    endpoint: String,
+    /// Interval in milliseconds to send a ping to keep the connection alive.
+    ping_interval: u64,
+}
+
+impl InstanceServer {
+    /// Interval of the pings, it fails when the server sends 0.
+    fn ping_interval(&self) -> Result<Duration, MBooksError> {
+        match self.ping_interval {
+            0 => Err(MBooksError::ExchangeError("invalid ping interval 0".to_string())),
+            ping_interval => Ok(Duration::from_millis(ping_interval)),
+        }
+    }
+}
+
+/// Token required to connect to the WebSocket, with the servers that accept it.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+struct Bullet {
+    token: String,
+    instance_servers: Vec<InstanceServer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OrderbookSnapshot {
+    sequence: String,
+    bids: Vec<Vec<String>>,
+    asks: Vec<Vec<String>>,
+}
+
+/// Changes in the format `[price, size, sequence]`, the size is absolute and zero removes the level.
+#[derive(Debug, Deserialize)]
+struct Changes {
+    bids: Vec<Vec<String>>,
+    asks: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+struct Level2Update {
+    symbol: String,
+    sequence_start: u64,
+    sequence_end: u64,
+    changes: Changes,
+    /// Time in milliseconds.
+    time: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum WebSocketEvent {
+    #[serde(rename(deserialize = "welcome"))]
+    Welcome,
+    #[serde(rename(deserialize = "message"))]
+    Message {
+        data: Level2Update,
+    },
+    #[serde(rename(deserialize = "error"))]
+    Error {
+        data: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Result of applying a `Level2Update` to the `SequencedBook`.
+#[derive(Debug, PartialEq)]
+enum Sequence {
+    /// The update was already contained in the snapshot.
+    Outdated,
+    Applied,
+    /// Some update was lost, the book needs a new snapshot.
+    Gap,
+}
+
+/// SequencedBook keeps the local book from the `/market/level2` topic.
+/// It starts from a REST snapshot and only applies the changes newer than it, every update must
+/// continue the sequence of the previous one.
+struct SequencedBook {
+    book: LocalBook,
+    sequence: u64,
+}
+
+impl SequencedBook {
+    fn new(snapshot: OrderbookSnapshot) -> Result<Self, MBooksError> {
+        let mut book = LocalBook::new("kucoin");
+        book.update_bids(&snapshot.bids)?;
+        book.update_asks(&snapshot.asks)?;
+
+        Ok(Self {
+            book,
+            sequence: snapshot.sequence.parse()
+                .map_err(|_| MBooksError::ExchangeError(format!("invalid sequence {}", snapshot.sequence)))?,
+        })
+    }
+
+    fn apply(&mut self, update: &Level2Update) -> Result<Sequence, MBooksError> {
+        if update.sequence_end <= self.sequence {
+            return Ok(Sequence::Outdated);
+        }
+        if update.sequence_start > self.sequence + 1 {
+            return Ok(Sequence::Gap);
+        }
+
+        for (changes, bid) in [(&update.changes.bids, true), (&update.changes.asks, false)] {
+            for change in changes {
+                // Each change has its own sequence, only the ones after the snapshot are applied
+                let sequence = change.get(2).and_then(|sequence| sequence.parse::<u64>().ok())
+                    .ok_or_else(|| MBooksError::InvalidLevel(change.clone()))?;
+                if sequence <= self.sequence {
+                    continue;
+                }
+                let level = Level::parse("kucoin", change)?;
+                if bid {
+                    self.book.update_bid(level.price, level.quantity);
+                } else {
+                    self.book.update_ask(level.price, level.quantity);
+                }
+            }
+        }
+        self.book.truncate(SNAPSHOT_DEPTH);
+        self.sequence = update.sequence_end;
+
+        Ok(Sequence::Applied)
+    }
+}
+
+fn symbol_to_string(symbol: &Symbol) -> String {
+    format!("{}-{}", symbol.base.to_string(), symbol.quote.to_string()).to_uppercase()
+}
+
+/// Fetches the token and the server to connect the WebSocket.
+async fn fetch_bullet(log: &Logger, rest_url: &str) -> Result<Bullet, Box<dyn std::error::Error>> {
+    let url = format!("{}/api/v1/bullet-public", rest_url);
+    info!(log, "fetching kucoin token"; "url" => &url);
+
+    let bullet: RestResponse<Bullet> = reqwest::Client::new().post(url).send().await?
+        .error_for_status()?
+        .json().await?;
+
+    Ok(bullet.into_data()?)
+}
+
+/// Fetches the REST snapshot used to start the `SequencedBook`.
+async fn fetch_sequenced_book(
+    log: &Logger, rest_url: &str, symbol: &Symbol,
+) -> Result<SequencedBook, Box<dyn std::error::Error>> {
+    let url = format!(
+        "{}/api/v1/market/orderbook/level2_{}?symbol={}",
+        rest_url, SNAPSHOT_DEPTH, symbol_to_string(symbol),
+    );
+    info!(log, "fetching kucoin snapshot"; "url" => &url);
+
+    let snapshot: RestResponse<OrderbookSnapshot> = reqwest::get(url).await?
+        .error_for_status()?
+        .json().await?;
+    let snapshot = snapshot.into_data()?;
+    info!(log, "got kucoin snapshot"; "sequence" => &snapshot.sequence);
+
+    Ok(SequencedBook::new(snapshot)?)
+}
+
+/// KuCoin `Source` implementation, it only supports the `/market/level2` topic which is kept in a
+/// `LocalBook` for each symbol started from the REST snapshot, so the depth is at most `SNAPSHOT_DEPTH`.
+pub struct Kucoin {
+    symbols: Vec<Symbol>,
+    depth: usize,
+    rest_url: String,
+    /// Replaces the WebSocket URL given with the token.
+    websocket_url: Option<String>,
+}
+
+impl Kucoin {
+    pub fn new(config: SourceConfig) -> Self {
+        Self {
+            rest_url: config.endpoints.rest("kucoin").unwrap_or(REST_URL).to_string(),
+            websocket_url: config.endpoints.websocket("kucoin").map(str::to_string),
+            symbols: config.symbols,
+            depth: config.depth.min(SNAPSHOT_DEPTH),
+        }
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for Kucoin {
+    fn name(&self) -> &'static str {
+        "kucoin"
+    }
+
+    fn symbol_to_string(&self, symbol: &Symbol) -> String {
+        symbol_to_string(symbol)
+    }
+
+    async fn connect(
+        &self,
+        log: &Logger,
+        shutdown_receiver: &mut broadcast::Receiver<String>,
+        summary_txs: &SummarySenders,
+    ) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+        run_kucoin(
+            log, shutdown_receiver, summary_txs, &self.symbols, self.depth,
+            &self.rest_url, self.websocket_url.as_deref(),
+        ).await
+    }
+}
+
+/// Connects to KuCoin and streams the books until the connection ends.
+/// The WebSocket needs a token from the REST API, which also tells the interval of the pings.
+async fn run_kucoin(
+    log: &Logger,
+    shutdown_receiver: &mut broadcast::Receiver<String>,
+    summary_txs: &SummarySenders,
+    symbols: &[Symbol], depth: usize, rest_url: &str, websocket_url: Option<&str>,
+) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+    let tracer = global::tracer("run_kucoin");
+    let span = tracer.start("running kucoin");
+    let cx = Context::current_with_span(span);
+    info!(log, "running kucoin"; "symbols" => format!("{:?}", symbols));
+
+    let bullet = fetch_bullet(log, rest_url).with_context(cx.clone()).await?;
+    let server = bullet.instance_servers.first()
+        .ok_or_else(|| MBooksError::ExchangeError("no instance server".to_string()))?;
+    let ping_interval = server.ping_interval()?;
+    let endpoint = websocket_url.unwrap_or(&server.endpoint);
+
+    let mut url = url::Url::parse(endpoint)?;
+    url.query_pairs_mut().append_pair("token", &bullet.token);
+    info!(log, "kucoin url"; "url" => endpoint);
+
+    let (ws_stream, _) = connect_async(url)
+        .with_context(cx.clone())
+        .await?;
+    info!(log, "WebSocket handshake has been successfully completed");
+
+    let markets: HashMap<String, Symbol> = symbols.iter()
+        .map(|symbol| (symbol_to_string(symbol), symbol.clone()))
+        .collect();
+    let (mut write, mut read) = ws_stream.split();
+
+    let mut books: HashMap<String, SequencedBook> = HashMap::new();
+    let mut ping = tokio::time::interval(ping_interval);
+    let mut request_id: u64 = 0;
+
+    loop {
+        tokio::select! {
+            message = read.next() => {
+                if let Some(message) = message {
+                    debug!(log, "websocket got message");
+                    match message {
+                        Ok(message_data) => {
+                            let received_timestamp = SystemTime::now();
+                            let message_data = message_data.into_data();
+                            summary_txs.record("kucoin", received_timestamp, &message_data);
+                            let kucoin_parse: serde_json::Result<WebSocketEvent> = serde_json::from_slice(
+                                &message_data,
+                            );
+
+                            let update = match kucoin_parse {
+                                Ok(WebSocketEvent::Welcome) => {
+                                    // The updates are buffered by the WebSocket while fetching the snapshots
+                                    request_id += 1;
+                                    let topic = format!(
+                                        "/market/level2:{}", markets.keys().cloned().collect::<Vec<_>>().join(","),
+                                    );
+                                    write.send(Message::Text(
+                                        json!({
+                                            "id": request_id.to_string(),
+                                            "type": "subscribe",
+                                            "topic": topic,
+                                            "response": true,
+                                        }).to_string()
+                                    )).with_context(cx.clone()).await?;
+                                    for (market, symbol) in &markets {
+                                        books.insert(
+                                            market.clone(),
+                                            fetch_sequenced_book(log, rest_url, symbol).with_context(cx.clone()).await?,
+                                        );
+                                    }
+                                    continue;
+                                }
+                                Ok(WebSocketEvent::Message { data }) => data,
+                                Ok(WebSocketEvent::Error { data }) => {
+                                    error!(log, "kucoin sent an error"; "message" => &data);
+                                    return Err(Box::new(MBooksError::ExchangeError(data)));
+                                }
+                                Ok(WebSocketEvent::Other) => continue,
+                                Err(err) => {
+                                    error!(log, "error parsing WebSocket data"; "error" => format!("{}", err));
+                                    cx.span().add_event(
+                                        "error parsing WebSocket data",
+                                        vec![
+                                            Key::new("message").string(format!("{:?}", message_data)),
+                                            Key::new("error").string(format!("{}", err)),
+                                        ],
+                                    );
+                                    continue;
+                                }
+                            };
+
+                            let (symbol, sequenced_book) = match (markets.get(&update.symbol), books.get_mut(&update.symbol)) {
+                                (Some(symbol), Some(sequenced_book)) => (symbol, sequenced_book),
+                                _ => {
+                                    warn!(log, "update from an unknown symbol"; "symbol" => &update.symbol);
+                                    continue;
+                                }
+                            };
+                            let log = log.new(o!("symbol" => update.symbol.clone()));
+
+                            match sequenced_book.apply(&update) {
+                                Ok(Sequence::Applied) => {
+                                    let summary = sequenced_book.book.summary(depth).with_timestamps(
+                                        Some(UNIX_EPOCH + Duration::from_millis(update.time)),
+                                        received_timestamp,
+                                    );
+                                    if let Err(err) = summary_txs.send(symbol, summary) {
+                                        error!(
+                                            log, "error sending information to the channel";
+                                            "error" => format!("{}", err)
+                                        );
+                                        cx.span().add_event(
+                                            "error sending information to the channel",
+                                            vec![
+                                                Key::new("error").string(format!("{}", err)),
+                                            ],
+                                        );
+                                    }
+                                }
+                                Ok(Sequence::Outdated) => {
+                                    debug!(log, "ignoring outdated update"; "sequence_end" => update.sequence_end);
+                                }
+                                Ok(Sequence::Gap) => {
+                                    warn!(
+                                        log, "gap in the level2 topic, fetching a new snapshot";
+                                        "sequence" => sequenced_book.sequence,
+                                        "sequence_start" => update.sequence_start
+                                    );
+                                    cx.span().add_event(
+                                        "gap in the level2 topic",
+                                        vec![
+                                            Key::new("symbol").string(update.symbol.clone()),
+                                            Key::new("sequence").i64(sequenced_book.sequence as i64),
+                                        ],
+                                    );
+                                    *sequenced_book = fetch_sequenced_book(&log, rest_url, symbol)
+                                        .with_context(cx.clone()).await?;
+                                }
+                                Err(err) => {
+                                    error!(
+                                        log, "error converting WebSocket data to domain type";
+                                        "error" => format!("{}", err)
+                                    );
+                                    cx.span().add_event(
+                                        "error converting WebSocket data to domain type",
+                                        vec![
+                                            Key::new("error").string(format!("{:?}", err)),
+                                        ],
+                                    );
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            error!(log, "problem fetching message"; "error" => format!("{}", err));
+                            return Err(Box::new(err));
+                        }
+                    }
+                } else {
+                    info!(log, "no more messages run_kucoin");
+                    return Ok(ConnectionEnd::Closed);
+                }
+            }
+            _ = ping.tick() => {
+                debug!(log, "sending ping");
+                request_id += 1;
+                write.send(Message::Text(
+                    json!({"id": request_id.to_string(), "type": "ping"}).to_string()
+                )).with_context(cx.clone()).await?;
+            }
+            _ = shutdown_receiver.recv() => {
+                info!(log, "application is shutting down, closing run_kucoin");
+                return Ok(ConnectionEnd::Shutdown);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        kucoin::{
+            symbol_to_string,
+            Bullet,
+            Changes,
+            InstanceServer,
+            Level2Update,
+            RestResponse,
+            Sequence,
+            SequencedBook,
+            WebSocketEvent,
+            SNAPSHOT_DEPTH,
+        },
+        types::{
+            Asset,
+            MBooksError,
+            Symbol,
+        },
+    };
+    use rust_decimal_macros::dec;
+
+    fn sequenced_book() -> SequencedBook {
+        let msg = r#"{"code":"200000","data":{"time":1666200249249,"sequence":"100","bids":[["0.06754","12.5"],["0.06753","1.2"]],"asks":[["0.06756","3.0"]]}}"#;
+        let snapshot: RestResponse<_> = serde_json::from_str(msg).unwrap();
+        SequencedBook::new(snapshot.into_data().unwrap()).unwrap()
+    }
+
+    fn update(sequence_start: u64, sequence_end: u64) -> Level2Update {
+        Level2Update {
+            symbol: "ETH-BTC".to_string(),
+            sequence_start,
+            sequence_end,
+            changes: Changes {
+                bids: vec![vec!["0.06754".to_string(), "0".to_string(), sequence_start.to_string()]],
+                asks: vec![vec!["0.06755".to_string(), "2.0".to_string(), sequence_end.to_string()]],
+            },
+            time: 1666200249349,
+        }
+    }
+
+    #[test]
+    fn should_parse_bullet() {
+        // Given
+        let msg = r#"{"code":"200000","data":{"token":"2neAiuYvAU61ZDXANAGAsiL4-iAExhsBXZxftpOeh_55i3Ysy2q2LEsEWU64mdzUOPusi34M_wGoSf7iNyEWJ4aBZXpWhrmY9jKtqkdWoFa75w3istPvPtiYB9J6i9GjsxUuhPw3BlrzazF6ghq4L_D-qnRFT6dYeRA_Sp9Q4E3J.95rXPcyVT4N37K3CH5UTjQ==","instanceServers":[{"endpoint":"wss://ws-api-spot.kucoin.com/","encrypt":true,"protocol":"websocket","pingInterval":18000,"pingTimeout":10000}]}}"#;
+
+        // When
+        let resp: RestResponse<Bullet> = serde_json::from_str(msg).unwrap();
+
+        // Then
+        let bullet = resp.into_data().unwrap();
+        assert_eq!("wss://ws-api-spot.kucoin.com/", bullet.instance_servers[0].endpoint);
+        assert_eq!(18000, bullet.instance_servers[0].ping_interval);
+    }
+
+    #[test]
+    fn should_fail_rest_error() {
+        // Given
+        let msg = r#"{"code":"400100","msg":"This pair is not provided at present"}"#;
+
+        // When
+        let resp: RestResponse<Bullet> = serde_json::from_str(msg).unwrap();
+
+        // Then
+        assert!(resp.into_data().is_err());
+    }
+
+    #[test]
+    fn should_parse_level2_message() {
+        // Given
+        let msg = r#"{"type":"message","topic":"/market/level2:ETH-BTC","subject":"trade.l2update","data":{"changes":{"asks":[["0.06755","2.0","102"]],"bids":[]},"sequenceEnd":102,"sequenceStart":101,"symbol":"ETH-BTC","time":1666200249349}}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        if let WebSocketEvent::Message { data } = resp {
+            assert_eq!("ETH-BTC", data.symbol);
+            assert_eq!(101, data.sequence_start);
+            assert_eq!(1, data.changes.asks.len());
+        } else {
+            panic!("not a message");
+        }
+    }
+
+    #[test]
+    fn should_apply_updates_in_sequence() {
+        // Given
+        let mut book = sequenced_book();
+
+        // When
+        let outdated = book.apply(&update(99, 100)).unwrap();
+        let first = book.apply(&update(100, 102)).unwrap();
+        let second = book.apply(&update(103, 104)).unwrap();
+
+        // Then
+        assert_eq!(Sequence::Outdated, outdated);
+        assert_eq!(Sequence::Applied, first);
+        assert_eq!(Sequence::Applied, second);
+        assert_eq!(104, book.sequence);
+        let summary = book.book.summary(10);
+        assert_eq!(1, summary.bids.len());
+        assert_eq!(dec!(0.06753), summary.bids[0].price);
+        assert_eq!(2, summary.asks.len());
+        assert_eq!(dec!(0.06755), summary.asks[0].price);
+    }
+
+    #[test]
+    fn should_detect_gap() {
+        // Given
+        let mut book = sequenced_book();
+
+        // When
+        let resp = book.apply(&update(102, 103)).unwrap();
+
+        // Then
+        assert_eq!(Sequence::Gap, resp);
+        assert_eq!(100, book.sequence);
+    }
+
+    #[test]
+    fn should_keep_the_snapshot_depth() {
+        // Given
+        let mut book = sequenced_book();
+        let mut deep = update(101, 102);
+        deep.changes.bids = (0..SNAPSHOT_DEPTH as u64)
+            .map(|i| vec![format!("0.0{}", 6000 + i), "1".to_string(), "102".to_string()])
+            .collect();
+
+        // When
+        book.apply(&deep).unwrap();
+
+        // Then
+        let summary = book.book.summary(1000);
+        assert_eq!(SNAPSHOT_DEPTH, summary.bids.len());
+        assert_eq!(dec!(0.06754), summary.bids[0].price);
+        // The 2 worst levels are dropped
+        assert_eq!(dec!(0.06002), summary.bids[SNAPSHOT_DEPTH - 1].price);
+    }
+
+    #[test]
+    fn should_reject_zero_ping_interval() {
+        // Given
+        let server = InstanceServer { endpoint: "wss://ws-api-spot.kucoin.com/".to_string(), ping_interval: 0 };
+
+        // When
+        let resp = server.ping_interval();
+
+        // Then
+        assert!(matches!(resp, Err(MBooksError::ExchangeError(_))));
+    }
+
+    #[test]
+    fn should_convert_symbol() {
+        // Given
+        let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+
+        // When
+        let resp = symbol_to_string(&symbol);
+
+        // Then
+        assert_eq!("ETH-BTC", resp)
+    }
+}
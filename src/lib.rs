@@ -2,9 +2,21 @@ extern crate slog;
 extern crate slog_term;
 
 pub mod types;
+mod arbitrage;
 mod binance;
 mod bitstamp;
+mod book;
+mod bybit;
+mod coinbase;
+mod delta;
+mod kraken;
+mod kucoin;
+mod okx;
 mod orderbook;
 pub mod client;
 pub mod server;
 pub mod merger;
+pub mod mock;
+pub mod recorder;
+pub mod replay;
+pub mod source;
@@ -1,15 +1,48 @@
 extern crate mbooks;
 
 use clap::{
-    arg,
     Parser,
     Subcommand,
 };
 use mbooks::{
-    client::run_client,
-    server::run_server,
+    client::{
+        get_snapshot,
+        run_arbitrage_client,
+        run_client,
+        run_delta_client,
+        SummaryRequest,
+    },
+    recorder::{
+        FeedRecorder,
+        SummaryRecorder,
+    },
+    replay::{
+        Replay,
+        ReplayPace,
+    },
+    merger::TakerFees,
+    mock::{
+        MockBooks,
+        MockExchange,
+        MockProtocol,
+    },
+    server::{
+        run_replay,
+        run_server,
+        ReplayConfig,
+        ServerConfig,
+        SlowClientPolicy,
+    },
+    source::{
+        BookMode,
+        Endpoints,
+        Source,
+        SourceConfig,
+        SourceRegistry,
+    },
     types::Symbol,
 };
+use rust_decimal::Decimal;
 use opentelemetry::{
     global,
     sdk::trace as sdktrace,
@@ -21,12 +54,17 @@ use slog::{
     Logger,
     o,
 };
+use std::{
+    path::PathBuf,
+    time::Duration,
+};
 use tokio::{
     signal,
     sync::broadcast,
 };
 
 #[derive(Clone, Subcommand)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     /// Runs the server
     Server {
@@ -36,15 +74,132 @@ pub enum Command {
         /// The depth of the book
         #[arg(short, long, default_value = "10")]
         depth: usize,
-        /// The symbol to be pulled from the websocket.
-        #[arg(short, long, default_value = "eth/btc")]
-        symbol: String,
+        /// The symbols to be pulled from the websocket, separated by comma.
+        #[arg(short, long, alias = "symbol", value_delimiter = ',', default_value = "eth/btc")]
+        symbols: Vec<String>,
+        /// The exchanges to connect to, separated by comma.
+        #[arg(short, long, value_delimiter = ',', default_value = "binance,bitstamp")]
+        exchanges: Vec<String>,
+        /// How the book is pulled from the exchanges, `snapshot` or `diff`.
+        #[arg(short, long, default_value = "snapshot")]
+        book_mode: String,
+        /// Milliseconds without updates from an exchange after which its levels are removed,
+        /// 0 disables it.
+        #[arg(long, default_value = "10000")]
+        stale_timeout_ms: u64,
+        /// Taker fees of the exchanges as `exchange=fee`, separated by comma, e.g.
+        /// `binance=0.001,bitstamp=0.004`, to merge the levels by their fee-adjusted prices.
+        #[arg(long, value_delimiter = ',')]
+        taker_fees: Vec<String>,
+        /// What to do with a client that does not keep up with the summaries, `conflate` to skip
+        /// the outdated ones or `disconnect` once it skips more than `--max-client-lag` in a row.
+        #[arg(long, default_value = "conflate")]
+        slow_client_policy: String,
+        /// Summaries a client can skip in a row before being disconnected.
+        #[arg(long, default_value = "100")]
+        max_client_lag: u64,
+        /// Replaces the WebSocket URL of an exchange as `exchange=url`, separated by comma,
+        /// e.g. `binance=ws://localhost:9001`.
+        #[arg(long, value_delimiter = ',')]
+        websocket_endpoints: Vec<String>,
+        /// Replaces the REST URL of an exchange as `exchange=url`, separated by comma,
+        /// e.g. `binance=http://localhost:9002`.
+        #[arg(long, value_delimiter = ',')]
+        rest_endpoints: Vec<String>,
+        /// Directory to record every frame received from the exchanges, in gzip compressed JSON
+        /// lines files.
+        #[arg(long)]
+        record_dir: Option<PathBuf>,
+        /// Directory to record every summary published to the clients, to be served again with
+        /// the `replay` command.
+        #[arg(long)]
+        record_summaries_dir: Option<PathBuf>,
+        /// Megabytes of uncompressed frames or summaries in each recorded file before starting a
+        /// new one.
+        #[arg(long, default_value = "100", value_parser = clap::value_parser!(u64).range(1..=u64::MAX / 1024 / 1024))]
+        record_max_mb: u64,
+        /// Directory with the frames recorded with `--record-dir` to replay instead of connecting
+        /// to the exchanges, which must be the same, with the same symbols and depth.
+        #[arg(long)]
+        replay_dir: Option<PathBuf>,
+        /// How fast the recorded frames are replayed, `max` for as fast as possible or a speed
+        /// multiplier, 1 for the original one.
+        #[arg(long, default_value = "1")]
+        replay_speed: String,
+    },
+    /// Serves the summaries recorded with `--record-summaries-dir` as the server did
+    Replay {
+        /// Address for the server.
+        #[arg(short, long, default_value = "[::1]:50501")]
+        address: String,
+        /// Directory with the recorded summaries.
+        #[arg(short, long)]
+        dir: PathBuf,
+        /// How fast the summaries are replayed, `max` for as fast as possible or a speed
+        /// multiplier, 1 for the original one.
+        #[arg(short, long, default_value = "1")]
+        speed: String,
+        /// Starts again from the first summary after the last one.
+        #[arg(short, long = "loop")]
+        looping: bool,
+        /// What to do with a client that does not keep up with the summaries, `conflate` or
+        /// `disconnect`, as in the server.
+        #[arg(long, default_value = "conflate")]
+        slow_client_policy: String,
+        /// Summaries a client can skip in a row before being disconnected.
+        #[arg(long, default_value = "100")]
+        max_client_lag: u64,
     },
     /// Runs the client
     Client {
         /// Address of the server to connect to.
         #[arg(short, long, default_value = "http://[::1]:50501")]
         address: String,
+        /// The symbol to request, the first one served when not set.
+        #[arg(short, long)]
+        symbol: Option<String>,
+        /// The depth of the book to request, 0 for the server depth.
+        #[arg(short, long, default_value = "0")]
+        depth: u32,
+        /// The exchanges to request separated by comma, all of them when not set.
+        #[arg(short, long, value_delimiter = ',')]
+        exchanges: Vec<String>,
+        /// Requests the current book once instead of listening to the updates.
+        #[arg(long)]
+        snapshot: bool,
+        /// Listens to the level changes instead of the whole book on every update.
+        #[arg(long)]
+        deltas: bool,
+        /// Listens to the arbitrage opportunities between the exchanges instead of the book.
+        #[arg(long)]
+        arbitrage: bool,
+        /// Requests a single level per price with the amount of all the exchanges.
+        #[arg(long)]
+        aggregated: bool,
+        /// Price step to group the levels in, e.g. `0.0001`, rounding the bids down and the asks up.
+        #[arg(long)]
+        bucket_size: Option<String>,
+    },
+    /// Runs a mock exchange serving a randomly walked book, to be used as the server endpoint
+    MockExchange {
+        /// Address for the mock exchange.
+        #[arg(short, long, default_value = "127.0.0.1:9001")]
+        address: String,
+        /// The exchange feed to imitate, `binance` or `bitstamp`.
+        #[arg(short, long, default_value = "binance")]
+        exchange: String,
+        /// Initial mid price of the book.
+        #[arg(long, default_value = "0.0675")]
+        mid: Decimal,
+        /// Distance between the levels, also the maximum move of the mid price.
+        #[arg(long, default_value = "0.00001")]
+        tick: Decimal,
+        /// Levels on each side of the book.
+        #[arg(short, long, default_value = "20")]
+        levels: usize,
+        /// Milliseconds between the books sent, more than 0.
+        #[arg(long, default_value = "100", value_parser = clap::value_parser!(u64).range(1..))]
+        interval_ms: u64,
     },
 }
 
@@ -90,15 +245,100 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut receiver = shutdown_sender.subscribe();
     match Cli::parse().command.clone() {
-        Command::Server { address, symbol, depth, .. } => {
-            let symbol = Symbol::try_from(symbol)?;
+        Command::Server {
+            address, symbols, depth, exchanges, book_mode, stale_timeout_ms, taker_fees, slow_client_policy,
+            max_client_lag, websocket_endpoints, rest_endpoints, record_dir, record_summaries_dir,
+            record_max_mb, replay_dir, replay_speed, ..
+        } => {
+            let symbols = symbols.into_iter()
+                .map(Symbol::try_from)
+                .collect::<Result<Vec<_>, _>>()?;
+            let book_mode = BookMode::try_from(book_mode.as_str())?;
+            let mut endpoints = Endpoints::new();
+            for endpoint in &websocket_endpoints {
+                endpoints.insert_websocket(endpoint)?;
+            }
+            for endpoint in &rest_endpoints {
+                endpoints.insert_rest(endpoint)?;
+            }
+            let config = SourceConfig { symbols: symbols.clone(), depth, book_mode, endpoints };
+            let sources: Vec<Box<dyn Source>> = match replay_dir {
+                Some(replay_dir) => {
+                    let pace = ReplayPace::try_from(replay_speed.as_str())?;
+                    vec![Box::new(Replay::new(config, &exchanges, &replay_dir, pace)?)]
+                }
+                None => SourceRegistry::default().build(&exchanges, &config)?,
+            };
+            let stale_timeout = match stale_timeout_ms {
+                0 => None,
+                stale_timeout_ms => Some(Duration::from_millis(stale_timeout_ms)),
+            };
+            let mut fees = TakerFees::new();
+            for fee in &taker_fees {
+                fees.insert(fee)?;
+            }
+            let slow_client_policy = SlowClientPolicy::new(&slow_client_policy, max_client_lag)?;
+            let (recorder, recorder_handle) = match record_dir {
+                Some(record_dir) => {
+                    let (recorder, handle) = FeedRecorder::start(
+                        logger.clone(), &record_dir, record_max_mb * 1024 * 1024,
+                    )?;
+                    (Some(recorder), Some(handle))
+                }
+                None => (None, None),
+            };
+            let (summary_recorder, summary_recorder_handle) = match record_summaries_dir {
+                Some(record_summaries_dir) => {
+                    let (recorder, handle) = SummaryRecorder::start(
+                        logger.clone(), &record_summaries_dir, record_max_mb * 1024 * 1024,
+                    )?;
+                    (Some(recorder), Some(handle))
+                }
+                None => (None, None),
+            };
             run_server(
                 logger.clone(), shutdown_sender.clone(),
-                address, symbol, depth,
+                ServerConfig {
+                    address, symbols, depth, stale_timeout, taker_fees: fees, slow_client_policy, recorder,
+                    summary_recorder,
+                },
+                sources,
             ).await?;
+            // The sources are gone, so the recorders complete the last files
+            for handle in recorder_handle.into_iter().chain(summary_recorder_handle) {
+                let _ = handle.join();
+            }
+        }
+        Command::Replay { address, dir, speed, looping, slow_client_policy, max_client_lag, .. } => {
+            let config = ReplayConfig {
+                address,
+                directory: dir,
+                pace: ReplayPace::try_from(speed.as_str())?,
+                looping,
+                slow_client_policy: SlowClientPolicy::new(&slow_client_policy, max_client_lag)?,
+            };
+            run_replay(logger.clone(), shutdown_sender.clone(), config).await?;
+        }
+        Command::Client {
+            address, symbol, depth, exchanges, snapshot, deltas, arbitrage, aggregated, bucket_size, ..
+        } => {
+            let request = SummaryRequest { symbol, depth, exchanges, aggregated, bucket_size };
+            if snapshot {
+                get_snapshot(logger.clone(), address, request).await?;
+                let _ = shutdown_sender.send("got the snapshot".to_string());
+            } else if arbitrage {
+                run_arbitrage_client(logger.clone(), &mut receiver, address, request.symbol).await?;
+            } else if deltas {
+                run_delta_client(logger.clone(), &mut receiver, address, request).await?;
+            } else {
+                run_client(logger.clone(), &mut receiver, address, request).await?;
+            }
         }
-        Command::Client { address, .. } => {
-            run_client(logger.clone(), &mut receiver, address).await?;
+        Command::MockExchange { address, exchange, mid, tick, levels, interval_ms, .. } => {
+            let protocol = MockProtocol::try_from(exchange.as_str())?;
+            MockExchange::bind(&address, protocol, MockBooks::RandomWalk { mid, tick, levels }).await?
+                .with_interval(Duration::from_millis(interval_ms))
+                .run(logger.clone(), receiver).await?;
         }
     };
 
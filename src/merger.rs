@@ -1,8 +1,9 @@
 use crate::{
-    orderbook,
     types::{
         self,
+        BookUpdate,
         Level,
+        MBooksError,
     },
 };
 use opentelemetry::{
@@ -14,32 +15,86 @@ use opentelemetry::{
         Tracer,
     },
 };
+use rust_decimal::Decimal;
 use slog::{
     error,
     info,
     Logger,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 use tokio::sync::mpsc::{
     UnboundedReceiver,
     UnboundedSender,
 };
 
+/// How often the exchanges are checked for staleness when no timeout is configured.
+/// The check is disabled in that case, it is only used to create the timer.
+const DEFAULT_STALE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// TakerFees keeps the taker fee of each exchange, as a fraction of the price, so the
+/// `OrderbookMerger` can merge the levels by the price actually paid to take them.
+#[derive(Clone, Debug, Default)]
+pub struct TakerFees {
+    fees: HashMap<String, Decimal>,
+}
+
+impl TakerFees {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the fee of an exchange, in the format `exchange=fee`, e.g. `binance=0.001` for 0.1%.
+    pub fn insert(&mut self, value: &str) -> Result<(), MBooksError> {
+        let (exchange, fee) = value.split_once('=')
+            .ok_or_else(|| MBooksError::InvalidFee(value.to_string()))?;
+        match fee.trim().parse::<Decimal>() {
+            Ok(fee) if fee >= Decimal::ZERO && fee < Decimal::ONE => {
+                self.fees.insert(exchange.trim().to_lowercase(), fee);
+                Ok(())
+            }
+            _ => Err(MBooksError::InvalidFee(value.to_string())),
+        }
+    }
+
+    /// Fee of the exchange, 0 when not set.
+    pub fn fee(&self, exchange: &str) -> Decimal {
+        self.fees.get(exchange).copied().unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fees.is_empty()
+    }
+}
+
 pub struct OrderbookMerger {
     log: Logger,
     /// Used to listen to updates from the WebSockets.
-    summary_receiver: UnboundedReceiver<types::Summary>,
+    summary_receiver: UnboundedReceiver<BookUpdate>,
     /// Used to send updates to connected clients.
-    summary_sender: UnboundedSender<orderbook::Summary>,
+    summary_sender: UnboundedSender<types::Summary>,
     bids: Vec<Level>,
     asks: Vec<Level>,
     depth: usize,
+    /// When the last update from each exchange was received.
+    last_updates: HashMap<String, Instant>,
+    /// Time without updates after which the levels from an exchange are removed.
+    stale_timeout: Option<Duration>,
+    /// Fees set to the levels received, so they are sorted by the fee-adjusted prices.
+    taker_fees: TakerFees,
 }
 
 impl OrderbookMerger {
     pub fn new(
         log: Logger,
-        summary_receiver: UnboundedReceiver<types::Summary>,
-        summary_sender: UnboundedSender<orderbook::Summary>,
+        summary_receiver: UnboundedReceiver<BookUpdate>,
+        summary_sender: UnboundedSender<types::Summary>,
         depth: usize,
     ) -> Self {
         Self {
@@ -49,15 +104,62 @@ impl OrderbookMerger {
             depth,
             bids: Vec::new(),
             asks: Vec::new(),
+            last_updates: HashMap::new(),
+            stale_timeout: None,
+            taker_fees: TakerFees::new(),
         }
     }
 
+    /// Removes the levels from an exchange once it goes `stale_timeout` without sending updates,
+    /// so the clients never get frozen quotes.
+    pub fn with_stale_timeout(mut self, stale_timeout: Duration) -> Self {
+        self.stale_timeout = Some(stale_timeout);
+        self
+    }
+
+    /// Merges the levels by their fee-adjusted prices, bid × (1 − fee) and ask × (1 + fee), so the
+    /// top of the book and the spread are the prices actually paid, while each `Level` keeps the
+    /// price sent by the exchange.
+    pub fn with_taker_fees(mut self, taker_fees: TakerFees) -> Self {
+        self.taker_fees = taker_fees;
+        self
+    }
+
     /// Generates a `Summary` from the internal state.
+    /// It has up to `depth` levels from each exchange, so every client can choose its own depth and
+    /// exchanges, with the timestamps of the most recent level.
     fn summary(&self) -> types::Summary {
-        types::Summary {
-            bids: self.bids.iter().take(self.depth).cloned().collect(),
-            asks: self.asks.iter().take(self.depth).cloned().collect(),
+        types::Summary::from_levels(self.bids.clone(), self.asks.clone())
+    }
+
+    /// Removes all the levels from the exchange.
+    fn remove_exchange(&mut self, exchange: &str) {
+        self.bids.retain(|x| x.exchange != exchange);
+        self.asks.retain(|x| x.exchange != exchange);
+        self.last_updates.remove(exchange);
+    }
+
+    /// Removes the levels from the exchanges without updates for longer than the `stale_timeout`,
+    /// returning if any was removed.
+    fn remove_stale_exchanges(&mut self, now: Instant) -> bool {
+        let stale_timeout = match self.stale_timeout {
+            Some(stale_timeout) => stale_timeout,
+            None => return false,
+        };
+
+        let stale: Vec<String> = self.last_updates.iter()
+            .filter(|(_, last_update)| now.saturating_duration_since(**last_update) > stale_timeout)
+            .map(|(exchange, _)| exchange.clone())
+            .collect();
+        for exchange in &stale {
+            warn!(
+                self.log, "exchange is stale, removing its levels";
+                "exchange" => exchange, "stale_timeout_ms" => stale_timeout.as_millis() as u64
+            );
+            self.remove_exchange(exchange);
         }
+
+        !stale.is_empty()
     }
 
     /// Starts the process of listening to summary updates from the WebSockets and notifies with
@@ -72,21 +174,43 @@ impl OrderbookMerger {
         info!(self.log, "starting merger");
 
         let mut shutdown_receiver = shutdown_sender.subscribe();
+        let mut stale_check = tokio::time::interval(
+            self.stale_timeout.map_or(DEFAULT_STALE_CHECK_PERIOD, |timeout| {
+                (timeout / 2).max(Duration::from_millis(1))
+            }),
+        );
         loop {
             tokio::select! {
                 message = self.summary_receiver.recv().with_context(cx.clone()) => {
-                    if let Some (summary) = message {
-                        // Avoiding having to clone bids and asks from self
-                        let mut asks = Vec::new();
-                        std::mem::swap(&mut asks, &mut self.asks);
-                        let mut bids = Vec::new();
-                        std::mem::swap(&mut bids, &mut self.bids);
-
-                        (self.bids, self.asks) = Self::process_summary(
-                            self.log.clone(), bids, asks, summary, self.depth,
-                        );
-
-                        if let Err(err) = self.summary_sender.send(self.summary().into()) {
+                    if let Some (update) = message {
+                        match update {
+                            BookUpdate::Summary(mut summary) => {
+                                if let Some(level) = summary.bids.first().or_else(|| summary.asks.first()) {
+                                    self.last_updates.insert(level.exchange.clone(), Instant::now());
+                                }
+                                if !self.taker_fees.is_empty() {
+                                    for level in summary.bids.iter_mut().chain(summary.asks.iter_mut()) {
+                                        level.fee = self.taker_fees.fee(&level.exchange);
+                                    }
+                                }
+
+                                // Avoiding having to clone bids and asks from self
+                                let mut asks = Vec::new();
+                                std::mem::swap(&mut asks, &mut self.asks);
+                                let mut bids = Vec::new();
+                                std::mem::swap(&mut bids, &mut self.bids);
+
+                                (self.bids, self.asks) = Self::process_summary(
+                                    self.log.clone(), bids, asks, summary, self.depth,
+                                );
+                            }
+                            BookUpdate::Disconnected(exchange) => {
+                                warn!(self.log, "exchange disconnected, removing its levels"; "exchange" => &exchange);
+                                self.remove_exchange(&exchange);
+                            }
+                        }
+
+                        if let Err(err) = self.summary_sender.send(self.summary()) {
                             error!(self.log, "problem sending summary"; "error" => format!("{}", err));
                         }
                     } else {
@@ -94,6 +218,13 @@ impl OrderbookMerger {
                         return Ok(());
                     }
                 }
+                _ = stale_check.tick(), if self.stale_timeout.is_some() => {
+                    if self.remove_stale_exchanges(Instant::now()) {
+                        if let Err(err) = self.summary_sender.send(self.summary()) {
+                            error!(self.log, "problem sending summary"; "error" => format!("{}", err));
+                        }
+                    }
+                }
                 _ = shutdown_receiver.recv() => {
                     info!(self.log, "application is shutting down, closing merger");
                     return Ok(());
@@ -118,10 +249,10 @@ impl OrderbookMerger {
         std::mem::swap(&mut summary_bids, &mut summary.bids);
 
         let (bids, exchange_bids) = Self::process_summary_asks_bids(
-            summary_bids, bids, -1.0, depth,
+            summary_bids, bids, Decimal::NEGATIVE_ONE, depth,
         );
         let (asks, exchange_aks) = Self::process_summary_asks_bids(
-            summary_asks, asks, 1.0, depth,
+            summary_asks, asks, Decimal::ONE, depth,
         );
 
         info!(
@@ -135,7 +266,7 @@ impl OrderbookMerger {
 
     /// Process a summary update for the ask or bid side.
     fn process_summary_asks_bids(
-        summary_asks_bids: Vec<Level>, asks_bids: Vec<Level>, multiplier: f64, depth: usize,
+        summary_asks_bids: Vec<Level>, asks_bids: Vec<Level>, multiplier: Decimal, depth: usize,
     ) -> (Vec<Level>, Option<String>) {
         if summary_asks_bids.is_empty() {
             return (asks_bids, None);
@@ -161,8 +292,9 @@ impl OrderbookMerger {
         for _ in 0..resp.capacity() {
             resp.push(Level {
                 exchange: "".to_string(),
-                price: 0.0,
-                quantity: 0.0,
+                price: Decimal::ZERO,
+                quantity: Decimal::ZERO,
+                ..Default::default()
             });
         }
 
@@ -173,7 +305,8 @@ impl OrderbookMerger {
                 continue;
             }
 
-            if asks_bids[idx_asks_bids].price * multiplier < summary_asks_bids[idx_summary].price * multiplier {
+            if asks_bids[idx_asks_bids].adjusted_price(multiplier) * multiplier
+                < summary_asks_bids[idx_summary].adjusted_price(multiplier) * multiplier {
                 std::mem::swap(&mut resp[idx_resp], &mut asks_bids[idx_asks_bids]);
                 idx_asks_bids += 1;
                 idx_resp += 1;
@@ -208,17 +341,29 @@ impl OrderbookMerger {
 #[cfg(test)]
 mod test {
     use crate::{
-        merger::OrderbookMerger,
+        merger::{
+            OrderbookMerger,
+            TakerFees,
+        },
         types::{
+            Asset,
+            BookUpdate,
             Level,
             Summary,
+            SummaryOptions,
+            Symbol,
         },
     };
+    use rust_decimal_macros::dec;
     use slog::{
         Logger,
         Drain,
         o,
     };
+    use std::time::{
+        Duration,
+        Instant,
+    };
     use tokio::sync::mpsc;
 
     #[tokio::test]
@@ -239,33 +384,39 @@ mod test {
             bids: vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 1.0,
-                    quantity: 10.0,
+                    price: dec!(1.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 0.9,
-                    quantity: 10.0,
+                    price: dec!(0.9),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
             asks: vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 2.0,
-                    quantity: 10.0,
+                    price: dec!(2.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 3.0,
-                    quantity: 10.0,
+                    price: dec!(3.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 4.0,
-                    quantity: 10.0,
+                    price: dec!(4.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
-        }).unwrap();
+            ..Default::default()
+        }.into()).unwrap();
         drop(test_sender);
         let (shutdown_sender, shutdown_receiver) = tokio::sync::broadcast::channel(1);
         merger.start(shutdown_sender).await.unwrap();
@@ -277,18 +428,21 @@ mod test {
             vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 2.0,
-                    quantity: 10.0,
+                    price: dec!(2.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 3.0,
-                    quantity: 10.0,
+                    price: dec!(3.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 4.0,
-                    quantity: 10.0,
+                    price: dec!(4.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
         );
@@ -299,17 +453,19 @@ mod test {
             vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 1.0,
-                    quantity: 10.0,
+                    price: dec!(1.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 0.9,
-                    quantity: 10.0,
+                    price: dec!(0.9),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
         );
-        assert_eq!(1.0, merger.summary().spread());
+        assert_eq!(Some(dec!(1.0)), merger.summary().spread());
     }
 
     #[tokio::test]
@@ -330,25 +486,29 @@ mod test {
         merger.bids = vec![
             Level {
                 exchange: binance.clone(),
-                price: 1.0,
-                quantity: 10.0,
+                price: dec!(1.0),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
             Level {
                 exchange: bitstamp.clone(),
-                price: 0.9,
-                quantity: 10.0,
+                price: dec!(0.9),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
         ];
         merger.asks = vec![
             Level {
                 exchange: binance.clone(),
-                price: 2.0,
-                quantity: 10.0,
+                price: dec!(2.0),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
             Level {
                 exchange: bitstamp.clone(),
-                price: 3.0,
-                quantity: 10.0,
+                price: dec!(3.0),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
         ];
 
@@ -356,55 +516,65 @@ mod test {
             bids: vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 1.1,
-                    quantity: 10.0,
+                    price: dec!(1.1),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 1.05,
-                    quantity: 10.0,
+                    price: dec!(1.05),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
             asks: vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 2.1,
-                    quantity: 10.0,
+                    price: dec!(2.1),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 3.1,
-                    quantity: 10.0,
+                    price: dec!(3.1),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
-        }).unwrap();
+            ..Default::default()
+        }.into()).unwrap();
 
         test_sender.send(Summary {
             bids: vec![
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 1.11,
-                    quantity: 10.0,
+                    price: dec!(1.11),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 1.051,
-                    quantity: 10.0,
+                    price: dec!(1.051),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
             asks: vec![
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 2.11,
-                    quantity: 10.0,
+                    price: dec!(2.11),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 3.11,
-                    quantity: 10.0,
+                    price: dec!(3.11),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
-        }).unwrap();
+            ..Default::default()
+        }.into()).unwrap();
         drop(test_sender);
         let (shutdown_sender, shutdown_receiver) = tokio::sync::broadcast::channel(1);
         merger.start(shutdown_sender).await.unwrap();
@@ -416,23 +586,27 @@ mod test {
             vec![
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 1.11,
-                    quantity: 10.0,
+                    price: dec!(1.11),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 1.1,
-                    quantity: 10.0,
+                    price: dec!(1.1),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 1.051,
-                    quantity: 10.0,
+                    price: dec!(1.051),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 1.05,
-                    quantity: 10.0,
+                    price: dec!(1.05),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
         );
@@ -443,42 +617,55 @@ mod test {
             vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 2.1,
-                    quantity: 10.0,
+                    price: dec!(2.1),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 2.11,
-                    quantity: 10.0,
+                    price: dec!(2.11),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 3.1,
-                    quantity: 10.0,
+                    price: dec!(3.1),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 3.11,
-                    quantity: 10.0,
+                    price: dec!(3.11),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
         );
 
-        let summary = merger.summary();
-        assert_eq!(0.99, summary.spread());
+        let summary = SummaryOptions {
+            symbol: Symbol { base: Asset::ETH, quote: Asset::BTC },
+            depth: 2,
+            exchanges: Vec::new(),
+            aggregated: false,
+            bucket_size: None,
+            exchange_depth: 10,
+        }.apply(&merger.summary());
+        assert_eq!(Some(dec!(0.99)), summary.spread());
         assert_eq!(2, summary.bids.len());
         assert_eq!(
             summary.bids,
             vec![
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 1.11,
-                    quantity: 10.0,
+                    price: dec!(1.11),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 1.1,
-                    quantity: 10.0,
+                    price: dec!(1.1),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
         );
@@ -489,13 +676,15 @@ mod test {
             vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 2.1,
-                    quantity: 10.0,
+                    price: dec!(2.1),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 2.11,
-                    quantity: 10.0,
+                    price: dec!(2.11),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
         );
@@ -519,32 +708,37 @@ mod test {
         merger.bids = vec![
             Level {
                 exchange: binance.clone(),
-                price: 1.0,
-                quantity: 10.0,
+                price: dec!(1.0),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
             Level {
                 exchange: bitstamp.clone(),
-                price: 0.9,
-                quantity: 10.0,
+                price: dec!(0.9),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
         ];
         merger.asks = vec![
             Level {
                 exchange: binance.clone(),
-                price: 2.0,
-                quantity: 10.0,
+                price: dec!(2.0),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
             Level {
                 exchange: bitstamp.clone(),
-                price: 3.0,
-                quantity: 10.0,
+                price: dec!(3.0),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
         ];
 
         test_sender.send(Summary {
             bids: Vec::new(),
             asks: Vec::new(),
-        }).unwrap();
+            ..Default::default()
+        }.into()).unwrap();
 
         drop(test_sender);
         let (shutdown_sender, shutdown_receiver) = tokio::sync::broadcast::channel(1);
@@ -573,25 +767,29 @@ mod test {
         merger.bids = vec![
             Level {
                 exchange: binance.clone(),
-                price: 1.0,
-                quantity: 10.0,
+                price: dec!(1.0),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
             Level {
                 exchange: bitstamp.clone(),
-                price: 0.9,
-                quantity: 10.0,
+                price: dec!(0.9),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
         ];
         merger.asks = vec![
             Level {
                 exchange: binance.clone(),
-                price: 2.0,
-                quantity: 10.0,
+                price: dec!(2.0),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
             Level {
                 exchange: bitstamp.clone(),
-                price: 3.0,
-                quantity: 10.0,
+                price: dec!(3.0),
+                quantity: dec!(10.0),
+                ..Default::default()
             },
         ];
 
@@ -599,18 +797,21 @@ mod test {
             bids: vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 0.8,
-                    quantity: 10.0,
+                    price: dec!(0.8),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
             asks: vec![
                 Level {
                     exchange: binance.clone(),
-                    price: 4.0,
-                    quantity: 10.0,
+                    price: dec!(4.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
-        }).unwrap();
+            ..Default::default()
+        }.into()).unwrap();
 
         drop(test_sender);
         let (shutdown_sender, shutdown_receiver) = tokio::sync::broadcast::channel(1);
@@ -623,13 +824,15 @@ mod test {
             vec![
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 0.9,
-                    quantity: 10.0,
+                    price: dec!(0.9),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 0.8,
-                    quantity: 10.0,
+                    price: dec!(0.8),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
         );
@@ -639,15 +842,223 @@ mod test {
             vec![
                 Level {
                     exchange: bitstamp.clone(),
-                    price: 3.0,
-                    quantity: 10.0,
+                    price: dec!(3.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
                 Level {
                     exchange: binance.clone(),
-                    price: 4.0,
-                    quantity: 10.0,
+                    price: dec!(4.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
                 },
             ],
         );
     }
+
+    #[tokio::test]
+    async fn should_remove_levels_from_disconnected_exchange() {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        let logger = Logger::root(
+            slog_term::FullFormat::new(plain)
+                .build().fuse(), o!(),
+        );
+        let (summary_sender, mut grpc_receiver) = mpsc::unbounded_channel();
+        let (test_sender, summary_receiver) = mpsc::unbounded_channel();
+        let mut merger = OrderbookMerger::new(
+            logger, summary_receiver, summary_sender, 2,
+        );
+
+        let binance = "binance".to_string();
+        let bitstamp = "bitstamp".to_string();
+        merger.bids = vec![
+            Level {
+                exchange: binance.clone(),
+                price: dec!(1.0),
+                quantity: dec!(10.0),
+                ..Default::default()
+            },
+            Level {
+                exchange: bitstamp.clone(),
+                price: dec!(0.9),
+                quantity: dec!(10.0),
+                ..Default::default()
+            },
+        ];
+        merger.asks = vec![
+            Level {
+                exchange: bitstamp.clone(),
+                price: dec!(2.0),
+                quantity: dec!(10.0),
+                ..Default::default()
+            },
+            Level {
+                exchange: binance.clone(),
+                price: dec!(3.0),
+                quantity: dec!(10.0),
+                ..Default::default()
+            },
+        ];
+
+        test_sender.send(BookUpdate::Disconnected(bitstamp.clone())).unwrap();
+
+        drop(test_sender);
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::broadcast::channel(1);
+        merger.start(shutdown_sender).await.unwrap();
+        drop(shutdown_receiver);
+
+        assert_eq!(
+            merger.bids,
+            vec![
+                Level {
+                    exchange: binance.clone(),
+                    price: dec!(1.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
+                },
+            ],
+        );
+        assert_eq!(
+            merger.asks,
+            vec![
+                Level {
+                    exchange: binance.clone(),
+                    price: dec!(3.0),
+                    quantity: dec!(10.0),
+                    ..Default::default()
+                },
+            ],
+        );
+        let summary = grpc_receiver.recv().await.unwrap();
+        assert_eq!(1, summary.bids.len());
+        assert_eq!(1, summary.asks.len());
+    }
+
+    #[tokio::test]
+    async fn should_remove_levels_from_stale_exchange() {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        let logger = Logger::root(
+            slog_term::FullFormat::new(plain)
+                .build().fuse(), o!(),
+        );
+        let (summary_sender, _summary_receiver) = mpsc::unbounded_channel();
+        let (_test_sender, summary_receiver) = mpsc::unbounded_channel();
+        let mut merger = OrderbookMerger::new(
+            logger, summary_receiver, summary_sender, 2,
+        ).with_stale_timeout(Duration::from_secs(5));
+
+        let binance = "binance".to_string();
+        let bitstamp = "bitstamp".to_string();
+        merger.bids = vec![
+            Level {
+                exchange: bitstamp.clone(),
+                price: dec!(1.0),
+                quantity: dec!(10.0),
+                ..Default::default()
+            },
+            Level {
+                exchange: binance.clone(),
+                price: dec!(0.9),
+                quantity: dec!(10.0),
+                ..Default::default()
+            },
+        ];
+        merger.asks = vec![
+            Level {
+                exchange: bitstamp.clone(),
+                price: dec!(2.0),
+                quantity: dec!(10.0),
+                ..Default::default()
+            },
+        ];
+        let now = Instant::now();
+        merger.last_updates.insert(binance.clone(), now - Duration::from_secs(1));
+        merger.last_updates.insert(bitstamp.clone(), now - Duration::from_secs(6));
+
+        let removed = merger.remove_stale_exchanges(now);
+        let removed_again = merger.remove_stale_exchanges(now);
+
+        assert!(removed);
+        assert!(!removed_again);
+        assert_eq!(
+            merger.bids,
+            vec![
+                Level {
+                    exchange: binance.clone(),
+                    price: dec!(0.9),
+                    quantity: dec!(10.0),
+                    ..Default::default()
+                },
+            ],
+        );
+        assert!(merger.asks.is_empty());
+        assert!(merger.last_updates.contains_key(&binance));
+        assert!(!merger.last_updates.contains_key(&bitstamp));
+    }
+
+    #[tokio::test]
+    async fn should_merge_with_fee_adjusted_prices() {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        let logger = Logger::root(
+            slog_term::FullFormat::new(plain)
+                .build().fuse(), o!(),
+        );
+        let mut taker_fees = TakerFees::new();
+        taker_fees.insert("binance=0.001").unwrap();
+        taker_fees.insert("Bitstamp = 0.004").unwrap();
+        let (summary_sender, _summary_receiver) = mpsc::unbounded_channel();
+        let (test_sender, summary_receiver) = mpsc::unbounded_channel();
+        let mut merger = OrderbookMerger::new(
+            logger, summary_receiver, summary_sender, 2,
+        ).with_taker_fees(taker_fees);
+
+        let level = |exchange: &str, price| Level {
+            exchange: exchange.to_string(),
+            price,
+            quantity: dec!(10.0),
+            ..Default::default()
+        };
+        test_sender.send(Summary {
+            bids: vec![level("binance", dec!(100.0))],
+            asks: vec![level("binance", dec!(101.0))],
+            ..Default::default()
+        }.into()).unwrap();
+        test_sender.send(Summary {
+            // Better raw prices, but worse after the higher fee
+            bids: vec![level("bitstamp", dec!(100.2))],
+            asks: vec![level("bitstamp", dec!(100.8))],
+            ..Default::default()
+        }.into()).unwrap();
+        drop(test_sender);
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::broadcast::channel(1);
+        merger.start(shutdown_sender).await.unwrap();
+        drop(shutdown_receiver);
+
+        let summary = merger.summary();
+        assert_eq!(
+            vec![("binance", dec!(100.0), dec!(0.001)), ("bitstamp", dec!(100.2), dec!(0.004))],
+            summary.bids.iter().map(|x| (x.exchange.as_str(), x.price, x.fee)).collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            vec![("binance", dec!(101.0)), ("bitstamp", dec!(100.8))],
+            summary.asks.iter().map(|x| (x.exchange.as_str(), x.price)).collect::<Vec<_>>(),
+        );
+        // 101.0 × 1.001 − 100.0 × 0.999
+        assert_eq!(Some(dec!(1.201)), summary.spread());
+    }
+
+    #[test]
+    fn should_reject_invalid_taker_fees() {
+        let mut taker_fees = TakerFees::new();
+
+        let resp = vec![
+            taker_fees.insert("binance"),
+            taker_fees.insert("binance=abc"),
+            taker_fees.insert("binance=-0.001"),
+            taker_fees.insert("binance=1"),
+        ];
+
+        assert!(resp.iter().all(|x| x.is_err()));
+        assert!(taker_fees.is_empty());
+    }
 }
@@ -0,0 +1,475 @@
+use crate::types::{
+    Level,
+    MBooksError,
+    Summary,
+};
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+use rand::Rng;
+use rust_decimal::Decimal;
+use serde_json::{
+    json,
+    Value,
+};
+use slog::{
+    debug,
+    error,
+    info,
+    Logger,
+    o,
+};
+use std::{
+    net::SocketAddr,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use tokio::{
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    sync::broadcast,
+    task::JoinHandle,
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{
+            Request,
+            Response,
+        },
+        protocol::Message,
+    },
+};
+
+/// Exchange whose WebSocket feed the `MockExchange` imitates, in the `snapshot` book mode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MockProtocol {
+    /// Combined stream with the partial book depth streams requested in the URL.
+    Binance,
+    /// `order_book` channels subscribed with `bts:subscribe`.
+    Bitstamp,
+}
+
+impl TryFrom<&str> for MockProtocol {
+    type Error = MBooksError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "binance" => Ok(MockProtocol::Binance),
+            "bitstamp" => Ok(MockProtocol::Bitstamp),
+            _ => Err(MBooksError::InvalidSource(value.to_string())),
+        }
+    }
+}
+
+/// Books sent by the `MockExchange`, the same ones are sent for every subscribed symbol and every
+/// connection starts them again.
+#[derive(Clone, Debug)]
+pub enum MockBooks {
+    /// Sends each book once in order, then keeps the connection open without sending anything else.
+    Scripted(Vec<Summary>),
+    /// Moves the mid price randomly by up to one `tick` on every book, with `levels` on each side
+    /// spaced by a `tick` and random quantities.
+    RandomWalk {
+        mid: Decimal,
+        tick: Decimal,
+        levels: usize,
+    },
+}
+
+impl MockBooks {
+    fn next_book(&mut self) -> Option<Summary> {
+        match self {
+            MockBooks::Scripted(books) => {
+                if books.is_empty() {
+                    None
+                } else {
+                    Some(books.remove(0))
+                }
+            }
+            MockBooks::RandomWalk { mid, tick, levels } => {
+                let mut rng = rand::thread_rng();
+                let step = *tick * Decimal::from(rng.gen_range(-1..=1));
+                if *mid + step > *tick * Decimal::from(*levels) {
+                    *mid += step;
+                }
+
+                let mut level = |side: Decimal, distance: usize| Level {
+                    exchange: "mock".to_string(),
+                    price: *mid + side * *tick * Decimal::from(distance),
+                    quantity: Decimal::new(rng.gen_range(1..=10_000), 2),
+                    ..Default::default()
+                };
+                let bids = (1..=*levels).map(|distance| level(Decimal::NEGATIVE_ONE, distance)).collect();
+                let asks = (1..=*levels).map(|distance| level(Decimal::ONE, distance)).collect();
+                Some(Summary {
+                    bids,
+                    asks,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+/// Levels in the `[price, quantity]` format used by both exchanges.
+fn levels_json(levels: &[Level]) -> Value {
+    levels.iter()
+        .map(|level| json!([level.price.to_string(), level.quantity.to_string()]))
+        .collect()
+}
+
+/// MockExchange serves a Binance or Bitstamp compatible WebSocket feed on a local address, so the
+/// server can run end to end without connecting to the real exchanges.
+/// The sources reach it by replacing their WebSocket endpoint with `websocket_url`.
+pub struct MockExchange {
+    listener: TcpListener,
+    protocol: MockProtocol,
+    books: MockBooks,
+    interval: Duration,
+}
+
+impl MockExchange {
+    /// Listens on the address, e.g. `127.0.0.1:0` to use any available port.
+    pub async fn bind(
+        address: &str, protocol: MockProtocol, books: MockBooks,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            listener: TcpListener::bind(address).await?,
+            protocol,
+            books,
+            interval: Duration::from_millis(100),
+        })
+    }
+
+    /// Time between the books sent, 100ms by default.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// URL to be used as the WebSocket endpoint of the source.
+    pub fn websocket_url(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(format!("ws://{}", self.local_addr()?))
+    }
+
+    /// Accepts connections until a message arrives at the `shutdown_receiver`, each one served in
+    /// its own task, which is aborted on the shutdown.
+    pub async fn run(
+        self,
+        log: Logger,
+        shutdown_receiver: broadcast::Receiver<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut shutdown_receiver = shutdown_receiver;
+        info!(log, "mock exchange listening"; "url" => self.websocket_url()?, "protocol" => format!("{:?}", self.protocol));
+        let mut connections = Vec::new();
+        loop {
+            tokio::select! {
+                connection = self.listener.accept() => {
+                    let (stream, address) = connection?;
+                    let log = log.new(o!("client" => address.to_string()));
+                    info!(log, "mock exchange got a connection");
+                    let protocol = self.protocol;
+                    let books = self.books.clone();
+                    let interval = self.interval;
+                    connections.retain(|connection: &JoinHandle<()>| !connection.is_finished());
+                    connections.push(tokio::spawn(async move {
+                        if let Err(err) = serve_connection(&log, stream, protocol, books, interval).await {
+                            error!(log, "mock exchange connection failed"; "error" => err.to_string());
+                        }
+                    }));
+                }
+                _ = shutdown_receiver.recv() => {
+                    info!(log, "application is shutting down, closing mock exchange");
+                    for connection in connections {
+                        connection.abort();
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Names of the streams requested in the Binance combined stream URL, e.g. `/stream?streams=a/b`.
+fn requested_streams(uri: &str) -> Vec<String> {
+    url::Url::parse("ws://localhost").and_then(|base| base.join(uri)).ok()
+        .and_then(|url| url.query_pairs().find(|(key, _)| key == "streams").map(|(_, streams)| streams.to_string()))
+        .map(|streams| streams.split('/').filter(|stream| !stream.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Message with the book for a Binance stream or a Bitstamp channel.
+fn book_message(protocol: MockProtocol, target: &str, sequence: u64, book: &Summary) -> String {
+    match protocol {
+        MockProtocol::Binance => json!({
+            "stream": target,
+            "data": {
+                "lastUpdateId": sequence,
+                "bids": levels_json(&book.bids),
+                "asks": levels_json(&book.asks),
+            },
+        }),
+        MockProtocol::Bitstamp => {
+            let microtimestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map_or(0, |timestamp| timestamp.as_micros());
+            json!({
+                "event": "data",
+                "channel": target,
+                "data": {
+                    "timestamp": (microtimestamp / 1_000_000).to_string(),
+                    "microtimestamp": microtimestamp.to_string(),
+                    "bids": levels_json(&book.bids),
+                    "asks": levels_json(&book.asks),
+                },
+            })
+        }
+    }.to_string()
+}
+
+/// Channel of a Bitstamp `bts:subscribe` request.
+fn subscribed_channel(message: &str) -> Option<String> {
+    let request: Value = serde_json::from_str(message).ok()?;
+    if request["event"] != "bts:subscribe" {
+        return None;
+    }
+    request["data"]["channel"].as_str().map(str::to_string)
+}
+
+/// Sends the books to a single connection, Binance streams are known from the URL while Bitstamp
+/// channels are only known once subscribed.
+async fn serve_connection(
+    log: &Logger,
+    stream: TcpStream,
+    protocol: MockProtocol,
+    books: MockBooks,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut uri = String::new();
+    // The error type is the `ErrorResponse` required by tungstenite's `Callback`, it is never returned
+    #[allow(clippy::result_large_err)]
+    let keep_uri = |request: &Request, response: Response| {
+        uri = request.uri().to_string();
+        Ok(response)
+    };
+    let ws_stream = accept_hdr_async(stream, keep_uri).await?;
+    let (mut write, mut read) = ws_stream.split();
+
+    let mut targets = match protocol {
+        MockProtocol::Binance => requested_streams(&uri),
+        MockProtocol::Bitstamp => Vec::new(),
+    };
+    let mut books = books;
+    let mut tick = tokio::time::interval(interval);
+    let mut sequence: u64 = 0;
+    loop {
+        tokio::select! {
+            message = read.next() => {
+                match message {
+                    Some(Ok(Message::Text(message))) => {
+                        if let Some(channel) = subscribed_channel(&message) {
+                            debug!(log, "mock exchange subscription"; "channel" => &channel);
+                            write.send(Message::Text(json!({
+                                "event": "bts:subscription_succeeded",
+                                "channel": &channel,
+                                "data": {},
+                            }).to_string())).await?;
+                            targets.push(channel);
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(Box::new(err)),
+                    None => {
+                        info!(log, "mock exchange connection closed");
+                        return Ok(());
+                    }
+                }
+            }
+            _ = tick.tick() => {
+                // The books are kept until there is someone to send them to
+                if targets.is_empty() {
+                    continue;
+                }
+                if let Some(book) = books.next_book() {
+                    sequence += 1;
+                    for target in &targets {
+                        write.send(Message::Text(book_message(protocol, target, sequence, &book))).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        client::{
+            run_client_with,
+            SummaryRequest,
+        },
+        merger::TakerFees,
+        mock::{
+            requested_streams,
+            MockBooks,
+            MockExchange,
+            MockProtocol,
+        },
+        server::{
+            run_server,
+            ServerConfig,
+            SlowClientPolicy,
+        },
+        source::{
+            BookMode,
+            Endpoints,
+            SourceConfig,
+            SourceRegistry,
+        },
+        types::{
+            Asset,
+            Level,
+            Summary,
+            Symbol,
+        },
+    };
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use slog::{
+        Logger,
+        o,
+    };
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    fn book(bid: (Decimal, Decimal), ask: (Decimal, Decimal)) -> Summary {
+        let level = |(price, quantity)| Level {
+            exchange: "mock".to_string(),
+            price,
+            quantity,
+            ..Default::default()
+        };
+        Summary {
+            bids: vec![level(bid)],
+            asks: vec![level(ask)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_get_requested_streams() {
+        // Given
+        let uri = "/stream?streams=ethbtc@depth10@100ms/btcusdt@depth10@100ms";
+
+        // When
+        let resp = requested_streams(uri);
+
+        // Then
+        assert_eq!(vec!["ethbtc@depth10@100ms", "btcusdt@depth10@100ms"], resp);
+    }
+
+    #[test]
+    fn should_walk_the_book() {
+        // Given
+        let mut books = MockBooks::RandomWalk { mid: dec!(0.0675), tick: dec!(0.00001), levels: 5 };
+
+        // When
+        let resp: Vec<Summary> = (0..10).filter_map(|_| books.next_book()).collect();
+
+        // Then
+        assert_eq!(10, resp.len());
+        for summary in resp {
+            assert_eq!(5, summary.bids.len());
+            assert_eq!(5, summary.asks.len());
+            assert_eq!(Some(dec!(0.00002)), summary.spread());
+            assert!(summary.bids.windows(2).all(|levels| levels[0].price > levels[1].price));
+        }
+    }
+
+    #[tokio::test]
+    async fn should_merge_mock_exchanges_end_to_end() {
+        // Given
+        let log = Logger::root(slog::Discard, o!());
+        let (shutdown_sender, _) = broadcast::channel(10);
+        let binance = MockExchange::bind(
+            "127.0.0.1:0", MockProtocol::Binance,
+            MockBooks::Scripted(vec![book((dec!(0.0675), dec!(1)), (dec!(0.0677), dec!(1)))]),
+        ).await.unwrap().with_interval(Duration::from_millis(10));
+        let bitstamp = MockExchange::bind(
+            "127.0.0.1:0", MockProtocol::Bitstamp,
+            MockBooks::Scripted(vec![book((dec!(0.0676), dec!(2)), (dec!(0.0678), dec!(2)))]),
+        ).await.unwrap().with_interval(Duration::from_millis(10));
+        let mut endpoints = Endpoints::new();
+        endpoints.insert_websocket(&format!("binance={}", binance.websocket_url().unwrap())).unwrap();
+        endpoints.insert_websocket(&format!("bitstamp={}", bitstamp.websocket_url().unwrap())).unwrap();
+        let symbols = vec![Symbol { base: Asset::ETH, quote: Asset::BTC }];
+        let sources = SourceRegistry::default().build(
+            &["binance".to_string(), "bitstamp".to_string()],
+            &SourceConfig { symbols: symbols.clone(), depth: 10, book_mode: BookMode::Snapshot, endpoints },
+        ).unwrap();
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = ServerConfig {
+            address: address.to_string(),
+            symbols,
+            depth: 10,
+            stale_timeout: None,
+            taker_fees: TakerFees::new(),
+            slow_client_policy: SlowClientPolicy::Conflate,
+            recorder: None,
+            summary_recorder: None,
+        };
+
+        // When
+        let mut merged = None;
+        let client = async {
+            let mut shutdown_receiver = shutdown_sender.subscribe();
+            for _ in 0..50 {
+                let resp = run_client_with(
+                    log.clone(), &mut shutdown_receiver, format!("http://{}", address), SummaryRequest::default(),
+                    |summary| {
+                        if summary.bids.len() == 2 && summary.asks.len() == 2 && merged.is_none() {
+                            merged = Some(summary.clone());
+                            shutdown_sender.send("got the merged summary".to_string()).unwrap();
+                        }
+                    },
+                ).await;
+                if resp.is_ok() || merged.is_some() {
+                    break;
+                }
+                // The server may not be listening yet
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        let resp = tokio::time::timeout(Duration::from_secs(30), async {
+            tokio::join!(
+                binance.run(log.clone(), shutdown_sender.subscribe()),
+                bitstamp.run(log.clone(), shutdown_sender.subscribe()),
+                run_server(log.clone(), shutdown_sender.clone(), config, sources),
+                client,
+            )
+        }).await;
+
+        // Then
+        assert!(resp.is_ok(), "timed out waiting for the merged summary");
+        let merged = merged.unwrap();
+        let bids: Vec<(&str, &str)> = merged.bids.iter()
+            .map(|level| (level.exchange.as_str(), level.exact_price.as_str()))
+            .collect();
+        let asks: Vec<(&str, &str)> = merged.asks.iter()
+            .map(|level| (level.exchange.as_str(), level.exact_price.as_str()))
+            .collect();
+        assert_eq!(vec![("bitstamp", "0.0676"), ("binance", "0.0675")], bids);
+        assert_eq!(vec![("binance", "0.0677"), ("bitstamp", "0.0678")], asks);
+        assert_eq!("0.0001", merged.exact_spread);
+    }
+}
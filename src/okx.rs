@@ -0,0 +1,494 @@
+use crate::{
+    book::LocalBook,
+    source::{
+        ConnectionEnd,
+        Source,
+        SourceConfig,
+        SummarySenders,
+    },
+    types::{
+        MBooksError,
+        Symbol,
+    },
+};
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+use opentelemetry::{
+    Context,
+    global,
+    Key,
+    trace::{
+        FutureExt,
+        TraceContextExt,
+        Tracer,
+    },
+};
+use serde_derive::Deserialize;
+use serde_json::json;
+use slog::{
+    debug,
+    error,
+    Logger,
+    info,
+    o,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use tokio::sync::broadcast;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::Message,
+};
+
+const WEBSOCKET_URL: &str = "wss://ws.okx.com:8443/ws/v5/public";
+
+#[derive(Debug, Deserialize)]
+struct Arg {
+    channel: String,
+    #[serde(rename(deserialize = "instId"))]
+    inst_id: String,
+}
+
+/// Levels in the format `[price, size, deprecated, orders]`, the size is absolute and zero removes
+/// the level.
+#[derive(Debug, Deserialize)]
+struct BookData {
+    asks: Vec<Vec<String>>,
+    bids: Vec<Vec<String>>,
+    /// Time in milliseconds.
+    ts: String,
+    #[serde(rename(deserialize = "seqId"))]
+    seq_id: i64,
+    /// Sequence ID of the previous message, -1 on a snapshot.
+    #[serde(rename(deserialize = "prevSeqId"))]
+    prev_seq_id: i64,
+}
+
+impl BookData {
+    fn exchange_timestamp(&self) -> Option<SystemTime> {
+        self.ts.parse::<u64>().ok().map(|ts| UNIX_EPOCH + Duration::from_millis(ts))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum WebSocketEvent {
+    /// Data pushed for a subscribed channel.
+    Push {
+        arg: Arg,
+        action: String,
+        data: Vec<BookData>,
+    },
+    /// Response to the `subscribe` and `unsubscribe` requests, or an error.
+    Event {
+        event: String,
+        msg: Option<String>,
+    },
+}
+
+/// Result of applying a `BookData` update to the `SequencedBook`.
+#[derive(Debug, PartialEq)]
+enum Sequence {
+    Applied,
+    /// Some update was lost, the book needs a new snapshot.
+    Gap,
+}
+
+/// SequencedBook keeps the local book from the `books` channel.
+/// Every update must point to the sequence ID of the previous one, otherwise it is a gap.
+struct SequencedBook {
+    book: LocalBook,
+    seq_id: i64,
+}
+
+impl SequencedBook {
+    fn new(snapshot: &BookData) -> Result<Self, MBooksError> {
+        let mut book = LocalBook::new("okx");
+        book.update_bids(&snapshot.bids)?;
+        book.update_asks(&snapshot.asks)?;
+
+        Ok(Self {
+            book,
+            seq_id: snapshot.seq_id,
+        })
+    }
+
+    fn apply(&mut self, update: &BookData) -> Result<Sequence, MBooksError> {
+        if update.prev_seq_id != self.seq_id {
+            return Ok(Sequence::Gap);
+        }
+
+        self.book.update_bids(&update.bids)?;
+        self.book.update_asks(&update.asks)?;
+        self.seq_id = update.seq_id;
+
+        Ok(Sequence::Applied)
+    }
+}
+
+fn symbol_to_string(symbol: &Symbol) -> String {
+    format!("{}-{}", symbol.base.to_string(), symbol.quote.to_string()).to_uppercase()
+}
+
+/// Request for the `books` channel of the instruments.
+fn books_request(op: &str, inst_ids: &[&String]) -> String {
+    json!({
+        "op": op,
+        "args": inst_ids.iter().map(|inst_id| json!({"channel": "books", "instId": inst_id})).collect::<Vec<_>>(),
+    }).to_string()
+}
+
+/// OKX `Source` implementation, it only supports the `books` channel which is kept in a
+/// `LocalBook` for each instrument.
+pub struct Okx {
+    symbols: Vec<Symbol>,
+    depth: usize,
+    websocket_url: String,
+}
+
+impl Okx {
+    pub fn new(config: SourceConfig) -> Self {
+        Self {
+            websocket_url: config.endpoints.websocket("okx").unwrap_or(WEBSOCKET_URL).to_string(),
+            symbols: config.symbols,
+            depth: config.depth,
+        }
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for Okx {
+    fn name(&self) -> &'static str {
+        "okx"
+    }
+
+    fn symbol_to_string(&self, symbol: &Symbol) -> String {
+        symbol_to_string(symbol)
+    }
+
+    async fn connect(
+        &self,
+        log: &Logger,
+        shutdown_receiver: &mut broadcast::Receiver<String>,
+        summary_txs: &SummarySenders,
+    ) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+        run_okx(log, shutdown_receiver, summary_txs, &self.symbols, self.depth, &self.websocket_url).await
+    }
+}
+
+/// Connects to OKX and streams the books until the connection ends.
+/// On a gap in the sequence IDs the instrument is subscribed again, so OKX sends a new snapshot.
+async fn run_okx(
+    log: &Logger,
+    shutdown_receiver: &mut broadcast::Receiver<String>,
+    summary_txs: &SummarySenders,
+    symbols: &[Symbol], depth: usize, websocket_url: &str,
+) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+    let tracer = global::tracer("run_okx");
+    let span = tracer.start("running okx");
+    let cx = Context::current_with_span(span);
+    info!(log, "running okx"; "symbols" => format!("{:?}", symbols));
+
+    let url = url::Url::parse(websocket_url)?;
+    info!(log, "okx url"; "url" => format!("{:?}", url));
+
+    let (ws_stream, _) = connect_async(url)
+        .with_context(cx.clone())
+        .await?;
+    info!(log, "WebSocket handshake has been successfully completed");
+
+    let instruments: HashMap<String, Symbol> = symbols.iter()
+        .map(|symbol| (symbol_to_string(symbol), symbol.clone()))
+        .collect();
+    let (mut write, mut read) = ws_stream.split();
+    write.send(Message::Text(
+        books_request("subscribe", &instruments.keys().collect::<Vec<_>>())
+    )).with_context(cx.clone()).await?;
+
+    // The books are only available after the snapshot of each instrument
+    let mut books: HashMap<String, SequencedBook> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            message = read.next() => {
+                if let Some(message) = message {
+                    debug!(log, "websocket got message");
+                    match message {
+                        Ok(message_data) => {
+                            let received_timestamp = SystemTime::now();
+                            let message_data = message_data.into_data();
+                            summary_txs.record("okx", received_timestamp, &message_data);
+                            let okx_parse: serde_json::Result<WebSocketEvent> = serde_json::from_slice(
+                                &message_data,
+                            );
+
+                            let (arg, action, data) = match okx_parse {
+                                Ok(WebSocketEvent::Push { arg, action, data }) => (arg, action, data),
+                                Ok(WebSocketEvent::Event { event, msg }) => {
+                                    if event == "error" {
+                                        let msg = msg.unwrap_or_default();
+                                        error!(log, "okx sent an error"; "message" => &msg);
+                                        return Err(Box::new(MBooksError::ExchangeError(msg)));
+                                    }
+                                    debug!(log, "okx event"; "event" => event);
+                                    continue;
+                                }
+                                Err(err) => {
+                                    error!(log, "error parsing WebSocket data"; "error" => format!("{}", err));
+                                    cx.span().add_event(
+                                        "error parsing WebSocket data",
+                                        vec![
+                                            Key::new("message").string(format!("{:?}", message_data)),
+                                            Key::new("error").string(format!("{}", err)),
+                                        ],
+                                    );
+                                    continue;
+                                }
+                            };
+
+                            let symbol = match instruments.get(&arg.inst_id) {
+                                Some(symbol) if arg.channel == "books" => symbol,
+                                _ => {
+                                    warn!(log, "data from an unknown channel"; "channel" => arg.channel, "inst_id" => arg.inst_id);
+                                    continue;
+                                }
+                            };
+                            let log = log.new(o!("inst_id" => arg.inst_id.clone()));
+
+                            for data in data {
+                                let sequence = if action == "snapshot" {
+                                    // A new snapshot always replaces the book
+                                    SequencedBook::new(&data).map(|book| {
+                                        books.insert(arg.inst_id.clone(), book);
+                                        Sequence::Applied
+                                    })
+                                } else {
+                                    match books.get_mut(&arg.inst_id) {
+                                        Some(book) => book.apply(&data),
+                                        None => {
+                                            debug!(log, "ignoring update before the snapshot");
+                                            continue;
+                                        }
+                                    }
+                                };
+
+                                match sequence {
+                                    Ok(Sequence::Applied) => {
+                                        let summary = books[&arg.inst_id].book.summary(depth).with_timestamps(
+                                            data.exchange_timestamp(), received_timestamp,
+                                        );
+                                        if let Err(err) = summary_txs.send(symbol, summary) {
+                                            error!(
+                                                log, "error sending information to the channel";
+                                                "error" => format!("{}", err)
+                                            );
+                                            cx.span().add_event(
+                                                "error sending information to the channel",
+                                                vec![
+                                                    Key::new("error").string(format!("{}", err)),
+                                                ],
+                                            );
+                                        }
+                                    }
+                                    Ok(Sequence::Gap) => {
+                                        warn!(
+                                            log, "gap in the books channel, subscribing again";
+                                            "seq_id" => books[&arg.inst_id].seq_id,
+                                            "prev_seq_id" => data.prev_seq_id
+                                        );
+                                        cx.span().add_event(
+                                            "gap in the books channel",
+                                            vec![
+                                                Key::new("inst_id").string(arg.inst_id.clone()),
+                                                Key::new("seq_id").i64(books[&arg.inst_id].seq_id),
+                                            ],
+                                        );
+                                        books.remove(&arg.inst_id);
+                                        for op in ["unsubscribe", "subscribe"] {
+                                            write.send(Message::Text(
+                                                books_request(op, &[&arg.inst_id])
+                                            )).with_context(cx.clone()).await?;
+                                        }
+                                        break;
+                                    }
+                                    Err(err) => {
+                                        error!(
+                                            log, "error converting WebSocket data to domain type";
+                                            "error" => format!("{}", err)
+                                        );
+                                        cx.span().add_event(
+                                            "error converting WebSocket data to domain type",
+                                            vec![
+                                                Key::new("error").string(format!("{:?}", err)),
+                                            ],
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            error!(log, "problem fetching message"; "error" => format!("{}", err));
+                            return Err(Box::new(err));
+                        }
+                    }
+                } else {
+                    info!(log, "no more messages run_okx");
+                    return Ok(ConnectionEnd::Closed);
+                }
+            }
+            _ = shutdown_receiver.recv() => {
+                info!(log, "application is shutting down, closing run_okx");
+                return Ok(ConnectionEnd::Shutdown);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        okx::{
+            books_request,
+            symbol_to_string,
+            BookData,
+            Sequence,
+            SequencedBook,
+            WebSocketEvent,
+        },
+        types::{
+            Asset,
+            Symbol,
+        },
+    };
+    use rust_decimal_macros::dec;
+    use std::time::{
+        Duration,
+        UNIX_EPOCH,
+    };
+
+    fn book_data(msg: &str) -> BookData {
+        match serde_json::from_str(msg).unwrap() {
+            WebSocketEvent::Push { mut data, .. } => data.remove(0),
+            _ => panic!("not a push"),
+        }
+    }
+
+    fn snapshot() -> SequencedBook {
+        let msg = r#"{"arg":{"channel":"books","instId":"ETH-BTC"},"action":"snapshot","data":[{"asks":[["0.06756","3.0","0","2"]],"bids":[["0.06754","12.5","0","4"],["0.06753","1.2","0","1"]],"ts":"1666200249249","checksum":-855196043,"prevSeqId":-1,"seqId":100}]}"#;
+        SequencedBook::new(&book_data(msg)).unwrap()
+    }
+
+    fn update(prev_seq_id: i64, seq_id: i64) -> BookData {
+        BookData {
+            asks: vec![vec!["0.06755".to_string(), "2.0".to_string(), "0".to_string(), "1".to_string()]],
+            bids: vec![vec!["0.06754".to_string(), "0".to_string(), "0".to_string(), "0".to_string()]],
+            ts: "1666200249349".to_string(),
+            seq_id,
+            prev_seq_id,
+        }
+    }
+
+    #[test]
+    fn should_parse_snapshot() {
+        // Given
+        let msg = r#"{"arg":{"channel":"books","instId":"ETH-BTC"},"action":"snapshot","data":[{"asks":[["0.06756","3.0","0","2"]],"bids":[["0.06754","12.5","0","4"]],"ts":"1666200249249","checksum":-855196043,"prevSeqId":-1,"seqId":100}]}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        if let WebSocketEvent::Push { arg, action, data } = resp {
+            assert_eq!("ETH-BTC", arg.inst_id);
+            assert_eq!("snapshot", action);
+            assert_eq!(-1, data[0].prev_seq_id);
+            assert_eq!(100, data[0].seq_id);
+            assert_eq!(Some(UNIX_EPOCH + Duration::from_millis(1666200249249)), data[0].exchange_timestamp());
+        } else {
+            panic!("not a push");
+        }
+    }
+
+    #[test]
+    fn should_parse_error_event() {
+        // Given
+        let msg = r#"{"event":"error","code":"60018","msg":"Wrong URL or channel:books,instId:ETH-XXX doesn't exist.","connId":"a4d3ae55"}"#;
+
+        // When
+        let resp: WebSocketEvent = serde_json::from_str(msg).unwrap();
+
+        // Then
+        if let WebSocketEvent::Event { event, msg } = resp {
+            assert_eq!("error", event);
+            assert!(msg.is_some());
+        } else {
+            panic!("not an event");
+        }
+    }
+
+    #[test]
+    fn should_apply_updates_in_sequence() {
+        // Given
+        let mut book = snapshot();
+
+        // When
+        let first = book.apply(&update(100, 101)).unwrap();
+        let second = book.apply(&update(101, 101)).unwrap();
+
+        // Then
+        assert_eq!(Sequence::Applied, first);
+        assert_eq!(Sequence::Applied, second);
+        assert_eq!(101, book.seq_id);
+        let summary = book.book.summary(10);
+        assert_eq!(1, summary.bids.len());
+        assert_eq!(dec!(0.06753), summary.bids[0].price);
+        assert_eq!(2, summary.asks.len());
+        assert_eq!(dec!(0.06755), summary.asks[0].price);
+    }
+
+    #[test]
+    fn should_detect_gap() {
+        // Given
+        let mut book = snapshot();
+        book.apply(&update(100, 102)).unwrap();
+
+        // When
+        let resp = book.apply(&update(103, 104)).unwrap();
+
+        // Then
+        assert_eq!(Sequence::Gap, resp);
+        assert_eq!(102, book.seq_id);
+    }
+
+    #[test]
+    fn should_create_books_request() {
+        // Given
+        let inst_id = "ETH-BTC".to_string();
+
+        // When
+        let resp = books_request("subscribe", &[&inst_id]);
+
+        // Then
+        assert_eq!(r#"{"args":[{"channel":"books","instId":"ETH-BTC"}],"op":"subscribe"}"#, resp);
+    }
+
+    #[test]
+    fn should_convert_symbol() {
+        // Given
+        let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+
+        // When
+        let resp = symbol_to_string(&symbol);
+
+        // Then
+        assert_eq!("ETH-BTC", resp)
+    }
+}
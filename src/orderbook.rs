@@ -1,23 +1,188 @@
+#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct Empty {
+pub struct Empty {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BookSummaryRequest {
+    /// Symbol in the format `eth/btc`, empty for the one served by the server.
+    #[prost(string, tag = "1")]
+    pub symbol: ::prost::alloc::string::String,
+    /// Levels on each side, 0 or more than the server depth uses the server depth.
+    #[prost(uint32, tag = "2")]
+    pub depth: u32,
+    /// Exchanges to keep in the summary, empty for all of them.
+    #[prost(string, repeated, tag = "3")]
+    pub exchanges: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// A single level per price, with the amount of all the exchanges quoting it, instead of one per
+    /// exchange. The depth is then the number of prices.
+    #[prost(bool, tag = "4")]
+    pub aggregated: bool,
+    /// Price step to group the levels in, e.g. `0.0001`, empty to keep the prices of the exchanges.
+    /// The prices are rounded outward, down for the bids and up for the asks, adding the amounts of
+    /// each exchange in the same bucket (of all of them when aggregated).
+    #[prost(string, tag = "5")]
+    pub bucket_size: ::prost::alloc::string::String,
 }
+/// The double fields are kept for compatibility, the `exact_` ones have the decimal values as sent
+/// by the exchanges, e.g. `0.06754400`.
+#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Summary {
-    #[prost(double, tag="1")]
+    #[prost(double, tag = "1")]
     pub spread: f64,
-    #[prost(message, repeated, tag="2")]
+    #[prost(message, repeated, tag = "2")]
     pub bids: ::prost::alloc::vec::Vec<Level>,
-    #[prost(message, repeated, tag="3")]
+    #[prost(message, repeated, tag = "3")]
     pub asks: ::prost::alloc::vec::Vec<Level>,
+    /// Empty when one of the sides is empty.
+    #[prost(string, tag = "4")]
+    pub exact_spread: ::prost::alloc::string::String,
+    /// Most recent timestamps among the levels.
+    #[prost(uint64, tag = "5")]
+    pub exchange_timestamp_us: u64,
+    #[prost(uint64, tag = "6")]
+    pub received_timestamp_us: u64,
 }
+#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Level {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub exchange: ::prost::alloc::string::String,
-    #[prost(double, tag="2")]
+    #[prost(double, tag = "2")]
     pub price: f64,
-    #[prost(double, tag="3")]
+    #[prost(double, tag = "3")]
     pub amount: f64,
+    #[prost(string, tag = "4")]
+    pub exact_price: ::prost::alloc::string::String,
+    #[prost(string, tag = "5")]
+    pub exact_amount: ::prost::alloc::string::String,
+    /// Microseconds since the epoch when the exchange generated the update, 0 when it does not send it.
+    #[prost(uint64, tag = "6")]
+    pub exchange_timestamp_us: u64,
+    /// Microseconds since the epoch when the server received the update from the exchange.
+    #[prost(uint64, tag = "7")]
+    pub received_timestamp_us: u64,
+    /// Only in the aggregated view, the level of each exchange at this price, while the exchange has
+    /// their names separated by comma and the timestamps are the most recent ones.
+    #[prost(message, repeated, tag = "8")]
+    pub breakdown: ::prost::alloc::vec::Vec<Level>,
+    /// Taker fee of the exchange when the server merges with the fee-adjusted prices, empty otherwise.
+    /// The bids are then sorted by price × (1 − fee) and the asks by price × (1 + fee), as is the spread,
+    /// while price and exact_price are kept as sent by the exchange.
+    #[prost(string, tag = "9")]
+    pub exact_fee: ::prost::alloc::string::String,
+}
+/// Changes to the book since the previous delta, a level is identified by its exchange and exact_price.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BookDelta {
+    /// Consecutive for each client, starting at 1, so a missing delta can be detected.
+    #[prost(uint64, tag = "1")]
+    pub sequence: u64,
+    /// The book must be cleared before applying this delta, it is set for the first one.
+    #[prost(bool, tag = "2")]
+    pub snapshot: bool,
+    #[prost(message, repeated, tag = "3")]
+    pub bids: ::prost::alloc::vec::Vec<LevelChange>,
+    #[prost(message, repeated, tag = "4")]
+    pub asks: ::prost::alloc::vec::Vec<LevelChange>,
+    /// Most recent timestamps among the levels of the whole book.
+    #[prost(uint64, tag = "5")]
+    pub exchange_timestamp_us: u64,
+    #[prost(uint64, tag = "6")]
+    pub received_timestamp_us: u64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LevelChange {
+    #[prost(enumeration = "level_change::Kind", tag = "1")]
+    pub kind: i32,
+    #[prost(message, optional, tag = "2")]
+    pub level: ::core::option::Option<Level>,
+}
+/// Nested message and enum types in `LevelChange`.
+pub mod level_change {
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum Kind {
+        Insert = 0,
+        /// Only sent when the amount changes, so the level timestamps are the ones of its last change.
+        Update = 1,
+        Delete = 2,
+    }
+    impl Kind {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Kind::Insert => "INSERT",
+                Kind::Update => "UPDATE",
+                Kind::Delete => "DELETE",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "INSERT" => Some(Self::Insert),
+                "UPDATE" => Some(Self::Update),
+                "DELETE" => Some(Self::Delete),
+                _ => None,
+            }
+        }
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ArbitrageRequest {
+    /// Symbol in the format `eth/btc`, empty for the one served by the server.
+    #[prost(string, tag = "1")]
+    pub symbol: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ArbitrageUpdate {
+    /// Sorted by profit, empty once the book is no longer crossed.
+    #[prost(message, repeated, tag = "1")]
+    pub opportunities: ::prost::alloc::vec::Vec<ArbitrageOpportunity>,
+    /// Timestamps of the summary the opportunities were found in.
+    #[prost(uint64, tag = "2")]
+    pub exchange_timestamp_us: u64,
+    #[prost(uint64, tag = "3")]
+    pub received_timestamp_us: u64,
+}
+/// Buying the asks of buy_exchange below the bids of sell_exchange, considering the taker fees when
+/// the server merges with the fee-adjusted prices. Each pair uses all the levels of its exchanges,
+/// so the quantity of different pairs may overlap.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ArbitrageOpportunity {
+    #[prost(string, tag = "1")]
+    pub buy_exchange: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub sell_exchange: ::prost::alloc::string::String,
+    /// Best ask of buy_exchange and best bid of sell_exchange, as sent by the exchanges.
+    #[prost(string, tag = "3")]
+    pub exact_buy_price: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub exact_sell_price: ::prost::alloc::string::String,
+    /// Quantity that can be bought and sold while crossed.
+    #[prost(string, tag = "5")]
+    pub exact_quantity: ::prost::alloc::string::String,
+    /// Profit of crossing the quantity, in the quote asset.
+    #[prost(string, tag = "6")]
+    pub exact_profit: ::prost::alloc::string::String,
 }
 /// Generated client implementations.
 pub mod orderbook_aggregator_client {
@@ -110,16 +275,106 @@ pub mod orderbook_aggregator_client {
             );
             self.inner.server_streaming(request.into_request(), path, codec).await
         }
+        /// Same as BookSummary, with the symbol, depth and exchanges chosen by the client.
+        pub async fn filtered_book_summary(
+            &mut self,
+            request: impl tonic::IntoRequest<super::BookSummaryRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::Summary>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/orderbook.OrderbookAggregator/FilteredBookSummary",
+            );
+            self.inner.server_streaming(request.into_request(), path, codec).await
+        }
+        /// Current merged book, with the symbol, depth and exchanges chosen as in FilteredBookSummary.
+        pub async fn get_book_snapshot(
+            &mut self,
+            request: impl tonic::IntoRequest<super::BookSummaryRequest>,
+        ) -> Result<tonic::Response<super::Summary>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/orderbook.OrderbookAggregator/GetBookSnapshot",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// Same view as FilteredBookSummary, streamed as the current book followed by the level changes.
+        pub async fn book_deltas(
+            &mut self,
+            request: impl tonic::IntoRequest<super::BookSummaryRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::BookDelta>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/orderbook.OrderbookAggregator/BookDeltas",
+            );
+            self.inner.server_streaming(request.into_request(), path, codec).await
+        }
+        /// Opportunities to buy on an exchange and sell on another while the merged book is crossed,
+        /// sent whenever they change.
+        pub async fn arbitrage(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ArbitrageRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::ArbitrageUpdate>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/orderbook.OrderbookAggregator/Arbitrage",
+            );
+            self.inner.server_streaming(request.into_request(), path, codec).await
+        }
     }
 }
 /// Generated server implementations.
 pub mod orderbook_aggregator_server {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
     use tonic::codegen::*;
-    ///Generated trait containing gRPC methods that should be implemented for use with OrderbookAggregatorServer.
+    /// Generated trait containing gRPC methods that should be implemented for use with OrderbookAggregatorServer.
     #[async_trait]
     pub trait OrderbookAggregator: Send + Sync + 'static {
-        ///Server streaming response type for the BookSummary method.
+        /// Server streaming response type for the BookSummary method.
         type BookSummaryStream: futures_core::Stream<
                 Item = Result<super::Summary, tonic::Status>,
             >
@@ -129,6 +384,45 @@ pub mod orderbook_aggregator_server {
             &self,
             request: tonic::Request<super::Empty>,
         ) -> Result<tonic::Response<Self::BookSummaryStream>, tonic::Status>;
+        /// Server streaming response type for the FilteredBookSummary method.
+        type FilteredBookSummaryStream: futures_core::Stream<
+                Item = Result<super::Summary, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// Same as BookSummary, with the symbol, depth and exchanges chosen by the client.
+        async fn filtered_book_summary(
+            &self,
+            request: tonic::Request<super::BookSummaryRequest>,
+        ) -> Result<tonic::Response<Self::FilteredBookSummaryStream>, tonic::Status>;
+        /// Current merged book, with the symbol, depth and exchanges chosen as in FilteredBookSummary.
+        async fn get_book_snapshot(
+            &self,
+            request: tonic::Request<super::BookSummaryRequest>,
+        ) -> Result<tonic::Response<super::Summary>, tonic::Status>;
+        /// Server streaming response type for the BookDeltas method.
+        type BookDeltasStream: futures_core::Stream<
+                Item = Result<super::BookDelta, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// Same view as FilteredBookSummary, streamed as the current book followed by the level changes.
+        async fn book_deltas(
+            &self,
+            request: tonic::Request<super::BookSummaryRequest>,
+        ) -> Result<tonic::Response<Self::BookDeltasStream>, tonic::Status>;
+        /// Server streaming response type for the Arbitrage method.
+        type ArbitrageStream: futures_core::Stream<
+                Item = Result<super::ArbitrageUpdate, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// Opportunities to buy on an exchange and sell on another while the merged book is crossed,
+        /// sent whenever they change.
+        async fn arbitrage(
+            &self,
+            request: tonic::Request<super::ArbitrageRequest>,
+        ) -> Result<tonic::Response<Self::ArbitrageStream>, tonic::Status>;
     }
     #[derive(Debug)]
     pub struct OrderbookAggregatorServer<T: OrderbookAggregator> {
@@ -230,6 +524,165 @@ pub mod orderbook_aggregator_server {
                     };
                     Box::pin(fut)
                 }
+                "/orderbook.OrderbookAggregator/FilteredBookSummary" => {
+                    #[allow(non_camel_case_types)]
+                    struct FilteredBookSummarySvc<T: OrderbookAggregator>(pub Arc<T>);
+                    impl<
+                        T: OrderbookAggregator,
+                    > tonic::server::ServerStreamingService<super::BookSummaryRequest>
+                    for FilteredBookSummarySvc<T> {
+                        type Response = super::Summary;
+                        type ResponseStream = T::FilteredBookSummaryStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::BookSummaryRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).filtered_book_summary(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = FilteredBookSummarySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/orderbook.OrderbookAggregator/GetBookSnapshot" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetBookSnapshotSvc<T: OrderbookAggregator>(pub Arc<T>);
+                    impl<
+                        T: OrderbookAggregator,
+                    > tonic::server::UnaryService<super::BookSummaryRequest>
+                    for GetBookSnapshotSvc<T> {
+                        type Response = super::Summary;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::BookSummaryRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).get_book_snapshot(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetBookSnapshotSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/orderbook.OrderbookAggregator/BookDeltas" => {
+                    #[allow(non_camel_case_types)]
+                    struct BookDeltasSvc<T: OrderbookAggregator>(pub Arc<T>);
+                    impl<
+                        T: OrderbookAggregator,
+                    > tonic::server::ServerStreamingService<super::BookSummaryRequest>
+                    for BookDeltasSvc<T> {
+                        type Response = super::BookDelta;
+                        type ResponseStream = T::BookDeltasStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::BookSummaryRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).book_deltas(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = BookDeltasSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/orderbook.OrderbookAggregator/Arbitrage" => {
+                    #[allow(non_camel_case_types)]
+                    struct ArbitrageSvc<T: OrderbookAggregator>(pub Arc<T>);
+                    impl<
+                        T: OrderbookAggregator,
+                    > tonic::server::ServerStreamingService<super::ArbitrageRequest>
+                    for ArbitrageSvc<T> {
+                        type Response = super::ArbitrageUpdate;
+                        type ResponseStream = T::ArbitrageStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ArbitrageRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).arbitrage(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ArbitrageSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(
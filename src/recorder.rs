@@ -0,0 +1,475 @@
+use crate::types::{
+    from_timestamp_us,
+    Level,
+    MBooksError,
+    Summary,
+    Symbol,
+    timestamp_us,
+};
+use flate2::{
+    Compression,
+    read::GzDecoder,
+    write::GzEncoder,
+};
+use serde::de::DeserializeOwned;
+use serde_derive::{
+    Deserialize,
+    Serialize,
+};
+use slog::{
+    error,
+    info,
+    Logger,
+    warn,
+};
+use std::{
+    fs::File,
+    io::{
+        BufRead,
+        BufReader,
+        BufWriter,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    thread::JoinHandle,
+    time::SystemTime,
+};
+use tokio::sync::mpsc::{
+    self,
+    error::TrySendError,
+    Receiver,
+};
+
+/// Records waiting for the writer thread before the new ones are dropped, so a slow disk does not
+/// make the memory grow without bound.
+const RECORD_CAPACITY: usize = 10_000;
+
+/// RawFrame is a WebSocket message exactly as it was received from an exchange.
+/// It is the format of the recorded feeds: one JSON object per line, e.g.
+/// `{"exchange":"binance","received_timestamp_us":1666200249249913,"frame":"{\"stream\":...}"}`,
+/// in gzip compressed files named `feed-{milliseconds since the epoch}-{index}.jsonl.gz`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RawFrame {
+    pub exchange: String,
+    /// Microseconds since the epoch when the frame was received.
+    pub received_timestamp_us: u64,
+    pub frame: String,
+}
+
+/// RecordedLevel is a `Level` of a `RecordedSummary`, with the prices and quantities exactly as
+/// sent by the exchanges and the timestamps in microseconds since the epoch, 0 when unknown.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecordedLevel {
+    pub exchange: String,
+    pub price: String,
+    pub quantity: String,
+    pub exchange_timestamp_us: u64,
+    pub received_timestamp_us: u64,
+    /// Taker fee of the exchange when merging with the fee-adjusted prices, missing otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<String>,
+}
+
+/// RecordedSummary is a summary of a symbol as it was published by its `OrderbookMerger`.
+/// It is the format of the recorded summaries: one JSON object per line, e.g.
+/// `{"symbol":"eth/btc","recorded_timestamp_us":1666200249250102,"bids":[{"exchange":"binance","price":"0.06754400",...}],"asks":[...]}`,
+/// in gzip compressed files named `summaries-{milliseconds since the epoch}-{index}.jsonl.gz`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecordedSummary {
+    pub symbol: String,
+    /// Microseconds since the epoch when the summary was published.
+    pub recorded_timestamp_us: u64,
+    pub bids: Vec<RecordedLevel>,
+    pub asks: Vec<RecordedLevel>,
+}
+
+impl RecordedSummary {
+    pub fn new(symbol: &Symbol, recorded_timestamp: SystemTime, summary: &Summary) -> Self {
+        let levels = |levels: &[Level]| levels.iter()
+            .map(|level| RecordedLevel {
+                exchange: level.exchange.clone(),
+                price: level.price.to_string(),
+                quantity: level.quantity.to_string(),
+                exchange_timestamp_us: timestamp_us(level.exchange_timestamp),
+                received_timestamp_us: timestamp_us(level.received_timestamp),
+                fee: (!level.fee.is_zero()).then(|| level.fee.to_string()),
+            })
+            .collect();
+        Self {
+            symbol: symbol.to_string(),
+            recorded_timestamp_us: timestamp_us(Some(recorded_timestamp)),
+            bids: levels(&summary.bids),
+            asks: levels(&summary.asks),
+        }
+    }
+
+    /// Parses the symbol and the summary back, as they were before being recorded.
+    pub fn summary(&self) -> Result<(Symbol, Summary), MBooksError> {
+        let levels = |levels: &[RecordedLevel]| levels.iter()
+            .map(|level| {
+                let mut parsed = Level::parse(&level.exchange, &[level.price.clone(), level.quantity.clone()])?;
+                parsed.exchange_timestamp = from_timestamp_us(level.exchange_timestamp_us);
+                parsed.received_timestamp = from_timestamp_us(level.received_timestamp_us);
+                if let Some(fee) = &level.fee {
+                    parsed.fee = fee.parse().map_err(MBooksError::ParseError)?;
+                }
+                Ok(parsed)
+            })
+            .collect::<Result<Vec<_>, MBooksError>>();
+        Ok((
+            Symbol::try_from(self.symbol.clone())?,
+            Summary::from_levels(levels(&self.bids)?, levels(&self.asks)?),
+        ))
+    }
+}
+
+/// RotatingWriter writes JSON lines to gzip compressed files in a directory, starting a new file
+/// once the current one has `max_bytes` of uncompressed lines.
+/// The files are named `{prefix}-{milliseconds since the epoch}-{index}.jsonl.gz`, so sorting them
+/// by name keeps the order they were written.
+pub(crate) struct RotatingWriter {
+    directory: PathBuf,
+    prefix: &'static str,
+    max_bytes: u64,
+    file: Option<GzEncoder<BufWriter<File>>>,
+    written: u64,
+    index: u64,
+}
+
+impl RotatingWriter {
+    pub(crate) fn new(directory: &Path, prefix: &'static str, max_bytes: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(directory)?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            prefix,
+            max_bytes,
+            file: None,
+            written: 0,
+            index: 0,
+        })
+    }
+
+    pub(crate) fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.written >= self.max_bytes {
+            self.finish()?;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let path = self.directory.join(format!(
+                    "{}-{:013}-{:06}.jsonl.gz",
+                    self.prefix, timestamp_us(Some(SystemTime::now())) / 1000, self.index,
+                ));
+                self.index += 1;
+                self.written = 0;
+                self.file.insert(GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default()))
+            }
+        };
+        file.write_all(line)?;
+        file.write_all(b"\n")?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    /// Completes the current file, the next line starts a new one.
+    pub(crate) fn finish(&mut self) -> std::io::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.finish()?.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the lines of the files written by a `RotatingWriter` with the `prefix`, in the order they
+/// were written.
+pub(crate) fn read_lines<T: DeserializeOwned>(
+    directory: &Path, prefix: &str,
+) -> std::io::Result<impl Iterator<Item = std::io::Result<T>>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let recorded = matches!(
+            path.file_name().and_then(|name| name.to_str()),
+            Some(name) if name.starts_with(&format!("{}-", prefix)) && name.ends_with(".jsonl.gz")
+        );
+        if recorded {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    Ok(files.into_iter()
+        .flat_map(|path| -> Box<dyn Iterator<Item = std::io::Result<String>>> {
+            match File::open(path) {
+                Ok(file) => Box::new(BufReader::new(GzDecoder::new(file)).lines()),
+                Err(err) => Box::new(std::iter::once(Err(err))),
+            }
+        })
+        .map(|line| line.and_then(|line| serde_json::from_str(&line).map_err(std::io::Error::from))))
+}
+
+/// Reads the frames recorded by the `FeedRecorder` in the `directory`, in the order they were received.
+pub fn read_frames(directory: &Path) -> std::io::Result<impl Iterator<Item = std::io::Result<RawFrame>>> {
+    read_lines(directory, "feed")
+}
+
+/// Reads the summaries recorded by the `SummaryRecorder` in the `directory`, in the order they were published.
+pub fn read_summaries(directory: &Path) -> std::io::Result<impl Iterator<Item = std::io::Result<RecordedSummary>>> {
+    read_lines(directory, "summaries")
+}
+
+/// RecordSender sends the records to the writer thread without waiting for it, counting the ones
+/// dropped because it is behind.
+#[derive(Debug)]
+struct RecordSender<T> {
+    sender: mpsc::Sender<T>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<T> Clone for RecordSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+impl<T> RecordSender<T> {
+    fn channel(capacity: usize) -> (Self, Receiver<T>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        (Self { sender, dropped: Arc::new(AtomicU64::new(0)) }, receiver)
+    }
+
+    /// Sends the record, it is dropped if the writer thread is behind or has ended.
+    fn send(&self, record: T) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Starts a thread writing what is received to the `writer` as JSON lines, until every sender is
+/// dropped, logging the records the senders dropped meanwhile.
+fn spawn_writer<T: serde::Serialize + Send + 'static>(
+    log: Logger, mut writer: RotatingWriter, sender: &RecordSender<T>, mut receiver: Receiver<T>,
+) -> JoinHandle<()> {
+    let dropped = sender.dropped.clone();
+    std::thread::spawn(move || {
+        let mut reported = 0;
+        while let Some(record) = receiver.blocking_recv() {
+            let written = serde_json::to_vec(&record)
+                .map_err(std::io::Error::from)
+                .and_then(|line| writer.write_line(&line));
+            if let Err(err) = written {
+                error!(log, "error recording"; "error" => err.to_string(), "prefix" => writer.prefix);
+            }
+            let total = dropped.load(Ordering::Relaxed);
+            if total > reported {
+                warn!(
+                    log, "the recorder is behind, records were dropped";
+                    "dropped" => total - reported, "prefix" => writer.prefix
+                );
+                reported = total;
+            }
+        }
+        if let Err(err) = writer.finish() {
+            error!(log, "error completing the recording"; "error" => err.to_string(), "prefix" => writer.prefix);
+        }
+        info!(log, "recorder has ended"; "prefix" => writer.prefix);
+    })
+}
+
+/// FeedRecorder sends the raw frames from the sources to a thread writing them to disk, so an
+/// incident can be reproduced later from exactly what the exchanges sent.
+/// The thread ends once every clone of the recorder is dropped.
+#[derive(Clone, Debug)]
+pub struct FeedRecorder {
+    sender: RecordSender<RawFrame>,
+}
+
+impl FeedRecorder {
+    /// Starts the thread writing the frames to `directory` in files of up to `max_bytes`
+    /// uncompressed, returning its handle to wait for the last file to be completed.
+    pub fn start(
+        log: Logger, directory: &Path, max_bytes: u64,
+    ) -> Result<(Self, JoinHandle<()>), Box<dyn std::error::Error>> {
+        let writer = RotatingWriter::new(directory, "feed", max_bytes)?;
+        let (sender, receiver) = RecordSender::<RawFrame>::channel(RECORD_CAPACITY);
+        info!(log, "recording the exchange feeds"; "directory" => directory.display().to_string());
+
+        let handle = spawn_writer(log, writer, &sender, receiver);
+        Ok((Self { sender }, handle))
+    }
+
+    /// Records the frame received from the exchange, it is dropped if the writer thread is behind
+    /// or has ended.
+    pub fn record(&self, exchange: &str, received_timestamp: SystemTime, frame: &[u8]) {
+        self.sender.send(RawFrame {
+            exchange: exchange.to_string(),
+            received_timestamp_us: timestamp_us(Some(received_timestamp)),
+            frame: String::from_utf8_lossy(frame).into_owned(),
+        });
+    }
+}
+
+/// SummaryRecorder sends the summaries published by the `OrderbookMerger`s to a thread writing
+/// them to disk, so they can be served again exactly as they were with `run_replay`.
+/// The thread ends once every clone of the recorder is dropped.
+#[derive(Clone, Debug)]
+pub struct SummaryRecorder {
+    sender: RecordSender<RecordedSummary>,
+}
+
+impl SummaryRecorder {
+    /// Starts the thread writing the summaries to `directory` in files of up to `max_bytes`
+    /// uncompressed, returning its handle to wait for the last file to be completed.
+    pub fn start(
+        log: Logger, directory: &Path, max_bytes: u64,
+    ) -> Result<(Self, JoinHandle<()>), Box<dyn std::error::Error>> {
+        let writer = RotatingWriter::new(directory, "summaries", max_bytes)?;
+        let (sender, receiver) = RecordSender::<RecordedSummary>::channel(RECORD_CAPACITY);
+        info!(log, "recording the summaries"; "directory" => directory.display().to_string());
+
+        let handle = spawn_writer(log, writer, &sender, receiver);
+        Ok((Self { sender }, handle))
+    }
+
+    /// Records the summary published for the symbol, it is dropped if the writer thread is behind
+    /// or has ended.
+    pub fn record(&self, symbol: &Symbol, summary: &Summary) {
+        self.sender.send(RecordedSummary::new(symbol, SystemTime::now(), summary));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        recorder::{
+            read_frames,
+            read_summaries,
+            FeedRecorder,
+            RawFrame,
+            RecordSender,
+            SummaryRecorder,
+        },
+        types::{
+            Asset,
+            Level,
+            Summary,
+            Symbol,
+        },
+    };
+    use slog::{
+        Logger,
+        o,
+    };
+    use std::{
+        sync::atomic::Ordering,
+        time::{
+            Duration,
+            UNIX_EPOCH,
+        },
+    };
+
+    #[test]
+    fn should_record_frames_in_rotating_files() {
+        // Given
+        let directory = std::env::temp_dir().join(format!("mbooks-recorder-{}", rand::random::<u64>()));
+        let (recorder, handle) = FeedRecorder::start(
+            Logger::root(slog::Discard, o!()), &directory, 100,
+        ).unwrap();
+
+        // When
+        for sequence in 0..5 {
+            recorder.record(
+                "binance",
+                UNIX_EPOCH + Duration::from_micros(sequence),
+                format!("{{\"lastUpdateId\":{}}}", sequence).as_bytes(),
+            );
+        }
+        drop(recorder);
+        handle.join().unwrap();
+
+        // Then
+        let files = std::fs::read_dir(&directory).unwrap().count();
+        let frames: Vec<RawFrame> = read_frames(&directory).unwrap()
+            .map(|frame| frame.unwrap())
+            .collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(files > 1);
+        assert_eq!(5, frames.len());
+        assert_eq!(
+            RawFrame {
+                exchange: "binance".to_string(),
+                received_timestamp_us: 4,
+                frame: "{\"lastUpdateId\":4}".to_string(),
+            },
+            frames[4],
+        );
+        assert!(frames.windows(2).all(|frames| frames[0].received_timestamp_us < frames[1].received_timestamp_us));
+    }
+
+    #[test]
+    fn should_record_summaries_exactly() {
+        // Given
+        let directory = std::env::temp_dir().join(format!("mbooks-recorder-{}", rand::random::<u64>()));
+        let (recorder, handle) = SummaryRecorder::start(
+            Logger::root(slog::Discard, o!()), &directory, 1024,
+        ).unwrap();
+        let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+        let level = |exchange: &str, price: &str| Level::parse(exchange, &[price.to_string(), "31.99050000".to_string()]).unwrap();
+        let summary = Summary::from_levels(
+            vec![level("binance", "0.06754400")],
+            vec![level("bitstamp", "0.06754500")],
+        ).with_timestamps(Some(UNIX_EPOCH + Duration::from_micros(10)), UNIX_EPOCH + Duration::from_micros(20));
+
+        // When
+        recorder.record(&symbol, &summary);
+        recorder.record(&symbol, &Summary::default());
+        drop(recorder);
+        handle.join().unwrap();
+
+        // Then
+        let summaries: Vec<_> = read_summaries(&directory).unwrap()
+            .map(|summary| summary.unwrap().summary().unwrap())
+            .collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(2, summaries.len());
+        let (resp_symbol, resp) = &summaries[0];
+        assert_eq!(symbol, *resp_symbol);
+        assert_eq!(summary.bids, resp.bids);
+        assert_eq!(summary.asks, resp.asks);
+        assert_eq!("0.06754400", resp.bids[0].price.to_string());
+        assert_eq!(summary.exchange_timestamp, resp.exchange_timestamp);
+        assert_eq!(summary.received_timestamp, resp.received_timestamp);
+        assert!(summaries[1].1.bids.is_empty());
+    }
+
+    #[test]
+    fn should_count_the_records_dropped_when_the_writer_is_behind() {
+        // Given
+        let (sender, mut receiver) = RecordSender::channel(2);
+
+        // When
+        for record in 0..5 {
+            sender.send(record);
+        }
+
+        // Then
+        assert_eq!(3, sender.dropped.load(Ordering::Relaxed));
+        assert_eq!(Ok(0), receiver.try_recv());
+        assert_eq!(Ok(1), receiver.try_recv());
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use crate::{
+    recorder::{
+        read_frames,
+        RawFrame,
+    },
+    source::{
+        BookMode,
+        ConnectionEnd,
+        Source,
+        SourceConfig,
+        SourceRegistry,
+        SummarySenders,
+    },
+    types::{
+        MBooksError,
+        Symbol,
+    },
+};
+use futures_util::{
+    future::join_all,
+    SinkExt,
+    stream::SplitSink,
+    StreamExt,
+};
+use slog::{
+    error,
+    info,
+    Logger,
+    o,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    sync::broadcast,
+    time::Instant,
+};
+use tokio_tungstenite::{
+    accept_async,
+    tungstenite::protocol::Message,
+    WebSocketStream,
+};
+
+/// Exchanges that need a REST request to connect, so they can not be replayed from the frames.
+const REST_EXCHANGES: [&str; 1] = ["kucoin"];
+
+type ReplaySink = SplitSink<WebSocketStream<TcpStream>, Message>;
+
+/// ReplayPace is how fast the recorded frames are replayed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayPace {
+    /// Sends every frame as soon as the previous one was sent.
+    AsFastAsPossible,
+    /// Keeps the time between the frames divided by the speed, 1 for the original one.
+    Scaled(f64),
+}
+
+impl TryFrom<&str> for ReplayPace {
+    type Error = MBooksError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "max" => Ok(ReplayPace::AsFastAsPossible),
+            speed => match speed.parse::<f64>() {
+                Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(ReplayPace::Scaled(speed)),
+                _ => Err(MBooksError::InvalidReplaySpeed(value.to_string())),
+            },
+        }
+    }
+}
+
+/// Replay `Source` implementation, it replays the frames recorded by the `FeedRecorder` through the
+/// same sources that received them.
+/// Each replayed exchange gets a local WebSocket server sending its recorded frames, and its source
+/// connects to it instead of the exchange, so the frames go through the exact same parsing.
+/// The frames are sent in the order they were received across all the exchanges, then the
+/// connections are kept open so the last books are still served.
+/// The sources must be configured as they were when recording, with the `snapshot` book mode since
+/// the REST snapshots are not recorded.
+pub struct Replay {
+    config: SourceConfig,
+    exchanges: Vec<String>,
+    directory: PathBuf,
+    pace: ReplayPace,
+}
+
+impl Replay {
+    pub fn new(
+        config: SourceConfig, exchanges: &[String], directory: &Path, pace: ReplayPace,
+    ) -> Result<Self, MBooksError> {
+        if config.book_mode != BookMode::Snapshot {
+            return Err(MBooksError::InvalidBookMode(format!("{:?}", config.book_mode)));
+        }
+        let exchanges: Vec<String> = exchanges.iter().map(|exchange| exchange.to_lowercase()).collect();
+        if let Some(exchange) = exchanges.iter().find(|exchange| REST_EXCHANGES.contains(&exchange.as_str())) {
+            return Err(MBooksError::InvalidSource(exchange.clone()));
+        }
+
+        Ok(Self {
+            config,
+            exchanges,
+            directory: directory.to_path_buf(),
+            pace,
+        })
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for Replay {
+    fn name(&self) -> &'static str {
+        "replay"
+    }
+
+    fn symbol_to_string(&self, symbol: &Symbol) -> String {
+        symbol.to_string()
+    }
+
+    fn exchanges(&self) -> Vec<String> {
+        self.exchanges.clone()
+    }
+
+    async fn connect(
+        &self,
+        log: &Logger,
+        shutdown_receiver: &mut broadcast::Receiver<String>,
+        summary_txs: &SummarySenders,
+    ) -> Result<ConnectionEnd, Box<dyn std::error::Error>> {
+        info!(log, "replaying"; "directory" => self.directory.display().to_string(), "pace" => format!("{:?}", self.pace));
+
+        let mut config = self.config.clone();
+        let mut listeners = Vec::with_capacity(self.exchanges.len());
+        for exchange in &self.exchanges {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            config.endpoints.insert_websocket(&format!("{}=ws://{}", exchange, listener.local_addr()?))?;
+            listeners.push((exchange.clone(), listener));
+        }
+        let sources = SourceRegistry::default().build(&self.exchanges, &config)?;
+
+        // The sources stop with their own shutdown, since there is a single receiver
+        let (sources_shutdown, _) = broadcast::channel(1);
+        let run_sources = join_all(sources.iter().map(|source| {
+            let log = log.new(o!("exchange" => source.name()));
+            let mut shutdown_receiver = sources_shutdown.subscribe();
+            async move {
+                match source.connect(&log, &mut shutdown_receiver, summary_txs).await {
+                    Ok(end) => info!(log, "replayed source has ended"; "end" => format!("{:?}", end)),
+                    Err(err) => error!(log, "replayed source failed"; "error" => err.to_string()),
+                }
+            }
+        }));
+        let replay_frames = replay_frames(log, listeners, &self.directory, self.pace);
+        tokio::pin!(run_sources);
+        tokio::pin!(replay_frames);
+
+        let mut connections = None;
+        loop {
+            tokio::select! {
+                replayed = &mut replay_frames, if connections.is_none() => {
+                    connections = Some(replayed?);
+                    info!(log, "replay finished, keeping the last books");
+                }
+                _ = &mut run_sources => {
+                    warn!(log, "every replayed source has ended");
+                    return Ok(ConnectionEnd::Closed);
+                }
+                _ = shutdown_receiver.recv() => {
+                    info!(log, "application is shutting down, closing replay");
+                    let _ = sources_shutdown.send("replay is shutting down".to_string());
+                    run_sources.await;
+                    return Ok(ConnectionEnd::Shutdown);
+                }
+            }
+        }
+    }
+}
+
+/// Time to wait before sending a frame received `elapsed` after the first one.
+pub(crate) fn frame_delay(pace: ReplayPace, elapsed: Duration) -> Duration {
+    match pace {
+        ReplayPace::AsFastAsPossible => Duration::ZERO,
+        ReplayPace::Scaled(speed) => elapsed.div_f64(speed),
+    }
+}
+
+/// Waits for the source of each exchange to connect, then sends the recorded frames to them,
+/// returning the connections so they are kept open.
+async fn replay_frames(
+    log: &Logger, listeners: Vec<(String, TcpListener)>, directory: &Path, pace: ReplayPace,
+) -> Result<HashMap<String, ReplaySink>, Box<dyn std::error::Error>> {
+    let mut connections = HashMap::with_capacity(listeners.len());
+    for (exchange, listener) in listeners {
+        let (stream, _) = listener.accept().await?;
+        let (write, _) = accept_async(stream).await?.split();
+        connections.insert(exchange, write);
+    }
+    info!(log, "replayed sources connected");
+
+    let mut start: Option<(u64, Instant)> = None;
+    let mut replayed = 0;
+    for frame in read_frames(directory)? {
+        let RawFrame { exchange, received_timestamp_us, frame } = frame?;
+        let connection = match connections.get_mut(&exchange) {
+            Some(connection) => connection,
+            None => continue,
+        };
+
+        let (first_timestamp_us, started) = *start.get_or_insert((received_timestamp_us, Instant::now()));
+        let elapsed = Duration::from_micros(received_timestamp_us.saturating_sub(first_timestamp_us));
+        tokio::time::sleep_until(started + frame_delay(pace, elapsed)).await;
+        connection.send(Message::Text(frame)).await?;
+        replayed += 1;
+    }
+    info!(log, "replayed the recorded frames"; "frames" => replayed);
+
+    Ok(connections)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        recorder::FeedRecorder,
+        replay::{
+            frame_delay,
+            Replay,
+            ReplayPace,
+        },
+        source::{
+            BookMode,
+            Endpoints,
+            Source,
+            SourceConfig,
+            SummarySenders,
+        },
+        types::{
+            Asset,
+            BookUpdate,
+            MBooksError,
+            Symbol,
+        },
+    };
+    use rust_decimal_macros::dec;
+    use slog::{
+        Logger,
+        o,
+    };
+    use std::time::{
+        Duration,
+        UNIX_EPOCH,
+    };
+    use tokio::sync::{
+        broadcast,
+        mpsc,
+    };
+
+    fn config(book_mode: BookMode) -> SourceConfig {
+        SourceConfig {
+            symbols: vec![Symbol { base: Asset::ETH, quote: Asset::BTC }],
+            depth: 10,
+            book_mode,
+            endpoints: Endpoints::new(),
+        }
+    }
+
+    #[test]
+    fn should_parse_replay_pace() {
+        // Given
+        let values = vec!["max", "2.5", "0", "fast"];
+
+        // When
+        let resp: Vec<_> = values.into_iter().map(ReplayPace::try_from).collect();
+
+        // Then
+        assert_eq!(ReplayPace::AsFastAsPossible, *resp[0].as_ref().unwrap());
+        assert_eq!(ReplayPace::Scaled(2.5), *resp[1].as_ref().unwrap());
+        assert!(matches!(resp[2], Err(MBooksError::InvalidReplaySpeed(_))));
+        assert!(matches!(resp[3], Err(MBooksError::InvalidReplaySpeed(_))));
+    }
+
+    #[test]
+    fn should_scale_frame_delay() {
+        // Given
+        let elapsed = Duration::from_millis(100);
+
+        // When
+        let resp = vec![
+            frame_delay(ReplayPace::AsFastAsPossible, elapsed),
+            frame_delay(ReplayPace::Scaled(1.0), elapsed),
+            frame_delay(ReplayPace::Scaled(4.0), elapsed),
+        ];
+
+        // Then
+        assert_eq!(vec![Duration::ZERO, Duration::from_millis(100), Duration::from_millis(25)], resp);
+    }
+
+    #[test]
+    fn should_not_replay_rest_sources() {
+        // Given
+        let directory = std::env::temp_dir();
+
+        // When
+        let diff = Replay::new(config(BookMode::Diff), &["binance".to_string()], &directory, ReplayPace::AsFastAsPossible);
+        let kucoin = Replay::new(config(BookMode::Snapshot), &["kucoin".to_string()], &directory, ReplayPace::AsFastAsPossible);
+
+        // Then
+        assert!(matches!(diff, Err(MBooksError::InvalidBookMode(_))));
+        assert!(matches!(kucoin, Err(MBooksError::InvalidSource(_))));
+    }
+
+    #[tokio::test]
+    async fn should_replay_recorded_frames() {
+        // Given
+        let log = Logger::root(slog::Discard, o!());
+        let directory = std::env::temp_dir().join(format!("mbooks-replay-{}", rand::random::<u64>()));
+        let (recorder, handle) = FeedRecorder::start(log.clone(), &directory, 1024 * 1024).unwrap();
+        let frames = vec![
+            ("bitstamp", r#"{"event":"bts:subscription_succeeded","channel":"order_book_ethbtc","data":{}}"#),
+            ("binance", r#"{"stream":"ethbtc@depth10@100ms","data":{"lastUpdateId":1,"bids":[["0.0675","1"]],"asks":[["0.0677","1"]]}}"#),
+            ("bitstamp", r#"{"event":"data","channel":"order_book_ethbtc","data":{"timestamp":"1666200249","microtimestamp":"1666200249249913","bids":[["0.0676","2"]],"asks":[["0.0678","2"]]}}"#),
+            ("coinbase", r#"{"type":"heartbeat"}"#),
+        ];
+        for (received, (exchange, frame)) in frames.into_iter().enumerate() {
+            recorder.record(exchange, UNIX_EPOCH + Duration::from_millis(received as u64), frame.as_bytes());
+        }
+        drop(recorder);
+        handle.join().unwrap();
+        let replay = Replay::new(
+            config(BookMode::Snapshot), &["binance".to_string(), "bitstamp".to_string()],
+            &directory, ReplayPace::AsFastAsPossible,
+        ).unwrap();
+        let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+        let (summary_sender, mut summary_receiver) = mpsc::unbounded_channel();
+        let mut summary_txs = SummarySenders::new();
+        summary_txs.insert(symbol, summary_sender);
+        let (shutdown_sender, mut shutdown_receiver) = broadcast::channel(1);
+
+        // When
+        let mut updates = Vec::new();
+        let receive = async {
+            while updates.len() < 2 {
+                if let Some(BookUpdate::Summary(summary)) = summary_receiver.recv().await {
+                    updates.push(summary);
+                }
+            }
+            shutdown_sender.send("replayed".to_string()).unwrap();
+        };
+        let resp = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(replay.connect(&log, &mut shutdown_receiver, &summary_txs), receive).0
+        }).await;
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // Then
+        assert!(resp.unwrap().is_ok());
+        assert_eq!(vec!["binance", "bitstamp"], replay.exchanges());
+        // The sources run concurrently, so their summaries may come in any order
+        let exchange_update = |exchange: &str| updates.iter()
+            .find(|summary| summary.bids[0].exchange == exchange)
+            .unwrap();
+        assert_eq!(dec!(0.0675), exchange_update("binance").bids[0].price);
+        assert_eq!(dec!(0.0678), exchange_update("bitstamp").asks[0].price);
+    }
+}
@@ -1,7 +1,11 @@
 use crate::{
-    binance::run_binance,
-    bitstamp::run_bitstamp,
+    arbitrage::Arbitrage,
+    delta::DeltaEncoder,
     orderbook::{
+        ArbitrageRequest,
+        ArbitrageUpdate,
+        BookDelta,
+        BookSummaryRequest,
         Empty,
         Summary,
         orderbook_aggregator_server::{
@@ -9,9 +13,32 @@ use crate::{
             OrderbookAggregatorServer,
         },
     },
-    merger::OrderbookMerger,
-    types::Symbol,
+    merger::{
+        OrderbookMerger,
+        TakerFees,
+    },
+    recorder::{
+        read_summaries,
+        FeedRecorder,
+        SummaryRecorder,
+    },
+    replay::{
+        frame_delay,
+        ReplayPace,
+    },
+    source::{
+        Source,
+        SummarySenders,
+    },
+    types::{
+        self,
+        MBooksError,
+        SummaryOptions,
+        Symbol,
+    },
 };
+use futures_util::future::try_join_all;
+use rust_decimal::Decimal;
 use opentelemetry::{
     global,
     trace::{
@@ -22,52 +49,345 @@ use opentelemetry::{
     Context,
 };
 use slog::{
+    debug,
     error,
     Logger,
     info,
+    o,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::Duration,
 };
 use tonic::{
     transport::Server,
     Response,
     Status,
 };
-use tokio::sync::{
-    mpsc::{
-        self,
-        Receiver,
-        Sender,
-        UnboundedReceiver,
+use tokio::{
+    sync::{
+        mpsc::{
+            self,
+            Receiver,
+            Sender,
+            UnboundedReceiver,
+            UnboundedSender,
+        },
+        watch,
+        Mutex,
     },
-    Mutex,
+    time::Instant,
 };
 use tokio_stream::wrappers::ReceiverStream;
 
-pub type ClientSubscription = Sender<Result<Summary, Status>>;
+/// SlowClientPolicy is what the server does with a client that does not keep up with the summaries.
+/// In both cases the client only gets the most recent summary once it is ready for the next one,
+/// so a slow client never delays the others.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SlowClientPolicy {
+    /// Skips the outdated summaries.
+    Conflate,
+    /// Disconnects the client once it skips more than `max_lag` summaries in a row.
+    Disconnect { max_lag: u64 },
+}
+
+impl SlowClientPolicy {
+    pub fn new(name: &str, max_lag: u64) -> Result<Self, MBooksError> {
+        match name.to_lowercase().as_str() {
+            "conflate" => Ok(SlowClientPolicy::Conflate),
+            "disconnect" => Ok(SlowClientPolicy::Disconnect { max_lag }),
+            _ => Err(MBooksError::InvalidSlowClientPolicy(name.to_string())),
+        }
+    }
+}
+
+/// Time a client disconnected for being slow has to get the status with the reason.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ServerConfig has the choices for `run_server`.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Address the gRPC server listens to.
+    pub address: String,
+    /// Symbols served, each one has its own `OrderbookMerger`.
+    pub symbols: Vec<Symbol>,
+    /// Depth of the book kept by the `OrderbookMerger`s.
+    pub depth: usize,
+    /// Time without updates after which the levels from an exchange are removed.
+    pub stale_timeout: Option<Duration>,
+    /// Fees of the exchanges to merge with the fee-adjusted prices, the raw prices when empty.
+    pub taker_fees: TakerFees,
+    pub slow_client_policy: SlowClientPolicy,
+    /// Records the raw frames received by the sources.
+    pub recorder: Option<FeedRecorder>,
+    /// Records the summaries published by the `OrderbookMerger`s.
+    pub summary_recorder: Option<SummaryRecorder>,
+}
+
+/// ReplayConfig has the choices for `run_replay`.
+#[derive(Clone, Debug)]
+pub struct ReplayConfig {
+    /// Address the gRPC server listens to.
+    pub address: String,
+    /// Directory with the summaries recorded by the `SummaryRecorder`.
+    pub directory: PathBuf,
+    pub pace: ReplayPace,
+    /// Starts again from the first summary after the last one.
+    pub looping: bool,
+    pub slow_client_policy: SlowClientPolicy,
+}
+
+/// GrpcConfig has the choices for `run_grpc_server`.
+struct GrpcConfig {
+    address: String,
+    depth: usize,
+    exchanges: Vec<String>,
+    slow_client_policy: SlowClientPolicy,
+    summary_recorder: Option<SummaryRecorder>,
+}
+
+/// SummaryUpdate is the most recent summary for a client, numbered so it is possible to know how
+/// many were skipped.
+#[derive(Clone)]
+struct SummaryUpdate {
+    sequence: u64,
+    summary: Summary,
+}
+
+/// LatestSummary is the most recent summary published by the `OrderbookMerger` of a symbol, with the
+/// same sequence number given to the clients, 0 before the first one.
+type LatestSummary = (u64, types::Summary);
+
+/// ClientSubscription is a connected client, it keeps the channel to publish the summaries and the
+/// options the client chose for them.
+/// Publishing never waits for the client, the summaries are forwarded by `forward_summaries`.
+pub struct ClientSubscription {
+    sender: watch::Sender<Option<SummaryUpdate>>,
+    options: SummaryOptions,
+}
 
 /// OrderbookAggregatorImpl the gRPC server implementation.
 pub struct OrderbookAggregatorImpl {
     log: Logger,
     clients_to_connect_sender: Sender<ClientSubscription>,
+    /// Symbols served, the first one is the default for the clients.
+    symbols: Vec<Symbol>,
+    /// Depth of the book kept by the `OrderbookMerger`.
+    depth: usize,
+    /// Exchanges the server is connected to.
+    exchanges: Vec<String>,
+    slow_client_policy: SlowClientPolicy,
+    /// Most recent summary of each symbol, published by `listen_summaries`.
+    latest_summaries: HashMap<Symbol, watch::Receiver<LatestSummary>>,
+    /// Arbitrage opportunities of each symbol, published by `detect_arbitrage`.
+    arbitrage: HashMap<Symbol, watch::Receiver<Arbitrage>>,
 }
 
 impl OrderbookAggregatorImpl {
     fn new(
         log: Logger,
         clients_to_connect_sender: Sender<ClientSubscription>,
+        symbols: Vec<Symbol>, depth: usize, exchanges: Vec<String>,
+        slow_client_policy: SlowClientPolicy,
+        latest_summaries: HashMap<Symbol, watch::Receiver<LatestSummary>>,
     ) -> Self {
         Self {
             log,
             clients_to_connect_sender,
+            symbols,
+            depth,
+            exchanges,
+            slow_client_policy,
+            latest_summaries,
+            arbitrage: HashMap::new(),
+        }
+    }
+
+    /// Serves the opportunities found by `detect_arbitrage`, the `Arbitrage` stream is unavailable
+    /// otherwise.
+    fn with_arbitrage(mut self, arbitrage: HashMap<Symbol, watch::Receiver<Arbitrage>>) -> Self {
+        self.arbitrage = arbitrage;
+        self
+    }
+
+    /// Most recent summary of the symbol, `None` before the first one.
+    fn latest_summary(
+        latest_summaries: &HashMap<Symbol, watch::Receiver<LatestSummary>>, symbol: &Symbol,
+    ) -> Option<LatestSummary> {
+        latest_summaries.get(symbol)
+            .map(|latest| latest.borrow().clone())
+            .filter(|(sequence, _)| *sequence > 0)
+    }
+
+    /// Validates the request from the client, translating it to the `SummaryOptions`.
+    /// The error is the reason why the request is invalid.
+    fn summary_options(&self, request: BookSummaryRequest) -> Result<SummaryOptions, String> {
+        let symbol = if request.symbol.is_empty() {
+            self.symbols.first().cloned().ok_or_else(|| "no symbol is served".to_string())?
+        } else {
+            let symbol = Symbol::try_from(request.symbol.clone())
+                .map_err(|err| format!("invalid symbol: {}", err))?;
+            if !self.symbols.contains(&symbol) {
+                return Err(format!(
+                    "symbol {} is not served, the available ones are {:?}", request.symbol,
+                    self.symbols.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+                ));
+            }
+            symbol
+        };
+
+        let mut exchanges = Vec::with_capacity(request.exchanges.len());
+        for exchange in request.exchanges {
+            let exchange = exchange.to_lowercase();
+            if !self.exchanges.contains(&exchange) {
+                return Err(format!(
+                    "exchange {} is not available, the available ones are {:?}", exchange, self.exchanges,
+                ));
+            }
+            exchanges.push(exchange);
+        }
+
+        let depth = match request.depth as usize {
+            0 => self.depth,
+            depth => depth.min(self.depth),
+        };
+
+        let bucket_size = if request.bucket_size.is_empty() {
+            None
+        } else {
+            match request.bucket_size.parse::<Decimal>() {
+                Ok(bucket_size) if bucket_size > Decimal::ZERO => Some(bucket_size),
+                _ => return Err(format!("invalid bucket size {}, it must be a positive decimal", request.bucket_size)),
+            }
+        };
+
+        Ok(SummaryOptions {
+            symbol,
+            depth,
+            exchanges,
+            aggregated: request.aggregated,
+            bucket_size,
+            exchange_depth: self.depth,
+        })
+    }
+
+    /// Adds the client to the list of targets who will receive the summary updates, turned into
+    /// the messages of its stream by `encode`.
+    async fn subscribe<T, F>(
+        &self, options: SummaryOptions, encode: F,
+    ) -> Result<Response<ReceiverStream<Result<T, Status>>>, Status>
+    where
+        T: Send + 'static,
+        F: FnMut(Summary) -> Option<T> + Send + 'static,
+    {
+        info!(self.log, "got a new client"; "options" => format!("{:?}", options));
+        let (tx, rx) = mpsc::channel(1);
+        let (summary_sender, summary_receiver) = watch::channel(None);
+        tokio::spawn(Self::forward_summaries(
+            self.log.clone(), summary_receiver, tx, self.slow_client_policy, encode,
+        ));
+
+        let client = ClientSubscription {
+            sender: summary_sender,
+            options,
+        };
+        if let Err(err) = self.clients_to_connect_sender.send(client).await {
+            error!(self.log, "error adding client"; "error" => format!("{:?}", err));
+            Err(Status::internal("unable to add client"))
+        } else {
+            info!(self.log, "client added successfully");
+            Ok(Response::new(ReceiverStream::new(rx)))
+        }
+    }
+
+    /// Forwards the most recent summary to the client whenever it is ready to receive it, replacing
+    /// the pending one on every new summary, until the client disconnects or is removed.
+    /// The summaries are sent as `encode` returns them, nothing is sent when it returns `None`.
+    async fn forward_summaries<T, F: FnMut(Summary) -> Option<T>>(
+        log: Logger,
+        summary_receiver: watch::Receiver<Option<SummaryUpdate>>,
+        client_sender: Sender<Result<T, Status>>,
+        slow_client_policy: SlowClientPolicy,
+        mut encode: F,
+    ) {
+        let mut summary_receiver = summary_receiver;
+        let mut pending: Option<Summary> = None;
+        let mut last_sequence = 0;
+        let mut lag = 0;
+        loop {
+            tokio::select! {
+                permit = client_sender.reserve(), if pending.is_some() => {
+                    match permit {
+                        Ok(permit) => {
+                            if let Some(message) = encode(pending.take().unwrap()) {
+                                permit.send(Ok(message));
+                            }
+                            lag = 0;
+                        }
+                        Err(_) => {
+                            info!(log, "client dropped");
+                            return;
+                        }
+                    }
+                }
+                changed = summary_receiver.changed() => {
+                    if changed.is_err() {
+                        info!(log, "client removed, stopping forward_summaries");
+                        return;
+                    }
+                    let update = match summary_receiver.borrow().clone() {
+                        Some(update) => update,
+                        None => continue,
+                    };
+
+                    // The first summary is the one the client starts from, nothing was skipped before it
+                    let mut skipped = match last_sequence {
+                        0 => 0,
+                        last_sequence => update.sequence.saturating_sub(last_sequence + 1),
+                    };
+                    if pending.is_some() {
+                        skipped += 1;
+                    }
+                    last_sequence = update.sequence;
+                    pending = Some(update.summary);
+                    if skipped == 0 {
+                        continue;
+                    }
+
+                    lag += skipped;
+                    debug!(log, "slow client, skipping summaries"; "lag" => lag);
+                    if let SlowClientPolicy::Disconnect { max_lag } = slow_client_policy {
+                        if lag > max_lag {
+                            warn!(log, "client is too slow, disconnecting it"; "lag" => lag, "max_lag" => max_lag);
+                            // The status replaces the pending summary, once the client gets the one it is sent
+                            let status = Status::resource_exhausted(format!("client skipped {} summaries", lag));
+                            let sent = tokio::time::timeout(DISCONNECT_TIMEOUT, client_sender.send(Err(status))).await;
+                            if !matches!(sent, Ok(Ok(()))) {
+                                info!(log, "client did not get the reason it was disconnected");
+                            }
+                            return;
+                        }
+                    }
+                }
+                _ = client_sender.closed() => {
+                    info!(log, "client dropped");
+                    return;
+                }
+            }
         }
     }
 
     /// Listens to clients trying to connect and add them to the list of targets who will receive
     /// the summary updates.
+    /// The current book is the first summary a client gets, so it does not wait for an update.
     async fn listen_clients_to_connect(
         log: Logger,
         shutdown_receiver: tokio::sync::broadcast::Receiver<String>,
         targets: &Mutex<Vec<ClientSubscription>>,
         clients_to_connect_receiver: Receiver<ClientSubscription>,
+        latest_summaries: HashMap<Symbol, watch::Receiver<LatestSummary>>,
     ) -> Result<(), tonic::transport::Error> {
         let mut shutdown_receiver = shutdown_receiver;
         let mut clients_to_connect_receiver = clients_to_connect_receiver;
@@ -75,7 +395,16 @@ impl OrderbookAggregatorImpl {
             tokio::select! {
                 message = clients_to_connect_receiver.recv() => {
                     if let Some(client_to_connect) = message {
-                        targets.lock().await.push(client_to_connect);
+                        // Holding the lock, so `listen_summaries` can not publish a newer summary in between
+                        let mut targets = targets.lock().await;
+                        let options = &client_to_connect.options;
+                        if let Some((sequence, summary)) = Self::latest_summary(&latest_summaries, &options.symbol) {
+                            let _ = client_to_connect.sender.send(Some(SummaryUpdate {
+                                sequence,
+                                summary: options.apply(&summary).into(),
+                            }));
+                        }
+                        targets.push(client_to_connect);
                     } else {
                         info!(log, "no more messages listen_clients_to_connect");
                         return Ok(());
@@ -89,30 +418,125 @@ impl OrderbookAggregatorImpl {
         }
     }
 
-    /// Listens to the summary updates from the WebSocket connections and updates internal book.
+    /// Sends the arbitrage opportunities to a client whenever they change, starting with the current
+    /// ones when the book is already crossed.
+    /// A slow client only gets the most recent opportunities once it is ready for them.
+    async fn forward_arbitrage(
+        log: Logger,
+        arbitrage_receiver: watch::Receiver<Arbitrage>,
+        client_sender: Sender<Result<ArbitrageUpdate, Status>>,
+    ) {
+        let mut arbitrage_receiver = arbitrage_receiver;
+        let current = arbitrage_receiver.borrow_and_update().clone();
+        if !current.opportunities.is_empty() && client_sender.send(Ok(current.into())).await.is_err() {
+            info!(log, "client disconnected");
+            return;
+        }
+        loop {
+            tokio::select! {
+                changed = arbitrage_receiver.changed() => {
+                    if changed.is_err() {
+                        info!(log, "no more arbitrage updates");
+                        return;
+                    }
+                    let arbitrage = arbitrage_receiver.borrow_and_update().clone();
+                    if client_sender.send(Ok(arbitrage.into())).await.is_err() {
+                        info!(log, "client disconnected");
+                        return;
+                    }
+                }
+                _ = client_sender.closed() => {
+                    info!(log, "client disconnected");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Looks for arbitrage opportunities in the most recent summaries of a symbol, publishing and
+    /// logging them whenever they change.
+    /// It skips the summaries published while it is busy, so it never delays the clients.
+    async fn detect_arbitrage(
+        log: Logger,
+        shutdown_receiver: tokio::sync::broadcast::Receiver<String>,
+        latest_summary: watch::Receiver<LatestSummary>,
+        arbitrage_sender: watch::Sender<Arbitrage>,
+    ) -> Result<(), tonic::transport::Error> {
+        let mut shutdown_receiver = shutdown_receiver;
+        let mut latest_summary = latest_summary;
+        loop {
+            tokio::select! {
+                changed = latest_summary.changed() => {
+                    if changed.is_err() {
+                        info!(log, "no more messages detect_arbitrage");
+                        return Ok(());
+                    }
+                    let arbitrage = Arbitrage::find(&latest_summary.borrow_and_update().1);
+                    if arbitrage.opportunities == arbitrage_sender.borrow().opportunities {
+                        continue;
+                    }
+                    if arbitrage.opportunities.is_empty() {
+                        info!(log, "arbitrage opportunities closed");
+                    }
+                    for opportunity in &arbitrage.opportunities {
+                        warn!(log, "arbitrage opportunity";
+                            "buy_exchange" => &opportunity.buy_exchange,
+                            "sell_exchange" => &opportunity.sell_exchange,
+                            "buy_price" => opportunity.buy_price.to_string(),
+                            "sell_price" => opportunity.sell_price.to_string(),
+                            "quantity" => opportunity.quantity.to_string(),
+                            "profit" => opportunity.profit.to_string());
+                    }
+                    let _ = arbitrage_sender.send(arbitrage);
+                }
+                _ = shutdown_receiver.recv() => {
+                    info!(log, "application is shutting down, closing detect_arbitrage");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Listens to the summary updates of a symbol from its `OrderbookMerger` and sends them to the
+    /// clients subscribed to it, recording them first when there is a `summary_recorder`.
     async fn listen_summaries(
         log: Logger,
         shutdown_receiver: tokio::sync::broadcast::Receiver<String>,
         targets: &Mutex<Vec<ClientSubscription>>,
-        grpc_receiver: UnboundedReceiver<Summary>,
+        symbol: Symbol,
+        grpc_receiver: UnboundedReceiver<types::Summary>,
+        latest_summary: watch::Sender<LatestSummary>,
+        summary_recorder: Option<SummaryRecorder>,
     ) -> Result<(), tonic::transport::Error> {
         let mut shutdown_receiver = shutdown_receiver;
         let mut grpc_receiver = grpc_receiver;
+        let mut sequence = 0;
         loop {
             tokio::select! {
                 message = grpc_receiver.recv() => {
                     if let Some(summary) = message {
-                        let mut it_targets = targets.lock().await;
-                        let mut resp = Vec::new();
-                        for target in it_targets.iter() {
-                            if let Err(err) = target.send(Ok(summary.clone())).await {
-                                info!(log, "client dropped"; "error" => format!("{:?}", err));
+                        if let Some(summary_recorder) = &summary_recorder {
+                            summary_recorder.record(&symbol, &summary);
+                        }
+                        sequence += 1;
+                        // Publishing never waits, so the lock is not held while a client is slow
+                        let mut targets = targets.lock().await;
+                        let _ = latest_summary.send((sequence, summary.clone()));
+                        targets.retain(|target| {
+                            if target.options.symbol != symbol {
+                                return true;
+                            }
+                            let update = SummaryUpdate {
+                                sequence,
+                                summary: target.options.apply(&summary).into(),
+                            };
+                            if target.sender.send(Some(update)).is_err() {
+                                info!(log, "client dropped");
+                                false
                             } else {
-                                resp.push(target.clone());
+                                true
                             }
-                        }
-
-                        *it_targets = resp;
+                        });
                     } else {
                         info!(log, "no more messages listen_summaries");
                         return Ok(());
@@ -130,20 +554,58 @@ impl OrderbookAggregatorImpl {
 #[tonic::async_trait]
 impl OrderbookAggregator for OrderbookAggregatorImpl {
     type BookSummaryStream = ReceiverStream<Result<Summary, Status>>;
+    type FilteredBookSummaryStream = ReceiverStream<Result<Summary, Status>>;
+    type BookDeltasStream = ReceiverStream<Result<BookDelta, Status>>;
+    type ArbitrageStream = ReceiverStream<Result<ArbitrageUpdate, Status>>;
 
     async fn book_summary(
         &self, _: tonic::Request<Empty>,
     ) -> Result<tonic::Response<Self::BookSummaryStream>, tonic::Status> {
-        info!(self.log, "got a new client");
-        let (tx, rx) = mpsc::channel(4);
+        let options = self.summary_options(BookSummaryRequest::default())
+            .map_err(Status::invalid_argument)?;
+        self.subscribe(options, Some).await
+    }
 
-        if let Err(err) = self.clients_to_connect_sender.send(tx).await {
-            error!(self.log, "error adding client"; "error" => format!("{:?}", err));
-            Err(Status::internal("unable to add client"))
-        } else {
-            info!(self.log, "client added successfully");
-            Ok(Response::new(ReceiverStream::new(rx)))
-        }
+    async fn filtered_book_summary(
+        &self, request: tonic::Request<BookSummaryRequest>,
+    ) -> Result<tonic::Response<Self::FilteredBookSummaryStream>, tonic::Status> {
+        let options = self.summary_options(request.into_inner())
+            .map_err(Status::invalid_argument)?;
+        self.subscribe(options, Some).await
+    }
+
+    async fn get_book_snapshot(
+        &self, request: tonic::Request<BookSummaryRequest>,
+    ) -> Result<tonic::Response<Summary>, tonic::Status> {
+        let options = self.summary_options(request.into_inner())
+            .map_err(Status::invalid_argument)?;
+        let summary = Self::latest_summary(&self.latest_summaries, &options.symbol)
+            .map(|(_, summary)| options.apply(&summary))
+            .unwrap_or_default();
+        Ok(Response::new(summary.into()))
+    }
+
+    async fn book_deltas(
+        &self, request: tonic::Request<BookSummaryRequest>,
+    ) -> Result<tonic::Response<Self::BookDeltasStream>, tonic::Status> {
+        let options = self.summary_options(request.into_inner())
+            .map_err(Status::invalid_argument)?;
+        let mut encoder = DeltaEncoder::default();
+        self.subscribe(options, move |summary| encoder.encode(summary)).await
+    }
+
+    async fn arbitrage(
+        &self, request: tonic::Request<ArbitrageRequest>,
+    ) -> Result<tonic::Response<Self::ArbitrageStream>, tonic::Status> {
+        let request = BookSummaryRequest { symbol: request.into_inner().symbol, ..Default::default() };
+        let options = self.summary_options(request)
+            .map_err(Status::invalid_argument)?;
+        let arbitrage_receiver = self.arbitrage.get(&options.symbol)
+            .cloned()
+            .ok_or_else(|| Status::unavailable("arbitrage detection is not running"))?;
+        let (client_sender, client_receiver) = mpsc::channel(1);
+        tokio::spawn(Self::forward_arbitrage(self.log.clone(), arbitrage_receiver, client_sender));
+        Ok(Response::new(ReceiverStream::new(client_receiver)))
     }
 }
 
@@ -160,9 +622,12 @@ async fn shutdown_signal(log: Logger, shutdown_receiver: tokio::sync::broadcast:
 async fn run_grpc_server(
     log: Logger,
     shutdown_sender: tokio::sync::broadcast::Sender<String>,
-    grpc_receiver: UnboundedReceiver<Summary>,
-    address: String,
+    grpc_receivers: Vec<(Symbol, UnboundedReceiver<types::Summary>)>,
+    config: GrpcConfig,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let GrpcConfig {
+        address, depth, exchanges, slow_client_policy, summary_recorder,
+    } = config;
     let tracer = global::tracer("run_server");
     let span = tracer.start(format!("running server at: {}", &address));
     let cx = Context::current_with_span(span);
@@ -173,10 +638,30 @@ async fn run_grpc_server(
 
     let targets = Mutex::new(Vec::new());
     let (clients_to_connect_sender, clients_to_connect_receiver) = mpsc::channel(10);
+    let mut latest_summaries = HashMap::with_capacity(grpc_receivers.len());
+    let mut arbitrage = HashMap::with_capacity(grpc_receivers.len());
+    let mut detect_arbitrage = Vec::with_capacity(grpc_receivers.len());
+    let grpc_receivers: Vec<_> = grpc_receivers.into_iter()
+        .map(|(symbol, grpc_receiver)| {
+            let (latest_sender, latest_receiver) = watch::channel((0, types::Summary::default()));
+            let (arbitrage_sender, arbitrage_receiver) = watch::channel(Arbitrage::default());
+            detect_arbitrage.push(OrderbookAggregatorImpl::detect_arbitrage(
+                log.new(o!("symbol" => symbol.to_string())),
+                shutdown_sender.subscribe(),
+                latest_receiver.clone(),
+                arbitrage_sender,
+            ));
+            latest_summaries.insert(symbol.clone(), latest_receiver);
+            arbitrage.insert(symbol.clone(), arbitrage_receiver);
+            (symbol, grpc_receiver, latest_sender)
+        })
+        .collect();
     let orderbook = OrderbookAggregatorImpl::new(
         log.clone(),
         clients_to_connect_sender,
-    );
+        grpc_receivers.iter().map(|(symbol, _, _)| symbol.clone()).collect(),
+        depth, exchanges, slow_client_policy, latest_summaries.clone(),
+    ).with_arbitrage(arbitrage);
 
     info!(log, "Orderbook server listening"; "address" => addr);
 
@@ -187,21 +672,28 @@ async fn run_grpc_server(
         .serve_with_shutdown(addr, shutdown_signal(log.clone(), grpc_server_shutdown_receiver))
         .with_context(cx);
 
-    let listen_summaries_shutdown_receiver = shutdown_sender.subscribe();
+    let listen_summaries = grpc_receivers.into_iter()
+        .map(|(symbol, grpc_receiver, latest_sender)| OrderbookAggregatorImpl::listen_summaries(
+            log.new(o!("symbol" => symbol.to_string())),
+            shutdown_sender.subscribe(),
+            &targets,
+            symbol,
+            grpc_receiver,
+            latest_sender,
+            summary_recorder.clone(),
+        ))
+        .collect::<Vec<_>>();
     let listen_clients_to_connect_shutdown_receiver = shutdown_sender.subscribe();
     drop(shutdown_sender);
     tokio::try_join!(
-        OrderbookAggregatorImpl::listen_summaries(
-            log.clone(),
-            listen_summaries_shutdown_receiver,
-            &targets,
-            grpc_receiver,
-        ),
+        try_join_all(listen_summaries),
+        try_join_all(detect_arbitrage),
         OrderbookAggregatorImpl::listen_clients_to_connect(
             log.clone(),
             listen_clients_to_connect_shutdown_receiver,
             &targets,
             clients_to_connect_receiver,
+            latest_summaries,
         ),
         run_grpc_server,
     )?;
@@ -211,37 +703,64 @@ async fn run_grpc_server(
     Ok(())
 }
 
-/// Starts the `OrderbookMerger`, the exchange connections, the gRPC server and tries to join all
-/// those futures.
+/// Starts an `OrderbookMerger` for each symbol, the exchange connections, the gRPC server and tries
+/// to join all those futures.
 pub async fn run_server(
     log: Logger,
     shutdown_sender: tokio::sync::broadcast::Sender<String>,
-    address: String, pair: Symbol, depth: usize,
+    config: ServerConfig, sources: Vec<Box<dyn Source>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (summary_sender, summary_receiver) = mpsc::unbounded_channel();
-    let (grpc_sender, grpc_receiver) = mpsc::unbounded_channel();
+    let ServerConfig {
+        address, symbols, depth, stale_timeout, taker_fees, slow_client_policy, recorder, summary_recorder,
+    } = config;
+    let mut summary_senders = SummarySenders::new();
+    if let Some(recorder) = recorder {
+        summary_senders = summary_senders.with_recorder(recorder);
+    }
+    let mut grpc_receivers = Vec::with_capacity(symbols.len());
+    let mut mergers = Vec::with_capacity(symbols.len());
+    for symbol in symbols {
+        let (summary_sender, summary_receiver) = mpsc::unbounded_channel();
+        let (grpc_sender, grpc_receiver) = mpsc::unbounded_channel();
 
-    let mut merger = OrderbookMerger::new(
-        log.clone(), summary_receiver, grpc_sender, depth,
-    );
+        let mut merger = OrderbookMerger::new(
+            log.new(o!("symbol" => symbol.to_string())), summary_receiver, grpc_sender, depth,
+        );
+        if let Some(stale_timeout) = stale_timeout {
+            merger = merger.with_stale_timeout(stale_timeout);
+        }
+        if !taker_fees.is_empty() {
+            merger = merger.with_taker_fees(taker_fees.clone());
+        }
+
+        summary_senders.insert(symbol.clone(), summary_sender);
+        grpc_receivers.push((symbol, grpc_receiver));
+        mergers.push(merger);
+    }
 
-    let binance_receiver = shutdown_sender.subscribe();
-    let bitstamp_receiver = shutdown_sender.subscribe();
-    let grpc_shutdown_sender = shutdown_sender.clone();
-    let merger_shutdown_sender = shutdown_sender;
+    let exchanges = sources.iter().flat_map(|source| source.exchanges()).collect();
+    let mut run_sources = Vec::with_capacity(sources.len());
+    for source in &sources {
+        info!(log, "starting source"; "exchange" => source.name());
+        run_sources.push(source.run(
+            log.clone(), shutdown_sender.subscribe(), summary_senders.clone(),
+        ));
+    }
+    drop(summary_senders);
+
+    let run_mergers = mergers.iter_mut()
+        .map(|merger| merger.start(shutdown_sender.clone()))
+        .collect::<Vec<_>>();
+    let grpc_shutdown_sender = shutdown_sender;
     match tokio::try_join!(
-        run_binance(
-            log.clone(), binance_receiver,
-            summary_sender.clone(), &pair, depth,
+        try_join_all(run_sources),
+        run_grpc_server(
+            log.clone(), grpc_shutdown_sender, grpc_receivers,
+            GrpcConfig { address, depth, exchanges, slow_client_policy, summary_recorder },
         ),
-        run_bitstamp(
-            log.clone(), bitstamp_receiver,
-            summary_sender, &pair, depth,
-        ),
-        run_grpc_server(log.clone(), grpc_shutdown_sender, grpc_receiver, address),
-        merger.start(merger_shutdown_sender),
+        try_join_all(run_mergers),
     ) {
-        Ok((_, _, _, _)) => {
+        Ok((_, _, _)) => {
             info!(log, "finished running server");
         }
         Err(err) => {
@@ -251,3 +770,678 @@ pub async fn run_server(
 
     Ok(())
 }
+
+/// Least time between the passes of a looping replay, so a short recording or one replayed as fast
+/// as possible does not flood the clients.
+const MIN_LOOP_DELAY: Duration = Duration::from_millis(100);
+
+/// Sends the recorded summaries to the senders of their symbols, keeping the time between them
+/// according to the `pace`, until the last one or forever when `looping`.
+/// Each pass starts after the average time between the summaries, at least `MIN_LOOP_DELAY`, and
+/// it fails when a pass has no summary of the served symbols.
+/// It returns on the shutdown signal, keeping the senders so the clients are not disconnected.
+async fn replay_summaries(
+    log: Logger,
+    shutdown_receiver: tokio::sync::broadcast::Receiver<String>,
+    summary_senders: HashMap<Symbol, UnboundedSender<types::Summary>>,
+    config: ReplayConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut shutdown_receiver = shutdown_receiver;
+    loop {
+        let mut start: Option<(u64, Instant)> = None;
+        let mut last_timestamp_us = 0;
+        let mut replayed: u32 = 0;
+        for recorded in read_summaries(&config.directory)? {
+            let recorded = recorded?;
+            let (symbol, summary) = recorded.summary()?;
+
+            let (first_timestamp_us, started) = *start.get_or_insert((recorded.recorded_timestamp_us, Instant::now()));
+            let elapsed = Duration::from_micros(recorded.recorded_timestamp_us.saturating_sub(first_timestamp_us));
+            last_timestamp_us = recorded.recorded_timestamp_us;
+            tokio::select! {
+                _ = tokio::time::sleep_until(started + frame_delay(config.pace, elapsed)) => {}
+                _ = shutdown_receiver.recv() => {
+                    info!(log, "application is shutting down, closing replay_summaries");
+                    return Ok(());
+                }
+            }
+            if let Some(summary_sender) = summary_senders.get(&symbol) {
+                summary_sender.send(summary)?;
+                replayed += 1;
+            }
+        }
+        info!(log, "replayed the recorded summaries"; "summaries" => replayed);
+        if replayed == 0 {
+            return Err(format!(
+                "no recorded summary of the served symbols in {}", config.directory.display(),
+            ).into());
+        }
+        if !config.looping {
+            break;
+        }
+
+        let first_timestamp_us = start.map(|(first_timestamp_us, _)| first_timestamp_us).unwrap_or_default();
+        let gap = Duration::from_micros(last_timestamp_us.saturating_sub(first_timestamp_us)) / (replayed - 1).max(1);
+        tokio::select! {
+            _ = tokio::time::sleep(frame_delay(config.pace, gap).max(MIN_LOOP_DELAY)) => {}
+            _ = shutdown_receiver.recv() => {
+                info!(log, "application is shutting down, closing replay_summaries");
+                return Ok(());
+            }
+        }
+    }
+
+    let _ = shutdown_receiver.recv().await;
+    info!(log, "application is shutting down, closing replay_summaries");
+    Ok(())
+}
+
+/// Serves the summaries recorded by the `SummaryRecorder` with the same gRPC service as
+/// `run_server`, so the clients get the same stream they got when it was recorded.
+/// The symbols, exchanges and depth served are the ones found in the recorded summaries.
+pub async fn run_replay(
+    log: Logger,
+    shutdown_sender: tokio::sync::broadcast::Sender<String>,
+    config: ReplayConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut symbols: Vec<Symbol> = Vec::new();
+    let mut exchanges: Vec<String> = Vec::new();
+    let mut depth = 0;
+    for recorded in read_summaries(&config.directory)? {
+        let (symbol, summary) = recorded?.summary()?;
+        if !symbols.contains(&symbol) {
+            symbols.push(symbol);
+        }
+        for side in [&summary.bids, &summary.asks] {
+            let mut levels: HashMap<&str, usize> = HashMap::new();
+            for level in side {
+                *levels.entry(&level.exchange).or_default() += 1;
+                if !exchanges.contains(&level.exchange) {
+                    exchanges.push(level.exchange.clone());
+                }
+            }
+            depth = levels.into_values().fold(depth, usize::max);
+        }
+    }
+    if symbols.is_empty() {
+        return Err(format!("no recorded summaries in {}", config.directory.display()).into());
+    }
+    info!(
+        log, "replaying summaries";
+        "symbols" => format!("{:?}", symbols.iter().map(|x| x.to_string()).collect::<Vec<_>>()),
+        "exchanges" => format!("{:?}", exchanges), "depth" => depth
+    );
+
+    let mut summary_senders = HashMap::with_capacity(symbols.len());
+    let mut grpc_receivers = Vec::with_capacity(symbols.len());
+    for symbol in symbols {
+        let (grpc_sender, grpc_receiver) = mpsc::unbounded_channel();
+        summary_senders.insert(symbol.clone(), grpc_sender);
+        grpc_receivers.push((symbol, grpc_receiver));
+    }
+
+    let grpc_config = GrpcConfig {
+        address: config.address.clone(),
+        depth,
+        exchanges,
+        slow_client_policy: config.slow_client_policy,
+        summary_recorder: None,
+    };
+    let replay_shutdown_receiver = shutdown_sender.subscribe();
+    match tokio::try_join!(
+        replay_summaries(log.clone(), replay_shutdown_receiver, summary_senders, config),
+        run_grpc_server(log.clone(), shutdown_sender, grpc_receivers, grpc_config),
+    ) {
+        Ok((_, _)) => {
+            info!(log, "finished running replay");
+        }
+        Err(err) => {
+            error!(log, "a problem occurred"; "error" => format!("{:?}", err));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        client::{
+            run_arbitrage_client_with,
+            run_client_with,
+            run_delta_client_with,
+            SummaryRequest,
+        },
+        orderbook::{
+            BookSummaryRequest,
+            Summary,
+        },
+        recorder::SummaryRecorder,
+        replay::ReplayPace,
+        orderbook::orderbook_aggregator_server::OrderbookAggregator,
+        server::{
+            replay_summaries,
+            run_replay,
+            ClientSubscription,
+            LatestSummary,
+            OrderbookAggregatorImpl,
+            ReplayConfig,
+            SlowClientPolicy,
+            SummaryUpdate,
+        },
+        types::{
+            self,
+            Asset,
+            Level,
+            SummaryOptions,
+            Symbol,
+        },
+    };
+    use rust_decimal_macros::dec;
+    use std::{
+        collections::HashMap,
+        path::{
+            Path,
+            PathBuf,
+        },
+        time::Duration,
+    };
+    use tokio::sync::{
+        broadcast,
+        mpsc,
+        watch,
+        Mutex,
+    };
+    use tonic::Code;
+
+    fn orderbook() -> OrderbookAggregatorImpl {
+        let (clients_to_connect_sender, _) = mpsc::channel(1);
+        OrderbookAggregatorImpl::new(
+            slog::Logger::root(slog::Discard, slog::o!()),
+            clients_to_connect_sender,
+            vec![
+                Symbol { base: Asset::ETH, quote: Asset::BTC },
+                Symbol { base: Asset::BTC, quote: Asset::USDT },
+            ],
+            10,
+            vec!["binance".to_string(), "bitstamp".to_string()],
+            SlowClientPolicy::Conflate,
+            HashMap::new(),
+        )
+    }
+
+    fn latest_summaries(latest: LatestSummary) -> HashMap<Symbol, watch::Receiver<LatestSummary>> {
+        let (latest_sender, latest_receiver) = watch::channel(latest);
+        // Keeping the latest summary after the sender is gone
+        drop(latest_sender);
+        HashMap::from([(Symbol { base: Asset::ETH, quote: Asset::BTC }, latest_receiver)])
+    }
+
+    fn merged_summary() -> types::Summary {
+        let level = |exchange: &str, price: &str| Level::parse(exchange, &[price.to_string(), "1".to_string()]).unwrap();
+        types::Summary::from_levels(
+            vec![level("bitstamp", "0.0676"), level("binance", "0.0675"), level("binance", "0.0674")],
+            vec![level("binance", "0.0677")],
+        )
+    }
+
+    fn update(sequence: u64) -> Option<SummaryUpdate> {
+        Some(SummaryUpdate {
+            sequence,
+            summary: Summary {
+                spread: sequence as f64,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Publishes the summaries giving a chance to `forward_summaries` to run after each one.
+    async fn publish(summary_sender: &watch::Sender<Option<SummaryUpdate>>, sequences: std::ops::RangeInclusive<u64>) {
+        for sequence in sequences {
+            summary_sender.send(update(sequence)).unwrap();
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn should_use_server_defaults_for_empty_request() {
+        // Given
+        let request = BookSummaryRequest::default();
+
+        // When
+        let resp = orderbook().summary_options(request);
+
+        // Then
+        assert_eq!(
+            Ok(SummaryOptions {
+                symbol: Symbol { base: Asset::ETH, quote: Asset::BTC },
+                depth: 10,
+                exchanges: Vec::new(),
+                aggregated: false,
+                bucket_size: None,
+                exchange_depth: 10,
+            }),
+            resp,
+        );
+    }
+
+    #[test]
+    fn should_accept_served_symbol_and_exchanges() {
+        // Given
+        let request = BookSummaryRequest {
+            symbol: "BTC/usdt".to_string(),
+            depth: 5,
+            exchanges: vec!["Binance".to_string()],
+            aggregated: true,
+            bucket_size: "0.0001".to_string(),
+        };
+
+        // When
+        let resp = orderbook().summary_options(request);
+
+        // Then
+        assert_eq!(
+            Ok(SummaryOptions {
+                symbol: Symbol { base: Asset::BTC, quote: Asset::USDT },
+                depth: 5,
+                exchanges: vec!["binance".to_string()],
+                aggregated: true,
+                bucket_size: Some(dec!(0.0001)),
+                exchange_depth: 10,
+            }),
+            resp,
+        );
+    }
+
+    #[test]
+    fn should_reject_unknown_symbol_and_exchange() {
+        // Given
+        let invalid_symbol = BookSummaryRequest {
+            symbol: "eth/xyz".to_string(),
+            ..Default::default()
+        };
+        let not_served_symbol = BookSummaryRequest {
+            symbol: "eth/usdt".to_string(),
+            ..Default::default()
+        };
+        let unknown_exchange = BookSummaryRequest {
+            exchanges: vec!["mtgox".to_string()],
+            ..Default::default()
+        };
+        let invalid_bucket_size = BookSummaryRequest {
+            bucket_size: "-0.01".to_string(),
+            ..Default::default()
+        };
+
+        // When
+        let orderbook = orderbook();
+        let resp = vec![
+            orderbook.summary_options(invalid_symbol),
+            orderbook.summary_options(not_served_symbol),
+            orderbook.summary_options(unknown_exchange),
+            orderbook.summary_options(invalid_bucket_size),
+        ];
+
+        // Then
+        assert!(resp.iter().all(|x| x.is_err()));
+    }
+
+    #[tokio::test]
+    async fn should_conflate_summaries_for_a_slow_client() {
+        // Given
+        let (client_sender, mut client_receiver) = mpsc::channel(1);
+        let (summary_sender, summary_receiver) = watch::channel(None);
+        tokio::spawn(OrderbookAggregatorImpl::forward_summaries(
+            slog::Logger::root(slog::Discard, slog::o!()),
+            summary_receiver, client_sender, SlowClientPolicy::Conflate, Some,
+        ));
+
+        // When
+        publish(&summary_sender, 1..=10).await;
+        let mut resp = Vec::new();
+        for _ in 0..2 {
+            resp.push(client_receiver.recv().await.unwrap().unwrap().spread);
+        }
+
+        // Then
+        assert_eq!(vec![1.0, 10.0], resp);
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_a_client_lagging_behind() {
+        // Given
+        let (client_sender, mut client_receiver) = mpsc::channel(1);
+        let (summary_sender, summary_receiver) = watch::channel(None);
+        tokio::spawn(OrderbookAggregatorImpl::forward_summaries(
+            slog::Logger::root(slog::Discard, slog::o!()),
+            summary_receiver, client_sender, SlowClientPolicy::Disconnect { max_lag: 2 }, Some,
+        ));
+
+        // When
+        publish(&summary_sender, 1..=5).await;
+
+        // Then
+        assert_eq!(1.0, client_receiver.recv().await.unwrap().unwrap().spread);
+        let status = client_receiver.recv().await.unwrap().unwrap_err();
+        assert_eq!(Code::ResourceExhausted, status.code());
+        assert!(client_receiver.recv().await.is_none());
+        assert!(summary_sender.is_closed());
+    }
+
+    #[test]
+    fn should_parse_slow_client_policy() {
+        // Given
+        let names = vec!["Conflate", "disconnect", "block"];
+
+        // When
+        let resp: Vec<_> = names.into_iter().map(|name| SlowClientPolicy::new(name, 10)).collect();
+
+        // Then
+        assert_eq!(SlowClientPolicy::Conflate, *resp[0].as_ref().unwrap());
+        assert_eq!(SlowClientPolicy::Disconnect { max_lag: 10 }, *resp[1].as_ref().unwrap());
+        assert!(resp[2].is_err());
+    }
+
+    /// Records the `summaries` of ETH/BTC in a new temporary directory.
+    fn record_summaries(summaries: &[types::Summary]) -> PathBuf {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let directory = std::env::temp_dir().join(format!("mbooks-replay-{}", rand::random::<u64>()));
+        let (recorder, handle) = SummaryRecorder::start(log, &directory, 1024 * 1024).unwrap();
+        let symbol = Symbol { base: Asset::ETH, quote: Asset::BTC };
+        for summary in summaries {
+            recorder.record(&symbol, summary);
+        }
+        drop(recorder);
+        handle.join().unwrap();
+        directory
+    }
+
+    fn replay_config(directory: &Path, pace: ReplayPace) -> ReplayConfig {
+        ReplayConfig {
+            address: "127.0.0.1:0".to_string(),
+            directory: directory.to_path_buf(),
+            pace,
+            looping: true,
+            slow_client_policy: SlowClientPolicy::Conflate,
+        }
+    }
+
+    /// RecordedServer is a `run_replay` server looping over recorded summaries, for the clients to
+    /// connect to it.
+    struct RecordedServer {
+        log: slog::Logger,
+        address: String,
+        directory: PathBuf,
+        shutdown_sender: broadcast::Sender<String>,
+        replay: std::thread::JoinHandle<bool>,
+    }
+
+    impl RecordedServer {
+        fn url(&self) -> String {
+            format!("http://{}", self.address)
+        }
+
+        /// Stops the server, returning if it ended without errors.
+        async fn stop(self) -> bool {
+            let _ = self.shutdown_sender.send("stopping the recorded server".to_string());
+            let replay = self.replay;
+            let resp = tokio::task::spawn_blocking(move || replay.join()).await;
+            std::fs::remove_dir_all(&self.directory).unwrap();
+            matches!(resp, Ok(Ok(true)))
+        }
+    }
+
+    /// Serves the `summaries` of ETH/BTC with `run_replay`, once it accepts connections.
+    async fn serve_recorded(summaries: &[types::Summary]) -> RecordedServer {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let directory = record_summaries(summaries);
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let config = ReplayConfig {
+            address: address.clone(),
+            ..replay_config(&directory, ReplayPace::Scaled(1.0))
+        };
+        let (shutdown_sender, _) = broadcast::channel(10);
+        // The replay is not `Send`, so it runs on its own thread and runtime
+        let replay = {
+            let (log, shutdown_sender) = (log.clone(), shutdown_sender.clone());
+            std::thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+                runtime.block_on(run_replay(log, shutdown_sender, config)).is_ok()
+            })
+        };
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(&address).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        RecordedServer { log, address, directory, shutdown_sender, replay }
+    }
+
+    #[tokio::test]
+    async fn should_pause_between_the_passes_of_a_looping_replay() {
+        // Given
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let level = |price: &str| Level::parse("binance", &[price.to_string(), "1".to_string()]).unwrap();
+        let directory = record_summaries(&[types::Summary::from_levels(vec![level("0.0675")], vec![level("0.0676")])]);
+        let (summary_sender, mut summary_receiver) = mpsc::unbounded_channel();
+        let summary_senders = HashMap::from([(Symbol { base: Asset::ETH, quote: Asset::BTC }, summary_sender)]);
+        let (shutdown_sender, _) = broadcast::channel(10);
+
+        // When
+        let replay = replay_summaries(
+            log, shutdown_sender.subscribe(), summary_senders, replay_config(&directory, ReplayPace::AsFastAsPossible),
+        );
+        let shutdown = async {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            shutdown_sender.send("stop replaying".to_string()).unwrap();
+        };
+        let resp = tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(replay, shutdown).0 }).await;
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // Then
+        assert!(resp.unwrap().is_ok());
+        let mut replayed = 0;
+        while summary_receiver.try_recv().is_ok() {
+            replayed += 1;
+        }
+        assert!((2..=4).contains(&replayed), "replayed {} summaries", replayed);
+    }
+
+    #[tokio::test]
+    async fn should_fail_replaying_no_summary() {
+        // Given
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let level = |price: &str| Level::parse("binance", &[price.to_string(), "1".to_string()]).unwrap();
+        let directory = record_summaries(&[types::Summary::from_levels(vec![level("0.0675")], vec![level("0.0676")])]);
+        let (shutdown_sender, _) = broadcast::channel::<String>(10);
+
+        // When
+        let resp = tokio::time::timeout(Duration::from_secs(5), replay_summaries(
+            log, shutdown_sender.subscribe(), HashMap::new(), replay_config(&directory, ReplayPace::AsFastAsPossible),
+        )).await;
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // Then
+        assert!(resp.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn should_serve_recorded_summaries() {
+        // Given
+        let level = |exchange: &str, price: &str| Level::parse(exchange, &[price.to_string(), "1".to_string()]).unwrap();
+        let server = serve_recorded(&[types::Summary::from_levels(
+            vec![level("binance", "0.06754400"), level("bitstamp", "0.0675")],
+            vec![level("bitstamp", "0.06754500")],
+        )]).await;
+
+        // When
+        let mut replayed = None;
+        let resp = run_client_with(
+            server.log.clone(), &mut server.shutdown_sender.subscribe(), server.url(),
+            SummaryRequest { depth: 1, ..Default::default() },
+            |summary| {
+                replayed = Some(summary.clone());
+                let _ = server.shutdown_sender.send("got the replayed summary".to_string());
+            },
+        ).await;
+
+        // Then
+        assert!(resp.is_ok());
+        assert!(server.stop().await);
+        let replayed = replayed.unwrap();
+        assert_eq!(1, replayed.bids.len());
+        assert_eq!("0.06754400", replayed.bids[0].exact_price);
+        assert_eq!("bitstamp", replayed.asks[0].exchange);
+        assert_eq!("0.00000100", replayed.exact_spread);
+    }
+
+    #[tokio::test]
+    async fn should_get_book_snapshot() {
+        // Given
+        let (clients_to_connect_sender, _) = mpsc::channel(1);
+        let orderbook = OrderbookAggregatorImpl::new(
+            slog::Logger::root(slog::Discard, slog::o!()),
+            clients_to_connect_sender,
+            vec![
+                Symbol { base: Asset::ETH, quote: Asset::BTC },
+                Symbol { base: Asset::BTC, quote: Asset::USDT },
+            ],
+            10,
+            vec!["binance".to_string(), "bitstamp".to_string()],
+            SlowClientPolicy::Conflate,
+            latest_summaries((3, merged_summary())),
+        );
+        let request = |symbol: &str, depth: u32, exchanges: Vec<String>| tonic::Request::new(BookSummaryRequest {
+            symbol: symbol.to_string(),
+            depth,
+            exchanges,
+            ..Default::default()
+        });
+
+        // When
+        let filtered = orderbook.get_book_snapshot(request("eth/btc", 1, vec!["binance".to_string()])).await;
+        let without_updates = orderbook.get_book_snapshot(request("btc/usdt", 0, vec![])).await;
+        let invalid = orderbook.get_book_snapshot(request("eth/usdt", 0, vec![])).await;
+
+        // Then
+        let filtered = filtered.unwrap().into_inner();
+        assert_eq!(vec!["0.0675"], filtered.bids.iter().map(|x| x.exact_price.as_str()).collect::<Vec<_>>());
+        assert_eq!(vec!["0.0677"], filtered.asks.iter().map(|x| x.exact_price.as_str()).collect::<Vec<_>>());
+        assert_eq!("0.0002", filtered.exact_spread);
+        let without_updates = without_updates.unwrap().into_inner();
+        assert!(without_updates.bids.is_empty() && without_updates.asks.is_empty());
+        assert_eq!(tonic::Code::InvalidArgument, invalid.unwrap_err().code());
+    }
+
+    #[tokio::test]
+    async fn should_start_new_clients_from_the_latest_summary() {
+        // Given
+        let targets = Mutex::new(Vec::new());
+        let (clients_to_connect_sender, clients_to_connect_receiver) = mpsc::channel(1);
+        let (summary_sender, summary_receiver) = watch::channel(None);
+        let client = ClientSubscription {
+            sender: summary_sender,
+            options: SummaryOptions {
+                symbol: Symbol { base: Asset::ETH, quote: Asset::BTC },
+                depth: 1,
+                exchanges: Vec::new(),
+                aggregated: false,
+                bucket_size: None,
+                exchange_depth: 10,
+            },
+        };
+        let (_shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+
+        // When
+        clients_to_connect_sender.send(client).await.unwrap();
+        drop(clients_to_connect_sender);
+        OrderbookAggregatorImpl::listen_clients_to_connect(
+            slog::Logger::root(slog::Discard, slog::o!()),
+            shutdown_receiver, &targets, clients_to_connect_receiver,
+            latest_summaries((7, merged_summary())),
+        ).await.unwrap();
+
+        // Then
+        assert_eq!(1, targets.lock().await.len());
+        let snapshot = summary_receiver.borrow().clone().unwrap();
+        assert_eq!(7, snapshot.sequence);
+        assert_eq!(1, snapshot.summary.bids.len());
+        assert_eq!("0.0676", snapshot.summary.bids[0].exact_price);
+    }
+
+    #[tokio::test]
+    async fn should_not_count_summaries_before_subscribing_as_lag() {
+        // Given
+        let (client_sender, mut client_receiver) = mpsc::channel(1);
+        let (summary_sender, summary_receiver) = watch::channel(None);
+        tokio::spawn(OrderbookAggregatorImpl::forward_summaries(
+            slog::Logger::root(slog::Discard, slog::o!()),
+            summary_receiver, client_sender, SlowClientPolicy::Disconnect { max_lag: 2 }, Some,
+        ));
+
+        // When
+        publish(&summary_sender, 10..=11).await;
+
+        // Then
+        assert_eq!(10.0, client_receiver.recv().await.unwrap().unwrap().spread);
+        assert_eq!(11.0, client_receiver.recv().await.unwrap().unwrap().spread);
+    }
+
+    #[tokio::test]
+    async fn should_stream_the_book_as_deltas() {
+        // Given
+        let level = |exchange: &str, price: &str, amount: &str| Level::parse(exchange, &[price.to_string(), amount.to_string()]).unwrap();
+        let recorded = types::Summary::from_levels(
+            vec![level("binance", "0.0676", "2"), level("binance", "0.0675", "3")],
+            vec![level("bitstamp", "0.0677", "1")],
+        );
+        let server = serve_recorded(std::slice::from_ref(&recorded)).await;
+        let expected: Summary = recorded.into();
+
+        // When
+        let mut reassembled = None;
+        let resp = run_delta_client_with(
+            server.log.clone(), &mut server.shutdown_sender.subscribe(), server.url(), SummaryRequest::default(),
+            |summary| {
+                reassembled = Some(summary.clone());
+                let _ = server.shutdown_sender.send("got the reassembled summary".to_string());
+            },
+        ).await;
+
+        // Then
+        assert!(resp.is_ok());
+        assert!(server.stop().await);
+        assert_eq!(Some(expected), reassembled);
+    }
+
+    #[tokio::test]
+    async fn should_stream_arbitrage_opportunities() {
+        // Given
+        let level = |exchange: &str, price: &str, amount: &str| Level::parse(exchange, &[price.to_string(), amount.to_string()]).unwrap();
+        let server = serve_recorded(&[types::Summary::from_levels(
+            vec![level("binance", "0.0680", "1"), level("bitstamp", "0.0675", "2")],
+            vec![level("bitstamp", "0.0677", "3"), level("binance", "0.0681", "1")],
+        )]).await;
+
+        // When
+        let mut update = None;
+        let resp = run_arbitrage_client_with(
+            server.log.clone(), &mut server.shutdown_sender.subscribe(), server.url(), None,
+            |arbitrage| {
+                update = Some(arbitrage.clone());
+                let _ = server.shutdown_sender.send("got an arbitrage update".to_string());
+            },
+        ).await;
+
+        // Then
+        assert!(resp.is_ok());
+        assert!(server.stop().await);
+        let opportunities = update.unwrap().opportunities;
+        assert_eq!(1, opportunities.len());
+        assert_eq!("bitstamp", opportunities[0].buy_exchange);
+        assert_eq!("binance", opportunities[0].sell_exchange);
+        assert_eq!("1", opportunities[0].exact_quantity);
+        assert_eq!("0.0003", opportunities[0].exact_profit);
+    }
+}
@@ -0,0 +1,508 @@
+use crate::{
+    binance::Binance,
+    bitstamp::Bitstamp,
+    bybit::Bybit,
+    coinbase::Coinbase,
+    kraken::Kraken,
+    kucoin::Kucoin,
+    okx::Okx,
+    recorder::FeedRecorder,
+    types::{
+        BookUpdate,
+        MBooksError,
+        Summary,
+        Symbol,
+    },
+};
+use rand::Rng;
+use slog::{
+    error,
+    info,
+    Logger,
+    o,
+    warn,
+};
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
+use tokio::sync::{
+    broadcast,
+    mpsc::UnboundedSender,
+};
+
+/// Delay before the first reconnection attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Maximum delay between reconnection attempts, a connection lasting longer than it resets the backoff.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// BookMode is how the `Source` gets the book from the exchange.
+/// A source that supports only one of the modes ignores it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BookMode {
+    /// Every message has the top levels of the book, replacing the previous ones.
+    Snapshot,
+    /// The book is fetched once and then kept locally applying the incremental updates.
+    Diff,
+}
+
+impl TryFrom<&str> for BookMode {
+    type Error = MBooksError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "snapshot" => Ok(BookMode::Snapshot),
+            "diff" => Ok(BookMode::Diff),
+            _ => Err(MBooksError::InvalidBookMode(value.to_string())),
+        }
+    }
+}
+
+/// Base URLs a `Source` uses to reach its exchange.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Endpoint {
+    pub websocket: String,
+    pub rest: String,
+}
+
+/// Endpoints keeps the URLs replacing the default ones of each exchange, so the sources can connect
+/// to a local mock server, including plain `ws://` and `http://` ones.
+#[derive(Clone, Debug, Default)]
+pub struct Endpoints {
+    websocket: HashMap<String, String>,
+    rest: HashMap<String, String>,
+}
+
+impl Endpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the WebSocket URL of an exchange, in the format `exchange=url`.
+    pub fn insert_websocket(&mut self, value: &str) -> Result<(), MBooksError> {
+        let (exchange, url) = Self::parse(value)?;
+        self.websocket.insert(exchange, url);
+        Ok(())
+    }
+
+    /// Replaces the REST URL of an exchange, in the format `exchange=url`.
+    pub fn insert_rest(&mut self, value: &str) -> Result<(), MBooksError> {
+        let (exchange, url) = Self::parse(value)?;
+        self.rest.insert(exchange, url);
+        Ok(())
+    }
+
+    fn parse(value: &str) -> Result<(String, String), MBooksError> {
+        let (exchange, url) = value.split_once('=')
+            .ok_or_else(|| MBooksError::InvalidEndpoint(value.to_string()))?;
+        url::Url::parse(url).map_err(|_| MBooksError::InvalidEndpoint(value.to_string()))?;
+        Ok((exchange.to_lowercase(), url.trim_end_matches('/').to_string()))
+    }
+
+    /// WebSocket URL replacing the default one of the exchange.
+    pub fn websocket(&self, exchange: &str) -> Option<&str> {
+        self.websocket.get(exchange).map(String::as_str)
+    }
+
+    /// REST URL replacing the default one of the exchange.
+    pub fn rest(&self, exchange: &str) -> Option<&str> {
+        self.rest.get(exchange).map(String::as_str)
+    }
+
+    /// Endpoint of the exchange, using the given defaults for the URLs not replaced.
+    pub fn endpoint(&self, exchange: &str, websocket: &str, rest: &str) -> Endpoint {
+        Endpoint {
+            websocket: self.websocket(exchange).unwrap_or(websocket).to_string(),
+            rest: self.rest(exchange).unwrap_or(rest).to_string(),
+        }
+    }
+
+    /// Exchanges with any URL replaced.
+    pub fn exchanges(&self) -> impl Iterator<Item = &String> {
+        self.websocket.keys().chain(self.rest.keys())
+    }
+}
+
+/// Configuration used to create the `Source`s.
+#[derive(Clone, Debug)]
+pub struct SourceConfig {
+    /// Symbols to subscribe, the sources share a single connection for all of them when the
+    /// exchange supports it.
+    pub symbols: Vec<Symbol>,
+    pub depth: usize,
+    pub book_mode: BookMode,
+    pub endpoints: Endpoints,
+}
+
+/// SummarySenders keeps the channel to the `OrderbookMerger` of each symbol, and the optional
+/// `FeedRecorder` for the raw frames the sources receive.
+#[derive(Clone, Default)]
+pub struct SummarySenders {
+    senders: HashMap<Symbol, UnboundedSender<BookUpdate>>,
+    recorder: Option<FeedRecorder>,
+}
+
+impl SummarySenders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records every frame the sources receive.
+    pub fn with_recorder(mut self, recorder: FeedRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Records the raw frame received from the exchange, when recording.
+    pub fn record(&self, exchange: &str, received_timestamp: SystemTime, frame: &[u8]) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(exchange, received_timestamp, frame);
+        }
+    }
+
+    /// Adds the channel to the `OrderbookMerger` of the symbol.
+    pub fn insert(&mut self, symbol: Symbol, sender: UnboundedSender<BookUpdate>) {
+        self.senders.insert(symbol, sender);
+    }
+
+    /// Sends the summary to the `OrderbookMerger` of the symbol.
+    pub fn send(&self, symbol: &Symbol, summary: Summary) -> Result<(), MBooksError> {
+        let sender = self.senders.get(symbol)
+            .ok_or_else(|| MBooksError::InvalidPair(symbol.to_string()))?;
+        sender.send(summary.into())
+            .map_err(|_| MBooksError::ChannelClosed(symbol.to_string()))
+    }
+
+    /// Notifies every `OrderbookMerger` that the exchange was disconnected, returning if any of
+    /// them is still listening.
+    pub fn disconnected(&self, exchange: &str) -> bool {
+        let mut listening = false;
+        for sender in self.senders.values() {
+            listening |= sender.send(BookUpdate::Disconnected(exchange.to_string())).is_ok();
+        }
+        listening
+    }
+}
+
+/// Reason why the connection to the exchange has ended.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ConnectionEnd {
+    /// The application is shutting down, so the source must not reconnect.
+    Shutdown,
+    /// The exchange closed the stream or asked for a reconnection.
+    Closed,
+}
+
+/// Backoff generates the delays between reconnection attempts, doubling it at every attempt up to
+/// the maximum.
+/// Each delay is randomly reduced by up to a half, so the sources do not reconnect all at once.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Delay to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.initial
+            .checked_mul(2u32.saturating_pow(self.attempt))
+            .map_or(self.max, |delay| delay.min(self.max));
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// Starts again from the initial delay, used once a connection is healthy.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(INITIAL_BACKOFF, MAX_BACKOFF)
+    }
+}
+
+/// Source is an exchange connection that produces the updates consumed by the `OrderbookMerger`.
+/// New venues only need to implement this trait and be added to the `SourceRegistry`.
+#[tonic::async_trait(?Send)]
+pub trait Source {
+    /// Name of the exchange, used to tag every `Level` it produces.
+    fn name(&self) -> &'static str;
+
+    /// Translates the internal `Symbol` to the market format the exchange expects.
+    fn symbol_to_string(&self, symbol: &Symbol) -> String;
+
+    /// Names of the exchanges tagging the levels this source produces, only its own by default.
+    fn exchanges(&self) -> Vec<String> {
+        vec![self.name().to_string()]
+    }
+
+    /// Connects and subscribes to the exchange, sending every update to the `OrderbookMerger` of
+    /// its symbol until the connection is closed or a message arrives at the `shutdown_receiver`.
+    async fn connect(
+        &self,
+        log: &Logger,
+        shutdown_receiver: &mut broadcast::Receiver<String>,
+        summary_txs: &SummarySenders,
+    ) -> Result<ConnectionEnd, Box<dyn std::error::Error>>;
+
+    /// Keeps the source connected until the shutdown, reconnecting with a `Backoff` every time the
+    /// connection ends.
+    /// The `OrderbookMerger` is notified on every disconnection, so it can drop the levels from this
+    /// exchange while it is away.
+    async fn run(
+        &self,
+        log: Logger,
+        shutdown_receiver: broadcast::Receiver<String>,
+        summary_txs: SummarySenders,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let log = log.new(o!("exchange" => self.name()));
+        let mut shutdown_receiver = shutdown_receiver;
+        let mut backoff = Backoff::default();
+        loop {
+            let started = Instant::now();
+            match self.connect(&log, &mut shutdown_receiver, &summary_txs).await {
+                Ok(ConnectionEnd::Shutdown) => return Ok(()),
+                Ok(ConnectionEnd::Closed) => {
+                    warn!(log, "connection closed by the exchange");
+                }
+                Err(err) => {
+                    error!(log, "connection failed"; "error" => format!("{}", err));
+                }
+            }
+
+            let mut listening = false;
+            for exchange in self.exchanges() {
+                listening |= summary_txs.disconnected(&exchange);
+            }
+            if !listening {
+                info!(log, "mergers are gone, stopping source");
+                return Ok(());
+            }
+
+            if started.elapsed() > MAX_BACKOFF {
+                backoff.reset();
+            }
+            let delay = backoff.next_delay();
+            info!(log, "reconnecting"; "delay_ms" => delay.as_millis() as u64);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown_receiver.recv() => {
+                    info!(log, "application is shutting down, not reconnecting");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+pub type SourceBuilder = fn(SourceConfig) -> Box<dyn Source>;
+
+/// SourceRegistry keeps the available `Source`s by name, so it is possible to choose at startup
+/// which ones are going to run.
+pub struct SourceRegistry {
+    builders: Vec<(&'static str, SourceBuilder)>,
+}
+
+impl SourceRegistry {
+    /// Creates a registry without any source.
+    pub fn new() -> Self {
+        Self {
+            builders: Vec::new(),
+        }
+    }
+
+    /// Adds a new source, replacing any other registered with the same name.
+    pub fn register(&mut self, name: &'static str, builder: SourceBuilder) {
+        self.builders.retain(|(registered, _)| *registered != name);
+        self.builders.push((name, builder));
+    }
+
+    /// Names of the registered sources.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.builders.iter().map(|(name, _)| *name).collect()
+    }
+
+    /// Creates the sources with the given names.
+    /// It fails when an endpoint is replaced for an unknown source, since it is probably a typo.
+    pub fn build(
+        &self, names: &[String], config: &SourceConfig,
+    ) -> Result<Vec<Box<dyn Source>>, MBooksError> {
+        if let Some(exchange) = config.endpoints.exchanges()
+            .find(|exchange| !self.names().contains(&exchange.as_str())) {
+            return Err(MBooksError::InvalidSource(exchange.clone()));
+        }
+
+        let mut sources = Vec::with_capacity(names.len());
+        for name in names {
+            let name = name.to_lowercase();
+            let (_, builder) = self.builders.iter()
+                .find(|(registered, _)| *registered == name)
+                .ok_or_else(|| MBooksError::InvalidSource(name.clone()))?;
+            sources.push(builder(config.clone()));
+        }
+
+        Ok(sources)
+    }
+}
+
+impl Default for SourceRegistry {
+    /// Creates a registry with all the sources implemented in this crate.
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register("binance", |config| Box::new(Binance::new(config)));
+        registry.register("bitstamp", |config| Box::new(Bitstamp::new(config)));
+        registry.register("coinbase", |config| Box::new(Coinbase::new(config)));
+        registry.register("kraken", |config| Box::new(Kraken::new(config)));
+        registry.register("okx", |config| Box::new(Okx::new(config)));
+        registry.register("bybit", |config| Box::new(Bybit::new(config)));
+        registry.register("kucoin", |config| Box::new(Kucoin::new(config)));
+        registry
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        source::{
+            Backoff,
+            BookMode,
+            Endpoint,
+            Endpoints,
+            SourceConfig,
+            SourceRegistry,
+        },
+        types::{
+            Asset,
+            MBooksError,
+            Symbol,
+        },
+    };
+    use std::time::Duration;
+
+    fn config() -> SourceConfig {
+        SourceConfig {
+            symbols: vec![Symbol { base: Asset::ETH, quote: Asset::BTC }],
+            depth: 10,
+            book_mode: BookMode::Snapshot,
+            endpoints: Endpoints::new(),
+        }
+    }
+
+    #[test]
+    fn should_parse_book_mode() {
+        // Given
+        let values = vec!["Diff", "snapshot", "partial"];
+
+        // When
+        let resp: Vec<_> = values.into_iter().map(BookMode::try_from).collect();
+
+        // Then
+        assert_eq!(BookMode::Diff, *resp[0].as_ref().unwrap());
+        assert_eq!(BookMode::Snapshot, *resp[1].as_ref().unwrap());
+        assert!(resp[2].is_err());
+    }
+
+    #[test]
+    fn should_build_registered_sources() {
+        // Given
+        let registry = SourceRegistry::default();
+        let names = vec![
+            "bitstamp".to_string(), "Binance".to_string(), "coinbase".to_string(),
+            "kraken".to_string(), "okx".to_string(), "bybit".to_string(), "kucoin".to_string(),
+        ];
+
+        // When
+        let sources = registry.build(&names, &config()).unwrap();
+
+        // Then
+        let names: Vec<&str> = sources.iter().map(|x| x.name()).collect();
+        assert_eq!(vec!["bitstamp", "binance", "coinbase", "kraken", "okx", "bybit", "kucoin"], names);
+    }
+
+    #[test]
+    fn should_fail_to_build_unknown_source() {
+        // Given
+        let registry = SourceRegistry::default();
+        let names = vec!["binance".to_string(), "mtgox".to_string()];
+
+        // When
+        let sources = registry.build(&names, &config());
+
+        // Then
+        match sources {
+            Err(MBooksError::InvalidSource(name)) => assert_eq!("mtgox", name),
+            _ => panic!("expected an invalid source"),
+        }
+    }
+
+    #[test]
+    fn should_replace_endpoints() {
+        // Given
+        let mut endpoints = Endpoints::new();
+
+        // When
+        endpoints.insert_websocket("Binance=ws://localhost:9001/").unwrap();
+        endpoints.insert_rest("bitstamp=http://localhost:9002").unwrap();
+        let invalid = vec![endpoints.insert_websocket("ws://localhost:9001"), endpoints.insert_rest("binance=localhost")];
+
+        // Then
+        assert_eq!(
+            Endpoint { websocket: "ws://localhost:9001".to_string(), rest: "https://api.binance.com".to_string() },
+            endpoints.endpoint("binance", "wss://stream.binance.com:9443", "https://api.binance.com"),
+        );
+        assert_eq!(Some("http://localhost:9002"), endpoints.rest("bitstamp"));
+        assert_eq!(None, endpoints.websocket("bitstamp"));
+        assert!(invalid.iter().all(|resp| matches!(resp, Err(MBooksError::InvalidEndpoint(_)))));
+    }
+
+    #[test]
+    fn should_fail_to_build_with_endpoint_of_unknown_source() {
+        // Given
+        let registry = SourceRegistry::default();
+        let mut config = config();
+        config.endpoints.insert_websocket("binanse=ws://localhost:9001").unwrap();
+
+        // When
+        let sources = registry.build(&["binance".to_string()], &config);
+
+        // Then
+        match sources {
+            Err(MBooksError::InvalidSource(name)) => assert_eq!("binanse", name),
+            _ => panic!("expected an invalid source"),
+        }
+    }
+
+    #[test]
+    fn should_increase_backoff_up_to_the_max() {
+        // Given
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+
+        // When
+        let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay()).collect();
+        backoff.reset();
+        let after_reset = backoff.next_delay();
+
+        // Then
+        for (delay, expected) in delays.iter().zip(vec![100, 200, 400, 800, 1000, 1000]) {
+            assert!(*delay <= Duration::from_millis(expected));
+            assert!(*delay >= Duration::from_millis(expected / 2));
+        }
+        assert!(after_reset <= Duration::from_millis(100));
+    }
+}
@@ -1,17 +1,37 @@
 use crate::orderbook;
+use rust_decimal::{
+    prelude::ToPrimitive,
+    Decimal,
+};
 use std::{
+    collections::HashMap,
     fmt::{
         Display,
         Formatter,
     },
-    num::ParseFloatError,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
 };
 
 #[derive(Debug)]
 pub enum MBooksError {
+    ChannelClosed(String),
+    ExchangeError(String),
     InvalidAsset(String),
+    InvalidBookMode(String),
+    InvalidEndpoint(String),
+    InvalidFee(String),
+    InvalidLevel(Vec<String>),
     InvalidPair(String),
-    ParseError(ParseFloatError),
+    InvalidReplaySpeed(String),
+    InvalidSlowClientPolicy(String),
+    InvalidSource(String),
+    InvalidTimestamp(String),
+    ParseError(rust_decimal::Error),
+    SequenceGap(String),
 }
 
 impl Display for MBooksError {
@@ -24,7 +44,7 @@ impl std::error::Error for MBooksError {}
 
 /// Asset is designed to keep the supported assets.
 /// It avoids problems with typos, or configuring an unsupported asset in the market.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Asset {
     ADA,
@@ -65,12 +85,21 @@ impl ToString for Asset {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Symbol {
     pub base: Asset,
     pub quote: Asset,
 }
 
+impl Display for Symbol {
+    /// Formats in the same format it is parsed, e.g. `eth/btc`.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "{}/{}", self.base.to_string().to_lowercase(), self.quote.to_string().to_lowercase(),
+        )
+    }
+}
+
 impl TryFrom<String> for Symbol {
     type Error = MBooksError;
 
@@ -89,11 +118,119 @@ impl TryFrom<String> for Symbol {
 /// Level internal representation of a level abstracts from the gRPC format used in the messages.
 /// The gRPC format is the API that may change, so having this separated will not require a change
 /// in the internal logic or representation in case the API needs to be updated.
-#[derive(PartialEq, Clone, Debug)]
+/// The price and quantity are kept as the exact decimals sent by the exchange.
+#[derive(PartialEq, Clone, Debug, Default)]
 pub struct Level {
     pub exchange: String,
-    pub price: f64,
-    pub quantity: f64,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    /// When the exchange generated the update, if the exchange sends it.
+    pub exchange_timestamp: Option<SystemTime>,
+    /// When the update was received from the exchange.
+    pub received_timestamp: Option<SystemTime>,
+    /// The levels of each exchange when this one aggregates them, empty otherwise.
+    pub breakdown: Vec<Level>,
+    /// Taker fee of the exchange, as a fraction of the price, when merging with the fee-adjusted prices.
+    pub fee: Decimal,
+}
+
+impl Level {
+    /// Parses a level in the format `[price, quantity, ...]` used by the exchanges.
+    pub fn parse(exchange: &str, level: &[String]) -> Result<Self, MBooksError> {
+        if level.len() < 2 {
+            return Err(MBooksError::InvalidLevel(level.to_vec()));
+        }
+        Ok(Self {
+            exchange: exchange.to_string(),
+            price: level[0].parse::<Decimal>().map_err(MBooksError::ParseError)?,
+            quantity: level[1].parse::<Decimal>().map_err(MBooksError::ParseError)?,
+            ..Default::default()
+        })
+    }
+
+    /// Price paying the taker `fee` to take this level, given the `multiplier` used to sort its side,
+    /// -1 for the bids and 1 for the asks: bid × (1 − fee) and ask × (1 + fee).
+    pub fn adjusted_price(&self, multiplier: Decimal) -> Decimal {
+        if self.fee.is_zero() {
+            return self.price;
+        }
+        self.price * (Decimal::ONE + multiplier * self.fee)
+    }
+
+    /// Rounds the prices outward to a multiple of `bucket_size`, up for the asks and down for the
+    /// bids, adding the quantities of the levels of each exchange in the same bucket.
+    /// The levels must be sorted by price, the bucketed ones have no fee since it is not their price
+    /// that is paid.
+    pub fn bucket<'a>(levels: impl Iterator<Item = &'a Level>, bucket_size: Decimal, round_up: bool) -> Vec<Level> {
+        let mut bucketed: Vec<Level> = Vec::new();
+        // Where the levels of the current bucket start
+        let mut bucket_start = 0;
+        for level in levels {
+            let price = Self::bucket_price(level.price, bucket_size, round_up);
+            if bucketed.last().map(|last| last.price) != Some(price) {
+                bucket_start = bucketed.len();
+            }
+
+            match bucketed[bucket_start..].iter_mut().find(|x| x.exchange == level.exchange) {
+                Some(same_exchange) => {
+                    same_exchange.quantity += level.quantity;
+                    same_exchange.exchange_timestamp = same_exchange.exchange_timestamp.max(level.exchange_timestamp);
+                    same_exchange.received_timestamp = same_exchange.received_timestamp.max(level.received_timestamp);
+                }
+                None => bucketed.push(Level {
+                    price,
+                    fee: Decimal::ZERO,
+                    ..level.clone()
+                }),
+            }
+        }
+        bucketed
+    }
+
+    /// Rounds the `price` to a multiple of `bucket_size`, up or down.
+    fn bucket_price(price: Decimal, bucket_size: Decimal, round_up: bool) -> Decimal {
+        let buckets = price / bucket_size;
+        (if round_up { buckets.ceil() } else { buckets.floor() }) * bucket_size
+    }
+
+    /// Aggregates the consecutive levels with the same price, adding their quantities and keeping
+    /// the levels of each exchange in the `breakdown`.
+    /// The aggregated levels have no fee, each exchange keeps its own in the `breakdown`.
+    pub fn aggregate<'a>(levels: impl Iterator<Item = &'a Level>) -> Vec<Level> {
+        let mut aggregated: Vec<Level> = Vec::new();
+        for level in levels {
+            match aggregated.last_mut() {
+                Some(last) if last.price == level.price => {
+                    last.exchange = format!("{},{}", last.exchange, level.exchange);
+                    last.quantity += level.quantity;
+                    last.exchange_timestamp = last.exchange_timestamp.max(level.exchange_timestamp);
+                    last.received_timestamp = last.received_timestamp.max(level.received_timestamp);
+                    last.breakdown.push(level.clone());
+                }
+                _ => aggregated.push(Level {
+                    breakdown: vec![level.clone()],
+                    fee: Decimal::ZERO,
+                    ..level.clone()
+                }),
+            }
+        }
+        aggregated
+    }
+}
+
+/// Microseconds since the epoch used in the gRPC messages, 0 when unknown.
+pub(crate) fn timestamp_us(timestamp: Option<SystemTime>) -> u64 {
+    timestamp
+        .and_then(|timestamp| timestamp.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |timestamp| timestamp.as_micros() as u64)
+}
+
+/// Inverse of `timestamp_us`, `None` for 0.
+pub(crate) fn from_timestamp_us(timestamp_us: u64) -> Option<SystemTime> {
+    match timestamp_us {
+        0 => None,
+        timestamp_us => Some(UNIX_EPOCH + Duration::from_micros(timestamp_us)),
+    }
 }
 
 #[allow(clippy::from_over_into)]
@@ -101,35 +238,157 @@ impl Into<orderbook::Level> for &Level {
     fn into(self) -> orderbook::Level {
         orderbook::Level {
             exchange: self.exchange.clone(),
-            price: self.price,
-            amount: self.quantity,
+            price: self.price.to_f64().unwrap_or(f64::NAN),
+            amount: self.quantity.to_f64().unwrap_or(f64::NAN),
+            exact_price: self.price.to_string(),
+            exact_amount: self.quantity.to_string(),
+            exchange_timestamp_us: timestamp_us(self.exchange_timestamp),
+            received_timestamp_us: timestamp_us(self.received_timestamp),
+            breakdown: self.breakdown.iter().map(|x| x.into()).collect(),
+            exact_fee: if self.fee.is_zero() { String::new() } else { self.fee.to_string() },
         }
     }
 }
 
 /// Summary is the internal implementation of the summary that is going to me sent to the gRPC server.
 /// It is implemented in separated message so it can be decoupled from the gRPC interface.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Summary {
     pub bids: Vec<Level>,
     pub asks: Vec<Level>,
+    /// Most recent exchange timestamp among the levels.
+    pub exchange_timestamp: Option<SystemTime>,
+    /// Most recent received timestamp among the levels.
+    pub received_timestamp: Option<SystemTime>,
 }
 
 impl Summary {
-    pub fn spread(&self) -> f64 {
+    /// Creates a summary with the most recent timestamps among the levels.
+    pub fn from_levels(bids: Vec<Level>, asks: Vec<Level>) -> Self {
+        let levels = || bids.iter().chain(asks.iter());
+        let exchange_timestamp = levels().filter_map(|level| level.exchange_timestamp).max();
+        let received_timestamp = levels().filter_map(|level| level.received_timestamp).max();
+        Self {
+            bids,
+            asks,
+            exchange_timestamp,
+            received_timestamp,
+        }
+    }
+
+    /// Sets the timestamps of the summary and of all its levels, used by the `Source`s for the
+    /// updates they get.
+    pub fn with_timestamps(
+        mut self, exchange_timestamp: Option<SystemTime>, received_timestamp: SystemTime,
+    ) -> Self {
+        for level in self.bids.iter_mut().chain(self.asks.iter_mut()) {
+            level.exchange_timestamp = exchange_timestamp;
+            level.received_timestamp = Some(received_timestamp);
+        }
+        self.exchange_timestamp = exchange_timestamp;
+        self.received_timestamp = Some(received_timestamp);
+        self
+    }
+
+    /// Difference between the best ask and the best bid, `None` when a side is empty.
+    /// The prices are adjusted by the fee of their levels, which is 0 unless the merger has fees.
+    pub fn spread(&self) -> Option<Decimal> {
         if self.asks.is_empty() || self.bids.is_empty() {
-            f64::NAN
+            None
         } else {
-            self.asks[0].price - self.bids[0].price
+            Some(self.asks[0].adjusted_price(Decimal::ONE) - self.bids[0].adjusted_price(Decimal::NEGATIVE_ONE))
         }
     }
 }
 
+/// SummaryOptions are the choices of a client about the summaries it receives.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SummaryOptions {
+    pub symbol: Symbol,
+    /// Levels on each side.
+    pub depth: usize,
+    /// Exchanges to keep, empty keeps all of them.
+    pub exchanges: Vec<String>,
+    /// Aggregates the levels of the exchanges with the same price, the depth is the number of prices.
+    pub aggregated: bool,
+    /// Groups the prices in buckets of this size, rounded outward.
+    pub bucket_size: Option<Decimal>,
+    /// Levels the merger keeps of each exchange, one with this many may have more beyond them.
+    pub exchange_depth: usize,
+}
+
+impl SummaryOptions {
+    /// Generates the `Summary` for the client from the merged one.
+    /// The buckets and the aggregated levels are sorted by the raw prices, even when the merger sorts
+    /// by the fee-adjusted ones.
+    /// The buckets from the one of the worst level of an exchange truncated to `exchange_depth` are
+    /// dropped, since they may be missing its next levels.
+    pub fn apply(&self, summary: &Summary) -> Summary {
+        let keep = |level: &&Level| {
+            self.exchanges.is_empty() || self.exchanges.contains(&level.exchange)
+        };
+        if self.aggregated || self.bucket_size.is_some() {
+            let view = |levels: &[Level], multiplier: Decimal| {
+                let mut levels: Vec<Level> = levels.iter().filter(keep).cloned().collect();
+                // Stable, so the order of the merger is kept for the same price
+                levels.sort_by_key(|level| level.price * multiplier);
+                if let Some(bucket_size) = self.bucket_size {
+                    let round_up = multiplier == Decimal::ONE;
+                    // Sorted from the best level, so the last one of each exchange is its worst
+                    let mut partial: Option<Decimal> = None;
+                    let mut counts: HashMap<&str, usize> = HashMap::new();
+                    for level in &levels {
+                        let count = counts.entry(&level.exchange).or_default();
+                        *count += 1;
+                        if *count == self.exchange_depth {
+                            let price = Level::bucket_price(level.price, bucket_size, round_up) * multiplier;
+                            partial = Some(partial.map_or(price, |partial| partial.min(price)));
+                        }
+                    }
+                    levels = Level::bucket(levels.iter(), bucket_size, round_up);
+                    if let Some(partial) = partial {
+                        levels.retain(|level| level.price * multiplier < partial);
+                    }
+                }
+                if self.aggregated {
+                    levels = Level::aggregate(levels.iter());
+                }
+                levels.truncate(self.depth);
+                levels
+            };
+            return Summary::from_levels(view(&summary.bids, Decimal::NEGATIVE_ONE), view(&summary.asks, Decimal::ONE));
+        }
+        Summary::from_levels(
+            summary.bids.iter().filter(keep).take(self.depth).cloned().collect(),
+            summary.asks.iter().filter(keep).take(self.depth).cloned().collect(),
+        )
+    }
+}
+
+/// BookUpdate is the message the `Source`s send to the `OrderbookMerger`.
+#[derive(Clone, Debug)]
+pub enum BookUpdate {
+    /// New levels from an exchange, replacing the previous ones from it.
+    Summary(Summary),
+    /// The exchange was disconnected, so its levels are no longer valid.
+    Disconnected(String),
+}
+
+impl From<Summary> for BookUpdate {
+    fn from(summary: Summary) -> Self {
+        BookUpdate::Summary(summary)
+    }
+}
+
 #[allow(clippy::from_over_into)]
 impl Into<orderbook::Summary> for Summary {
     fn into(self) -> orderbook::Summary {
+        let spread = self.spread();
         orderbook::Summary {
-            spread: self.spread(),
+            spread: spread.and_then(|spread| spread.to_f64()).unwrap_or(f64::NAN),
+            exact_spread: spread.map(|spread| spread.to_string()).unwrap_or_default(),
+            exchange_timestamp_us: timestamp_us(self.exchange_timestamp),
+            received_timestamp_us: timestamp_us(self.received_timestamp),
             bids: self.bids.iter().map(|x| x.into()).collect(),
             asks: self.asks.iter().map(|x| x.into()).collect(),
         }
@@ -138,9 +397,21 @@ impl Into<orderbook::Summary> for Summary {
 
 #[cfg(test)]
 mod test {
-    use crate::types::{
-        Asset,
-        Symbol,
+    use crate::{
+        orderbook,
+        types::{
+            Asset,
+            Level,
+            Summary,
+            SummaryOptions,
+            Symbol,
+        },
+    };
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::time::{
+        Duration,
+        UNIX_EPOCH,
     };
 
     #[test]
@@ -155,5 +426,292 @@ mod test {
         assert!(pair.is_ok());
         let pair = pair.ok().unwrap();
         assert_eq!(Symbol { base: Asset::ETH, quote: Asset::BTC }, pair);
+        assert_eq!("eth/btc", pair.to_string());
+    }
+
+    #[test]
+    fn should_filter_summary_by_exchange_and_depth() {
+        // Given
+        let level = |exchange: &str, price: Decimal| Level {
+            exchange: exchange.to_string(),
+            price,
+            quantity: dec!(10),
+            ..Default::default()
+        };
+        let summary = Summary {
+            bids: vec![level("bitstamp", dec!(1.2)), level("binance", dec!(1.1)), level("binance", dec!(1.0)), level("binance", dec!(0.9))],
+            asks: vec![level("binance", dec!(2.0)), level("bitstamp", dec!(2.1)), level("bitstamp", dec!(2.2))],
+            ..Default::default()
+        };
+        let options = SummaryOptions {
+            symbol: Symbol { base: Asset::ETH, quote: Asset::BTC },
+            depth: 2,
+            exchanges: vec!["binance".to_string()],
+            aggregated: false,
+            bucket_size: None,
+            exchange_depth: 10,
+        };
+
+        // When
+        let resp = options.apply(&summary);
+
+        // Then
+        assert_eq!(vec![level("binance", dec!(1.1)), level("binance", dec!(1.0))], resp.bids);
+        assert_eq!(vec![level("binance", dec!(2.0))], resp.asks);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn should_keep_exact_prices_in_the_summary() {
+        // Given
+        let bid = Level::parse("binance", &["0.06754400".to_string(), "31.99050000".to_string()]).unwrap();
+        let ask = Level::parse("bitstamp", &["0.06754500".to_string(), "0.1".to_string()]).unwrap();
+        let summary = Summary {
+            bids: vec![bid],
+            asks: vec![ask],
+            ..Default::default()
+        };
+
+        // When
+        let resp: orderbook::Summary = summary.into();
+
+        // Then
+        assert_eq!("0.00000100", resp.exact_spread);
+        assert_eq!("0.06754400", resp.bids[0].exact_price);
+        assert_eq!("31.99050000", resp.bids[0].exact_amount);
+        assert_eq!("0.1", resp.asks[0].exact_amount);
+        assert_eq!(0.000001, resp.spread);
+    }
+
+    #[test]
+    fn should_fail_parsing_invalid_level() {
+        // Given
+        let incomplete = vec!["1.0".to_string()];
+        let invalid = vec!["1.0".to_string(), "abc".to_string()];
+
+        // When
+        let resp = vec![Level::parse("binance", &incomplete), Level::parse("binance", &invalid)];
+
+        // Then
+        assert!(resp.iter().all(|x| x.is_err()));
+    }
+
+    #[test]
+    fn should_keep_the_most_recent_timestamps() {
+        // Given
+        let level = |exchange: &str| Level::parse(exchange, &["1.0".to_string(), "2.0".to_string()]).unwrap();
+        let binance = Summary::from_levels(vec![level("binance")], Vec::new())
+            .with_timestamps(None, UNIX_EPOCH + Duration::from_micros(30));
+        let bitstamp = Summary::from_levels(Vec::new(), vec![level("bitstamp")])
+            .with_timestamps(Some(UNIX_EPOCH + Duration::from_micros(10)), UNIX_EPOCH + Duration::from_micros(20));
+
+        // When
+        let summary = Summary::from_levels(binance.bids, bitstamp.asks);
+        let resp: orderbook::Summary = summary.clone().into();
+
+        // Then
+        assert_eq!(Some(UNIX_EPOCH + Duration::from_micros(10)), summary.exchange_timestamp);
+        assert_eq!(Some(UNIX_EPOCH + Duration::from_micros(30)), summary.received_timestamp);
+        assert_eq!(10, resp.exchange_timestamp_us);
+        assert_eq!(30, resp.received_timestamp_us);
+        assert_eq!(0, resp.bids[0].exchange_timestamp_us);
+        assert_eq!(30, resp.bids[0].received_timestamp_us);
+        assert_eq!(10, resp.asks[0].exchange_timestamp_us);
+    }
+
+    #[test]
+    fn should_aggregate_levels_with_the_same_price() {
+        // Given
+        let level = |exchange: &str, price: &str, quantity: &str, received_us: u64| Level {
+            received_timestamp: Some(UNIX_EPOCH + Duration::from_micros(received_us)),
+            ..Level::parse(exchange, &[price.to_string(), quantity.to_string()]).unwrap()
+        };
+        let summary = Summary {
+            bids: vec![
+                level("bitstamp", "0.0675", "2", 20), level("binance", "0.06750000", "1.5", 10),
+                level("kraken", "0.0674", "1", 30), level("binance", "0.0673", "1", 10),
+            ],
+            asks: vec![level("binance", "0.0677", "1", 10), level("bitstamp", "0.0677", "3", 20)],
+            ..Default::default()
+        };
+        let options = SummaryOptions {
+            symbol: Symbol { base: Asset::ETH, quote: Asset::BTC },
+            depth: 2,
+            exchanges: vec!["binance".to_string(), "bitstamp".to_string()],
+            aggregated: true,
+            bucket_size: None,
+            exchange_depth: 10,
+        };
+
+        // When
+        let resp: orderbook::Summary = options.apply(&summary).into();
+
+        // Then
+        let bids: Vec<_> = resp.bids.iter()
+            .map(|x| (x.exchange.as_str(), x.exact_price.as_str(), x.exact_amount.as_str(), x.breakdown.len()))
+            .collect();
+        assert_eq!(vec![("bitstamp,binance", "0.0675", "3.5", 2), ("binance", "0.0673", "1", 1)], bids);
+        assert_eq!(20, resp.bids[0].received_timestamp_us);
+        assert_eq!("1.5", resp.bids[0].breakdown[1].exact_amount);
+        assert_eq!("binance,bitstamp", resp.asks[0].exchange);
+        assert_eq!("4", resp.asks[0].exact_amount);
+        assert_eq!("0.0002", resp.exact_spread);
+    }
+
+    #[test]
+    fn should_bucket_prices_outward() {
+        // Given
+        let level = |exchange: &str, price: Decimal, quantity: Decimal| Level {
+            exchange: exchange.to_string(),
+            price,
+            quantity,
+            ..Default::default()
+        };
+        let summary = Summary {
+            bids: vec![
+                level("binance", dec!(0.06759), dec!(1)), level("bitstamp", dec!(0.06755), dec!(2)),
+                level("binance", dec!(0.06751), dec!(3)), level("binance", dec!(0.0674), dec!(4)),
+            ],
+            asks: vec![level("binance", dec!(0.06761), dec!(1)), level("bitstamp", dec!(0.0677), dec!(2))],
+            ..Default::default()
+        };
+        let options = |aggregated: bool| SummaryOptions {
+            symbol: Symbol { base: Asset::ETH, quote: Asset::BTC },
+            depth: 10,
+            exchanges: Vec::new(),
+            aggregated,
+            bucket_size: Some(dec!(0.0001)),
+            exchange_depth: 10,
+        };
+        let prices = |levels: &[Level]| levels.iter()
+            .map(|x| (x.exchange.clone(), x.price, x.quantity))
+            .collect::<Vec<_>>();
+
+        // When
+        let by_exchange = options(false).apply(&summary);
+        let aggregated = options(true).apply(&summary);
+
+        // Then
+        assert_eq!(
+            vec![
+                ("binance".to_string(), dec!(0.0675), dec!(4)),
+                ("bitstamp".to_string(), dec!(0.0675), dec!(2)),
+                ("binance".to_string(), dec!(0.0674), dec!(4)),
+            ],
+            prices(&by_exchange.bids),
+        );
+        assert_eq!(
+            vec![("binance".to_string(), dec!(0.0677), dec!(1)), ("bitstamp".to_string(), dec!(0.0677), dec!(2))],
+            prices(&by_exchange.asks),
+        );
+        assert_eq!(
+            vec![("binance,bitstamp".to_string(), dec!(0.0675), dec!(6)), ("binance".to_string(), dec!(0.0674), dec!(4))],
+            prices(&aggregated.bids),
+        );
+        assert_eq!(vec![("binance,bitstamp".to_string(), dec!(0.0677), dec!(3))], prices(&aggregated.asks));
+        assert_eq!(Some(dec!(0.0002)), aggregated.spread());
+    }
+
+    #[test]
+    fn should_bucket_and_aggregate_by_raw_price_with_fees() {
+        // Given
+        let level = |exchange: &str, price: Decimal, fee: Decimal| Level {
+            exchange: exchange.to_string(),
+            price,
+            quantity: dec!(1),
+            fee,
+            ..Default::default()
+        };
+        // In the order of the merger with the fees binance=0.001,bitstamp=0.004
+        let summary = Summary {
+            bids: vec![
+                level("binance", dec!(100.0), dec!(0.001)), level("binance", dec!(99.95), dec!(0.001)),
+                level("bitstamp", dec!(100.2), dec!(0.004)),
+            ],
+            asks: vec![level("binance", dec!(100.3), dec!(0.001)), level("bitstamp", dec!(100.25), dec!(0.004))],
+            ..Default::default()
+        };
+        let options = |aggregated: bool, bucket_size: Option<Decimal>| SummaryOptions {
+            symbol: Symbol { base: Asset::ETH, quote: Asset::BTC },
+            depth: 10,
+            exchanges: Vec::new(),
+            aggregated,
+            bucket_size,
+            exchange_depth: 10,
+        };
+        let prices = |levels: &[Level]| levels.iter()
+            .map(|x| (x.exchange.clone(), x.price, x.fee))
+            .collect::<Vec<_>>();
+
+        // When
+        let bucketed = options(false, Some(dec!(1))).apply(&summary);
+        let aggregated = options(true, None).apply(&summary);
+        let both = options(true, Some(dec!(1))).apply(&summary);
+
+        // Then
+        assert_eq!(
+            vec![
+                ("bitstamp".to_string(), dec!(100), Decimal::ZERO),
+                ("binance".to_string(), dec!(100), Decimal::ZERO),
+                ("binance".to_string(), dec!(99), Decimal::ZERO),
+            ],
+            prices(&bucketed.bids),
+        );
+        assert_eq!(
+            vec![
+                ("bitstamp".to_string(), dec!(100.2), Decimal::ZERO),
+                ("binance".to_string(), dec!(100.0), Decimal::ZERO),
+                ("binance".to_string(), dec!(99.95), Decimal::ZERO),
+            ],
+            prices(&aggregated.bids),
+        );
+        assert_eq!(dec!(0.004), aggregated.bids[0].breakdown[0].fee);
+        assert_eq!(Some(dec!(0.05)), aggregated.spread());
+        assert_eq!(
+            vec![("bitstamp,binance".to_string(), dec!(100), Decimal::ZERO), ("binance".to_string(), dec!(99), Decimal::ZERO)],
+            prices(&both.bids),
+        );
+        assert_eq!(vec![("bitstamp,binance".to_string(), dec!(101), Decimal::ZERO)], prices(&both.asks));
+        assert_eq!(Some(dec!(1)), both.spread());
+    }
+
+    #[test]
+    fn should_drop_the_buckets_past_the_truncated_levels() {
+        // Given
+        let level = |exchange: &str, price: Decimal, quantity: Decimal| Level {
+            exchange: exchange.to_string(),
+            price,
+            quantity,
+            ..Default::default()
+        };
+        // The merger kept 2 levels of each exchange, binance may have more in the bucket of 0.06751
+        let summary = Summary {
+            bids: vec![
+                level("binance", dec!(0.06769), dec!(1)), level("bitstamp", dec!(0.06755), dec!(2)),
+                level("binance", dec!(0.06751), dec!(3)), level("bitstamp", dec!(0.0674), dec!(4)),
+            ],
+            asks: vec![level("binance", dec!(0.06761), dec!(1)), level("bitstamp", dec!(0.0677), dec!(2))],
+            ..Default::default()
+        };
+        let options = SummaryOptions {
+            symbol: Symbol { base: Asset::ETH, quote: Asset::BTC },
+            depth: 10,
+            exchanges: Vec::new(),
+            aggregated: true,
+            bucket_size: Some(dec!(0.0001)),
+            exchange_depth: 2,
+        };
+
+        // When
+        let resp = options.apply(&summary);
+
+        // Then
+        assert_eq!(
+            vec![(dec!(0.0676), dec!(1))],
+            resp.bids.iter().map(|x| (x.price, x.quantity)).collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            vec![(dec!(0.0677), dec!(3))],
+            resp.asks.iter().map(|x| (x.price, x.quantity)).collect::<Vec<_>>(),
+        );
+    }
+}